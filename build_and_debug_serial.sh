@@ -0,0 +1,16 @@
+#!/bin/sh
+
+# kernel with the gdb stub on COM2, it stops at boot until gdb is attached:
+#   gdb gab_kernel.elf -ex "set architecture i386" -ex "target remote localhost:1234"
+# on real hardware use the serial line instead: target remote /dev/ttyS0 (set serial baud 38400)
+
+cargo build --features gdb_stub
+
+~/opt/cross/bin/i686-elf-as -msyntax=intel -mnaked-reg -g src/start.s -o src/start.o
+~/opt/cross/bin/i686-elf-as -msyntax=intel -mnaked-reg -g src/interrupts/interrupt_handlers.s -o src/interrupts/interrupt_handlers.o
+~/opt/cross/bin/i686-elf-as -msyntax=intel -mnaked-reg -g src/memory_manager/memory_manager.s  -o src/memory_manager/memory_manager.o
+
+~/opt/cross/bin/i686-elf-ld -T src/linker.ld --gc-sections src/start.o src/interrupts/interrupt_handlers.o src/memory_manager/memory_manager.o target/x86_64-gab_os/debug/libkernel.a -o gab_kernel.elf
+
+# COM1 is the console, COM2 waits for gdb on tcp port 1234
+qemu-system-i386 -m 2G -kernel gab_kernel.elf -serial stdio -serial tcp::1234,server
@@ -0,0 +1,30 @@
+#!/bin/sh
+
+# build the kernel with the #[test_case]s (see src/test_framework.rs) and run them in qemu,
+# the results are printed on the serial port (this terminal)
+
+~/opt/cross/bin/i686-elf-as -msyntax=intel -mnaked-reg -g src/start.s -o src/start.o
+~/opt/cross/bin/i686-elf-as -msyntax=intel -mnaked-reg -g src/interrupts/interrupt_handlers.s -o src/interrupts/interrupt_handlers.o
+~/opt/cross/bin/i686-elf-as -msyntax=intel -mnaked-reg -g src/memory_manager/memory_manager.s  -o src/memory_manager/memory_manager.o
+
+# the test kernel is an executable linked by cargo, with the same script and objects of the kernel
+export RUSTFLAGS="-C linker=$HOME/opt/cross/bin/i686-elf-ld \
+    -C link-arg=-T$PWD/src/linker.ld -C link-arg=--gc-sections \
+    -C link-arg=$PWD/src/start.o \
+    -C link-arg=$PWD/src/interrupts/interrupt_handlers.o \
+    -C link-arg=$PWD/src/memory_manager/memory_manager.o"
+
+TEST_KERNEL=$(cargo test --no-run --message-format=json \
+    | grep -o '"executable":"[^"]*"' | cut -d '"' -f 4)
+if [ -z "$TEST_KERNEL" ]; then
+    exit 1
+fi
+
+qemu-system-i386 -m 500 -kernel "$TEST_KERNEL" \
+    -device isa-debug-exit,iobase=0xf4,iosize=0x04 -serial stdio -display none
+
+# isa-debug-exit: (QemuExitCode::Success << 1) | 1
+if [ $? -eq 33 ]; then
+    exit 0
+fi
+exit 1
@@ -12,5 +12,5 @@ cargo build
 
 #qemu-system-i386 -kernel gab_kernel.elf
 #qemu-system-i386 -kernel gab_kernel.elf -d int -M q35,smm=off -no-reboot -no-shutdown 
-qemu-system-i386 -m 500 -gdb tcp:localhost:1234 -S -kernel gab_kernel.elf
+qemu-system-i386 -m 500 -gdb tcp:localhost:1234 -S -kernel gab_kernel.elf -serial stdio
 
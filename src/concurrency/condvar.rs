@@ -0,0 +1,57 @@
+use super::{
+    mutex::{Mutex, MutexGuard},
+    wait_queue::WaitQueue,
+};
+use crate::interrupts::without_interrupts;
+use crate::task::scheduler;
+
+/// Condition variable to be used together with a Mutex
+pub struct Condvar {
+    waiters: WaitQueue,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Self {
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Unlock the mutex and block until a notify, the mutex is locked
+    /// again before returning
+    ///
+    /// Like the WaitQueue the task could wake up without a reason,
+    /// always check the condition in a loop or use wait_while
+    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex: &'a Mutex<T> = MutexGuard::mutex(&guard);
+
+        // registering as waiter and releasing the mutex must be atomic,
+        // otherwise a notify between the two will be lost
+        without_interrupts(|| {
+            self.waiters.register_current();
+            drop(guard);
+            scheduler::block_current();
+        });
+
+        mutex.lock()
+    }
+
+    /// Block until cond returns false, return with the mutex locked
+    pub fn wait_while<'a, T, F>(&self, mut guard: MutexGuard<'a, T>, mut cond: F) -> MutexGuard<'a, T>
+    where
+        F: FnMut(&mut T) -> bool,
+    {
+        while cond(&mut *guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    pub fn notify_one(&self) -> bool {
+        self.waiters.notify_one()
+    }
+
+    pub fn notify_all(&self) -> usize {
+        self.waiters.notify_all()
+    }
+}
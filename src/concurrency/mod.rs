@@ -1 +1,5 @@
+pub mod condvar;
+pub mod mutex;
+pub mod semaphore;
 pub mod spin_mutex;
+pub mod wait_queue;
@@ -0,0 +1,46 @@
+use super::{spin_mutex::SpinMutex, wait_queue::WaitQueue};
+use crate::interrupts::without_interrupts;
+
+/// Counting semaphore, acquire will block the task if there
+/// are no permits left
+pub struct Semaphore {
+    permits: SpinMutex<usize>,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    pub const fn new(permits: usize) -> Self {
+        Self {
+            permits: SpinMutex::new(permits),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    pub fn acquire(&self) {
+        self.waiters.wait_while(|| !self.try_acquire());
+    }
+
+    /// Take a permit only if there is one avaiable, never block
+    pub fn try_acquire(&self) -> bool {
+        without_interrupts(|| {
+            let mut permits = self.permits.lock();
+            if *permits == 0 {
+                return false;
+            }
+            *permits -= 1;
+            true
+        })
+    }
+
+    /// Could be called also inside an interrupt handler
+    pub fn release(&self) {
+        without_interrupts(|| {
+            *self.permits.lock() += 1;
+            self.waiters.notify_one();
+        });
+    }
+
+    pub fn available_permits(&self) -> usize {
+        without_interrupts(|| *self.permits.lock())
+    }
+}
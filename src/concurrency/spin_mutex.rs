@@ -13,9 +13,9 @@ pub struct SpinGuard<'a, T> {
 }
 
 impl<T> SpinMutex<T> {
-    pub fn new(data: T) -> Self {
+    pub const fn new(data: T) -> Self {
         Self {
-            lock: Default::default(), // false
+            lock: AtomicBool::new(false),
             data,
         }
     }
@@ -37,6 +37,14 @@ impl<T> SpinMutex<T> {
             data: &self.data as *const T as *mut T,
         }
     }
+
+    /// Release the lock held by someone else
+    ///
+    /// Only when the owner will never run again (a panic)
+    #[cfg_attr(all(test, not(target_os = "none")), allow(dead_code))]
+    pub unsafe fn force_unlock(&self) {
+        self.lock.store(false, Ordering::Relaxed);
+    }
 }
 
 impl<'a, T> core::ops::Deref for SpinGuard<'a, T> {
@@ -0,0 +1,70 @@
+use super::spin_mutex::SpinMutex;
+use crate::interrupts::without_interrupts;
+use crate::task::{scheduler, TaskId};
+use alloc::collections::VecDeque;
+
+/// List of tasks blocked waiting for something
+///
+/// There is only one cpu, so checking a condition and going to wait
+/// with the interrupts disabled is enough to not lose a notify
+/// (the notify can only come from an interrupt or from another task
+/// executed after the switch)
+pub struct WaitQueue {
+    waiters: SpinMutex<VecDeque<TaskId>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self {
+            waiters: SpinMutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Block the running task until a notify
+    ///
+    /// Could return also if nothing changed, so always check
+    /// the condition again in a loop
+    pub fn wait(&self) {
+        without_interrupts(|| {
+            self.register_current();
+            scheduler::block_current();
+        });
+    }
+
+    /// Block the running task until cond returns false
+    pub fn wait_while<F: FnMut() -> bool>(&self, mut cond: F) {
+        without_interrupts(|| {
+            while cond() {
+                self.wait();
+            }
+        });
+    }
+
+    /// Add the running task to the waiters without blocking it,
+    /// the caller MUST call scheduler::block_current with the interrupts
+    /// still disabled
+    pub fn register_current(&self) {
+        let id = scheduler::current_id();
+        without_interrupts(|| self.waiters.lock().push_back(id));
+    }
+
+    /// Wake up the first waiter, return false if nobody was waiting
+    pub fn notify_one(&self) -> bool {
+        match without_interrupts(|| self.waiters.lock().pop_front()) {
+            Some(id) => {
+                scheduler::wake_up(id);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Wake up all the waiters, return how many tasks were waiting
+    pub fn notify_all(&self) -> usize {
+        let mut woken = 0;
+        while self.notify_one() {
+            woken += 1;
+        }
+        woken
+    }
+}
@@ -0,0 +1,208 @@
+use super::{ElfFile, ProgramHeader, SegmentType, PF_W};
+use crate::memory_manager::{
+    address_space::AddressSpace,
+    paging::{PageTableFlag, VirtualAddr},
+    PAGE_SIZE, USER_SPACE_END, USER_SPACE_START,
+};
+use alloc::vec::Vec;
+use core::mem::size_of;
+
+// The user stack is at the end of the user space
+pub const USER_STACK_TOP: usize = USER_SPACE_END;
+// only reserved, the pages are allocated on the first access
+pub const USER_STACK_SIZE: usize = 256 * PAGE_SIZE;
+
+// Auxiliary vector entries, the same used by linux
+const AT_NULL: u32 = 0;
+const AT_PHDR: u32 = 3;
+const AT_PHENT: u32 = 4;
+const AT_PHNUM: u32 = 5;
+const AT_PAGESZ: u32 = 6;
+const AT_ENTRY: u32 = 9;
+
+/// Everything needed to jump in user space
+#[derive(Debug, Clone, Copy)]
+pub struct LoadedProgram {
+    pub entry: usize,
+    pub stack_pointer: usize,
+    // first page after the last segment, the heap of the program can start here
+    pub program_break: usize,
+}
+
+/// Map all the PT_LOAD segments of elf inside address_space and prepare the user stack
+///
+/// The stack follow the System V i386 ABI, from esp:
+/// argc | argv[0..argc] | NULL | envp[..] | NULL | auxv pairs | AT_NULL | ...strings...
+pub fn load(
+    elf: &ElfFile,
+    address_space: &mut AddressSpace,
+    argv: &[&str],
+    envp: &[&str],
+) -> Result<LoadedProgram, &'static str> {
+    let header = elf.header();
+    let mut program_break = USER_SPACE_START;
+    let mut phdr_addr: Option<u32> = None;
+
+    for ph in elf.program_headers() {
+        match ph.get_type() {
+            SegmentType::Load => {
+                load_segment(elf, &ph, address_space)?;
+                program_break = program_break.max(ph.vaddr as usize + ph.memsz as usize);
+
+                // the program headers are inside this segment
+                // (no ph.offset + ph.filesz, it could overflow)
+                if phdr_addr.is_none()
+                    && ph.offset <= header.phoff
+                    && header.phoff - ph.offset < ph.filesz
+                {
+                    phdr_addr = Some(ph.vaddr + (header.phoff - ph.offset));
+                }
+            }
+            SegmentType::Phdr => phdr_addr = Some(ph.vaddr),
+            SegmentType::Interp => return Err("Dynamic linked executables are not supported"),
+            _ => (),
+        }
+    }
+
+    let mut auxv: Vec<(u32, u32)> = Vec::new();
+    if let Some(phdr_addr) = phdr_addr {
+        auxv.push((AT_PHDR, phdr_addr));
+    }
+    auxv.push((AT_PHENT, size_of::<ProgramHeader>() as u32));
+    auxv.push((AT_PHNUM, header.phnum as u32));
+    auxv.push((AT_PAGESZ, PAGE_SIZE as u32));
+    auxv.push((AT_ENTRY, header.entry));
+
+    let stack_pointer = set_up_user_stack(address_space, argv, envp, &auxv)?;
+
+    let program_break = align_up(program_break);
+    address_space.init_program_break(program_break);
+
+    Ok(LoadedProgram {
+        entry: elf.entry_point(),
+        stack_pointer,
+        program_break,
+    })
+}
+
+fn load_segment(
+    elf: &ElfFile,
+    ph: &ProgramHeader,
+    address_space: &mut AddressSpace,
+) -> Result<(), &'static str> {
+    if ph.memsz == 0 {
+        return Ok(());
+    }
+    if ph.filesz > ph.memsz {
+        return Err("Segment with filesz bigger than memsz");
+    }
+
+    let start = ph.vaddr as usize;
+    let end = start
+        .checked_add(ph.memsz as usize)
+        .ok_or("Segment size overflow")?;
+    if start < USER_SPACE_START || end > USER_SPACE_END {
+        return Err("Segment outside of the user space");
+    }
+
+    // without NX every present page can be executed
+    let mut flags = PageTableFlag::User as u32;
+    if ph.flags & PF_W != 0 {
+        flags |= PageTableFlag::Writable as u32;
+    }
+
+    let mut page = start & !(PAGE_SIZE - 1);
+    while page < end {
+        let virt = VirtualAddr::new(page);
+        match address_space.get_page_flags(&virt) {
+            // page shared with the previous segment
+            Some(_) => address_space.add_page_flags(&virt, flags)?,
+            None => {
+                address_space.alloc_page(virt, flags)?;
+            }
+        }
+        page += PAGE_SIZE;
+    }
+
+    let data = elf.segment_data(ph)?;
+    address_space.write(VirtualAddr::new(start), data)?;
+
+    // bss
+    address_space.fill(
+        VirtualAddr::new(start + ph.filesz as usize),
+        0,
+        (ph.memsz - ph.filesz) as usize,
+    )?;
+
+    Ok(())
+}
+
+/// Reserve the user stack and write on it arguments, environment and auxiliary vector,
+/// return the initial esp
+fn set_up_user_stack(
+    address_space: &mut AddressSpace,
+    argv: &[&str],
+    envp: &[&str],
+    auxv: &[(u32, u32)],
+) -> Result<usize, &'static str> {
+    let stack_bottom = USER_STACK_TOP - USER_STACK_SIZE;
+    // the writes below allocate only the pages at the top
+    address_space.reserve(
+        stack_bottom,
+        USER_STACK_SIZE,
+        PageTableFlag::User as u32 | PageTableFlag::Writable as u32,
+    )?;
+
+    let mut sp = USER_STACK_TOP;
+
+    // C strings on top of the stack
+    let mut push_strings = |strings: &[&str]| -> Result<Vec<u32>, &'static str> {
+        let mut pointers = Vec::with_capacity(strings.len());
+        for string in strings {
+            sp = sp
+                .checked_sub(string.len() + 1)
+                .filter(|&sp| sp >= stack_bottom)
+                .ok_or("Arguments too big for the user stack")?;
+
+            address_space.write(VirtualAddr::new(sp), string.as_bytes())?;
+            address_space.write(VirtualAddr::new(sp + string.len()), &[0])?;
+            pointers.push(sp as u32);
+        }
+        Ok(pointers)
+    };
+
+    let argv_pointers = push_strings(argv)?;
+    let envp_pointers = push_strings(envp)?;
+
+    let mut table: Vec<u32> = Vec::new();
+    table.push(argv_pointers.len() as u32);
+    table.extend_from_slice(&argv_pointers);
+    table.push(0);
+    table.extend_from_slice(&envp_pointers);
+    table.push(0);
+    for (key, value) in auxv {
+        table.push(*key);
+        table.push(*value);
+    }
+    table.push(AT_NULL);
+    table.push(0);
+
+    // the ABI wants esp aligned to 16 byte at the entry point
+    sp = (sp - table.len() * size_of::<u32>()) & !0xF;
+    if sp < stack_bottom {
+        return Err("Arguments too big for the user stack");
+    }
+
+    for (i, value) in table.iter().enumerate() {
+        address_space.write(
+            VirtualAddr::new(sp + i * size_of::<u32>()),
+            &value.to_le_bytes(),
+        )?;
+    }
+
+    Ok(sp)
+}
+
+fn align_up(addr: usize) -> usize {
+    (addr + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
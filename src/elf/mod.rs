@@ -0,0 +1,324 @@
+pub mod loader;
+
+use core::{mem::size_of, ptr::read_unaligned};
+
+// e_ident
+const ELF_MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+const ELF_CLASS_32: u8 = 1;
+const ELF_DATA_LITTLE_ENDIAN: u8 = 1;
+const ELF_VERSION_CURRENT: u8 = 1;
+
+// e_type
+const ET_EXEC: u16 = 2;
+// e_machine
+const EM_386: u16 = 3;
+
+/// Header at the beginning of every ELF32 file
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct ElfHeader {
+    pub ident: [u8; 16],
+    pub elf_type: u16,
+    pub machine: u16,
+    pub version: u32,
+    pub entry: u32,
+    pub phoff: u32, // program headers offset
+    pub shoff: u32, // section headers offset
+    pub flags: u32,
+    pub ehsize: u16,
+    pub phentsize: u16,
+    pub phnum: u16,
+    pub shentsize: u16,
+    pub shnum: u16,
+    pub shstrndx: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SegmentType {
+    Null,
+    Load,
+    Dynamic,
+    Interp,
+    Note,
+    Phdr,
+    Other(u32),
+}
+
+impl From<u32> for SegmentType {
+    fn from(value: u32) -> Self {
+        match value {
+            0 => SegmentType::Null,
+            1 => SegmentType::Load,
+            2 => SegmentType::Dynamic,
+            3 => SegmentType::Interp,
+            4 => SegmentType::Note,
+            6 => SegmentType::Phdr,
+            other => SegmentType::Other(other),
+        }
+    }
+}
+
+// p_flags
+pub const PF_X: u32 = 1;
+pub const PF_W: u32 = 2;
+pub const PF_R: u32 = 4;
+
+/// Describe a segment, only the Load ones are copied in memory
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct ProgramHeader {
+    pub segment_type: u32,
+    pub offset: u32,
+    pub vaddr: u32,
+    pub paddr: u32,
+    pub filesz: u32,
+    pub memsz: u32,
+    pub flags: u32,
+    pub align: u32,
+}
+
+impl ProgramHeader {
+    pub fn get_type(&self) -> SegmentType {
+        SegmentType::from(self.segment_type)
+    }
+}
+
+// sh_type
+pub const SHT_SYMTAB: u32 = 2;
+pub const SHT_STRTAB: u32 = 3;
+
+/// Describe a section, the bootloader gives the table of the kernel ones
+/// (multiboot flag 5) with addr set also for the sections it loaded without SHF_ALLOC
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct SectionHeader {
+    pub name: u32, // offset inside the section names (shstrndx)
+    pub section_type: u32,
+    pub flags: u32,
+    pub addr: u32,
+    pub offset: u32,
+    pub size: u32,
+    pub link: u32, // for SHT_SYMTAB the index of its string table
+    pub info: u32,
+    pub addralign: u32,
+    pub entsize: u32,
+}
+
+// st_info & 0xF
+pub const STT_NOTYPE: u8 = 0;
+pub const STT_FUNC: u8 = 2;
+// st_shndx
+pub const SHN_UNDEF: u16 = 0;
+
+/// Element of a SHT_SYMTAB section
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct Symbol {
+    pub name: u32, // offset inside the string table
+    pub value: u32,
+    pub size: u32,
+    pub info: u8,
+    pub other: u8,
+    pub shndx: u16,
+}
+
+impl Symbol {
+    pub fn symbol_type(&self) -> u8 {
+        self.info & 0xF
+    }
+}
+
+/// ELF32 i386 executable, it just check and read the bytes,
+/// nothing is copied
+pub struct ElfFile<'a> {
+    data: &'a [u8],
+    header: ElfHeader,
+}
+
+impl<'a> ElfFile<'a> {
+    pub fn new(data: &'a [u8]) -> Result<Self, &'static str> {
+        if data.len() < size_of::<ElfHeader>() {
+            return Err("File too small to be an ELF");
+        }
+
+        // the data could be not aligned
+        let header = unsafe { read_unaligned(data.as_ptr() as *const ElfHeader) };
+
+        if header.ident[0..4] != ELF_MAGIC {
+            return Err("ELF magic number wrong");
+        }
+        if header.ident[4] != ELF_CLASS_32 {
+            return Err("Only ELF32 is supported");
+        }
+        if header.ident[5] != ELF_DATA_LITTLE_ENDIAN {
+            return Err("Only little endian ELF is supported");
+        }
+        if header.ident[6] != ELF_VERSION_CURRENT {
+            return Err("ELF version not supported");
+        }
+        if header.elf_type != ET_EXEC {
+            return Err("ELF is not an executable");
+        }
+        if header.machine != EM_386 {
+            return Err("ELF is not for i386");
+        }
+
+        if header.phnum != 0 {
+            if header.phentsize as usize != size_of::<ProgramHeader>() {
+                return Err("Program header size wrong");
+            }
+            // the values come from the file, the sum could overflow
+            let ph_end = (header.phnum as usize)
+                .checked_mul(size_of::<ProgramHeader>())
+                .and_then(|size| size.checked_add(header.phoff as usize))
+                .ok_or("Program headers outside of the file")?;
+            if ph_end > data.len() {
+                return Err("Program headers outside of the file");
+            }
+        }
+
+        Ok(Self { data, header })
+    }
+
+    pub fn header(&self) -> &ElfHeader {
+        &self.header
+    }
+
+    pub fn entry_point(&self) -> usize {
+        self.header.entry as usize
+    }
+
+    pub fn program_headers(&self) -> ProgramHeaderIterator<'a> {
+        ProgramHeaderIterator {
+            data: self.data,
+            offset: self.header.phoff as usize,
+            remaining: self.header.phnum as usize,
+        }
+    }
+
+    /// Bytes of the segment stored in the file (filesz, not memsz)
+    pub fn segment_data(&self, ph: &ProgramHeader) -> Result<&'a [u8], &'static str> {
+        let start = ph.offset as usize;
+        let end = start
+            .checked_add(ph.filesz as usize)
+            .ok_or("Segment size overflow")?;
+
+        self.data
+            .get(start..end)
+            .ok_or("Segment outside of the file")
+    }
+}
+
+pub struct ProgramHeaderIterator<'a> {
+    data: &'a [u8],
+    offset: usize,
+    remaining: usize,
+}
+
+impl<'a> Iterator for ProgramHeaderIterator<'a> {
+    type Item = ProgramHeader;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+
+        // bounds already checked in ElfFile::new
+        let ph = unsafe {
+            read_unaligned(self.data.as_ptr().add(self.offset) as *const ProgramHeader)
+        };
+
+        self.offset += size_of::<ProgramHeader>();
+        self.remaining -= 1;
+
+        Some(ph)
+    }
+}
+
+// on the host, see test_on_host.sh
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    const HEADER_SIZE: usize = size_of::<ElfHeader>();
+    const PH_SIZE: usize = size_of::<ProgramHeader>();
+
+    // header of an i386 executable followed by phnum program headers
+    fn elf_file(phoff: u32, phnum: u16) -> Vec<u8> {
+        let mut data = std::vec![0u8; HEADER_SIZE + phnum as usize * PH_SIZE];
+        data[0..4].copy_from_slice(&ELF_MAGIC);
+        data[4] = ELF_CLASS_32;
+        data[5] = ELF_DATA_LITTLE_ENDIAN;
+        data[6] = ELF_VERSION_CURRENT;
+        data[16..18].copy_from_slice(&ET_EXEC.to_le_bytes());
+        data[18..20].copy_from_slice(&EM_386.to_le_bytes());
+        data[28..32].copy_from_slice(&phoff.to_le_bytes());
+        data[42..44].copy_from_slice(&(PH_SIZE as u16).to_le_bytes());
+        data[44..46].copy_from_slice(&phnum.to_le_bytes());
+        data
+    }
+
+    // PT_LOAD program header written at offset
+    fn write_segment(data: &mut [u8], offset: usize, file_offset: u32, filesz: u32) {
+        let ph = &mut data[offset..offset + PH_SIZE];
+        ph[0..4].copy_from_slice(&1u32.to_le_bytes());
+        ph[4..8].copy_from_slice(&file_offset.to_le_bytes());
+        ph[16..20].copy_from_slice(&filesz.to_le_bytes());
+        ph[20..24].copy_from_slice(&filesz.to_le_bytes());
+    }
+
+    #[test]
+    fn valid_file_is_read() {
+        let mut data = elf_file(HEADER_SIZE as u32, 1);
+        write_segment(&mut data, HEADER_SIZE, 0, 16);
+        let elf = ElfFile::new(&data).unwrap();
+
+        let segments: Vec<ProgramHeader> = elf.program_headers().collect();
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].get_type(), SegmentType::Load);
+        assert_eq!(elf.segment_data(&segments[0]).unwrap(), &data[..16]);
+    }
+
+    #[test]
+    fn truncated_header_is_rejected() {
+        let data = elf_file(HEADER_SIZE as u32, 0);
+        assert!(ElfFile::new(&data[..HEADER_SIZE - 1]).is_err());
+        assert!(ElfFile::new(&[]).is_err());
+    }
+
+    #[test]
+    fn truncated_program_headers_are_rejected() {
+        let data = elf_file(HEADER_SIZE as u32, 2);
+        assert!(ElfFile::new(&data[..data.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn overflowing_program_headers_are_rejected() {
+        // phoff + phnum * 32 wraps around on 32 bits
+        let mut data = elf_file(u32::MAX - 31, 1);
+        assert!(ElfFile::new(&data).is_err());
+
+        data[44..46].copy_from_slice(&u16::MAX.to_le_bytes());
+        assert!(ElfFile::new(&data).is_err());
+    }
+
+    #[test]
+    fn overflowing_segment_is_rejected() {
+        let mut data = elf_file(HEADER_SIZE as u32, 1);
+        write_segment(&mut data, HEADER_SIZE, u32::MAX, 2);
+        let elf = ElfFile::new(&data).unwrap();
+
+        let segment = elf.program_headers().next().unwrap();
+        assert!(elf.segment_data(&segment).is_err());
+    }
+
+    #[test]
+    fn wrong_header_is_rejected() {
+        for (offset, value) in [(0, 0x7E), (4, 2), (5, 2), (6, 0), (16, 3), (18, 62)] {
+            let mut data = elf_file(HEADER_SIZE as u32, 0);
+            data[offset] = value;
+            assert!(ElfFile::new(&data).is_err());
+        }
+    }
+}
@@ -0,0 +1,462 @@
+use crate::concurrency::spin_mutex::SpinMutex;
+use crate::interrupts::idt::IDT;
+use crate::interrupts::interrupt_frame::InterruptFrame;
+use crate::memory_manager::{current_page_flags, paging::PageTableFlag, PAGE_SIZE};
+use crate::serial::{SerialPort, SERIAL2};
+use core::arch::asm;
+use core::mem::size_of;
+
+// GDB remote serial protocol on COM2
+//
+// #BP (int3, also the breakpoints written by gdb in memory) and #DB (single step) stop the
+// whole kernel inside their handler: the stub talks with gdb polling the uart until it
+// is told to continue or to step. A Ctrl-C of gdb (0x03) raises COM2_IRQ and stops the
+// kernel too. Every packet is
+//
+// $<data>#<checksum>
+//
+// acknowledged with + (or - to send it again). Only what gdb needs is implemented:
+// ? g G m M c s D k, the rest gets an empty reply (unsupported).
+// Never put a breakpoint inside the stub or the serial driver, it would wait for itself
+
+// longest packet accepted and sent, told to gdb with qSupported
+const PACKET_SIZE: usize = 1024;
+
+const CTRL_C: u8 = 0x03;
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+// Trap Flag, a #DB after every instruction
+const EFLAGS_TF: u32 = 0x100;
+
+// registers in the order of gdb for i386: eax ecx edx ebx esp ebp esi edi eip eflags
+// cs ss ds es fs gs
+const REGISTERS: usize = 16;
+
+struct Packet {
+    data: [u8; PACKET_SIZE],
+    len: usize,
+}
+
+impl Packet {
+    const fn new() -> Self {
+        Self {
+            data: [0; PACKET_SIZE],
+            len: 0,
+        }
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    // what doesn't fit is lost, the replies are sized to fit
+    fn push(&mut self, byte: u8) {
+        if self.len < PACKET_SIZE {
+            self.data[self.len] = byte;
+            self.len += 1;
+        }
+    }
+
+    fn push_str(&mut self, s: &str) {
+        s.bytes().for_each(|byte| self.push(byte));
+    }
+
+    fn push_hex(&mut self, byte: u8) {
+        self.push(HEX_DIGITS[(byte >> 4) as usize]);
+        self.push(HEX_DIGITS[(byte & 0xF) as usize]);
+    }
+
+    /// Wait for a packet with the right checksum, everything before its start
+    /// is ignored (acks and Ctrl-C)
+    fn receive(&mut self, serial: &mut SerialPort) {
+        loop {
+            while read_byte(serial) != b'$' {}
+
+            self.clear();
+            let mut byte = read_byte(serial);
+            while byte != b'#' {
+                self.push(byte);
+                byte = read_byte(serial);
+            }
+
+            let expected = parse_hex(&[read_byte(serial), read_byte(serial)]);
+            if expected == Some(checksum(self.as_slice()) as u32) {
+                serial.write_byte(b'+');
+                return;
+            }
+            serial.write_byte(b'-');
+        }
+    }
+
+    /// Send it until gdb acknowledges it
+    fn send(&self, serial: &mut SerialPort) {
+        let checksum = checksum(self.as_slice());
+
+        loop {
+            serial.write_byte(b'$');
+            self.as_slice()
+                .iter()
+                .for_each(|byte| serial.write_byte(*byte));
+            serial.write_byte(b'#');
+            serial.write_byte(HEX_DIGITS[(checksum >> 4) as usize]);
+            serial.write_byte(HEX_DIGITS[(checksum & 0xF) as usize]);
+
+            // - asks to send it again
+            loop {
+                match read_byte(serial) {
+                    b'+' => return,
+                    b'-' => break,
+                    _ => (),
+                }
+            }
+        }
+    }
+}
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+// sum modulo 256 of the data between $ and #
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, byte| sum.wrapping_add(*byte))
+}
+
+fn read_byte(serial: &mut SerialPort) -> u8 {
+    loop {
+        if let Some(byte) = serial.read_byte() {
+            return byte;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+struct GdbStub {
+    packet: Packet,
+    reply: Packet,
+    // gdb is attached, it is waiting for a stop reply
+    connected: bool,
+}
+
+static STUB: SpinMutex<GdbStub> = SpinMutex::new(GdbStub {
+    packet: Packet::new(),
+    reply: Packet::new(),
+    connected: false,
+});
+
+/// Enable the interrupt of COM2, so gdb can stop the kernel with Ctrl-C
+pub fn init() {
+    crate::interrupts::without_interrupts(|| SERIAL2.lock().enable_receive_interrupt());
+}
+
+/// Stop here and wait for gdb
+#[inline(always)]
+pub fn breakpoint() {
+    unsafe { asm!("int3", options(nomem, nostack)) };
+}
+
+/// #DB and #BP
+pub fn handle_debug_exception(_idt: &IDT, esp: u32) -> u32 {
+    enter(esp, SIGTRAP);
+    esp
+}
+
+/// Something from gdb while the kernel is running, it can only be a Ctrl-C
+pub fn handle_serial_interrupt(_idt: &IDT, esp: u32) -> u32 {
+    let mut interrupted = false;
+    while let Some(byte) = SERIAL2.lock().read_byte() {
+        interrupted |= byte == CTRL_C;
+    }
+
+    if interrupted {
+        enter(esp, SIGINT);
+    }
+    esp
+}
+
+// the handlers run with the interrupts disabled, nothing else can run until gdb resumes
+fn enter(esp: u32, signal: u8) {
+    let frame = unsafe { InterruptFrame::from_esp(esp) };
+    STUB.lock().run(frame, signal, &mut SERIAL2.lock());
+}
+
+impl GdbStub {
+    fn run(&mut self, frame: &mut InterruptFrame, signal: u8, serial: &mut SerialPort) {
+        frame.eflags &= !EFLAGS_TF;
+
+        if self.connected {
+            self.reply.clear();
+            stop_reply(&mut self.reply, signal);
+            self.reply.send(serial);
+        }
+
+        loop {
+            self.packet.receive(serial);
+            self.connected = true;
+
+            let reply = &mut self.reply;
+            reply.clear();
+            let (&command, args) = match self.packet.as_slice().split_first() {
+                Some(split) => split,
+                None => (&0, &[][..]),
+            };
+
+            match command {
+                b'?' => stop_reply(reply, signal),
+                b'g' => read_registers(reply, frame),
+                b'G' => write_registers(reply, frame, args),
+                b'm' => read_memory(reply, args),
+                b'M' => write_memory(reply, args),
+                b'c' | b's' => {
+                    // the optional argument is where to resume
+                    if let Some(addr) = parse_hex(args) {
+                        frame.eip = addr;
+                    }
+                    if command == b's' {
+                        frame.eflags |= EFLAGS_TF;
+                    }
+                    // the reply is the next stop
+                    return;
+                }
+                b'D' => {
+                    reply.push_str("OK");
+                    reply.send(serial);
+                    self.connected = false;
+                    return;
+                }
+                b'k' => {
+                    self.connected = false;
+                    return;
+                }
+                // every thread is the same for the stub
+                b'H' => reply.push_str("OK"),
+                b'q' if args.starts_with(b"Supported") => reply.push_str("PacketSize=400"),
+                b'q' if args == b"Attached" => reply.push_str("1"),
+                _ => (),
+            }
+
+            reply.send(serial);
+        }
+    }
+}
+
+fn stop_reply(reply: &mut Packet, signal: u8) {
+    reply.push(b'S');
+    reply.push_hex(signal);
+}
+
+fn read_registers(reply: &mut Packet, frame: &mut InterruptFrame) {
+    for value in registers(frame) {
+        value
+            .to_le_bytes()
+            .iter()
+            .for_each(|byte| reply.push_hex(*byte));
+    }
+}
+
+// G, the stack and the segments can't be changed: the frame is on the stack
+fn write_registers(reply: &mut Packet, frame: &mut InterruptFrame, args: &[u8]) {
+    let Some(values) = decode_registers(args) else {
+        return reply.push_str("E01");
+    };
+
+    frame.eax = values[0];
+    frame.ecx = values[1];
+    frame.edx = values[2];
+    frame.ebx = values[3];
+    frame.ebp = values[5];
+    frame.esi = values[6];
+    frame.edi = values[7];
+    frame.eip = values[8];
+    frame.eflags = values[9];
+    reply.push_str("OK");
+}
+
+// every register is 8 hex digits, little endian, in the order of gdb
+fn decode_registers(args: &[u8]) -> Option<[u32; REGISTERS]> {
+    let mut values = [0u32; REGISTERS];
+    for (i, value) in values.iter_mut().enumerate() {
+        let mut bytes = [0u8; 4];
+        for (j, byte) in bytes.iter_mut().enumerate() {
+            let start = i * 8 + j * 2;
+            *byte = args.get(start..start + 2).and_then(parse_hex)? as u8;
+        }
+        *value = u32::from_le_bytes(bytes);
+    }
+    Some(values)
+}
+
+// m addr,len
+fn read_memory(reply: &mut Packet, args: &[u8]) {
+    let Some((addr, len)) = parse_addr_len(args) else {
+        return reply.push_str("E01");
+    };
+    // no data would be an empty reply, that means unsupported
+    if len == 0 {
+        return reply.push_str("OK");
+    }
+    // two hex digits for every byte
+    let len = len.min(PACKET_SIZE / 2);
+    if !accessible(addr, len, false) {
+        return reply.push_str("E14");
+    }
+
+    for i in 0..len {
+        let byte = unsafe { core::ptr::read_volatile((addr + i) as *const u8) };
+        reply.push_hex(byte);
+    }
+}
+
+// M addr,len:data
+fn write_memory(reply: &mut Packet, args: &[u8]) {
+    let Some(colon) = args.iter().position(|byte| *byte == b':') else {
+        return reply.push_str("E01");
+    };
+    let Some((addr, len)) = parse_addr_len(&args[..colon]) else {
+        return reply.push_str("E01");
+    };
+    let data = &args[colon + 1..];
+    if data.len() != len * 2 {
+        return reply.push_str("E01");
+    }
+    if !accessible(addr, len, true) {
+        return reply.push_str("E14");
+    }
+
+    for (i, digits) in data.chunks(2).enumerate() {
+        let Some(byte) = parse_hex(digits) else {
+            return reply.push_str("E01");
+        };
+        unsafe { core::ptr::write_volatile((addr + i) as *mut u8, byte as u8) };
+    }
+    reply.push_str("OK");
+}
+
+// values of the interrupted code, in the order of gdb
+fn registers(frame: &mut InterruptFrame) -> [u32; REGISTERS] {
+    let (esp, ss) = match frame.as_user_frame() {
+        Some(user_frame) => (user_frame.user_esp, user_frame.user_ss),
+        // same ring, the cpu didn't switch stack: it was just above the frame
+        None => {
+            let ss: u16;
+            unsafe { asm!("mov {:x}, ss", out(reg) ss, options(nomem, nostack, preserves_flags)) };
+            let esp = frame as *const InterruptFrame as u32 + size_of::<InterruptFrame>() as u32;
+            (esp, ss as u32)
+        }
+    };
+
+    [
+        frame.eax,
+        frame.ecx,
+        frame.edx,
+        frame.ebx,
+        esp,
+        frame.ebp,
+        frame.esi,
+        frame.edi,
+        frame.eip,
+        frame.eflags,
+        frame.cs,
+        ss,
+        frame.ds,
+        frame.es,
+        frame.fs,
+        frame.gs,
+    ]
+}
+
+// every page of [addr, addr + len) is mapped (and writable)
+fn accessible(addr: usize, len: usize, write: bool) -> bool {
+    if len == 0 {
+        return true;
+    }
+    let Some(last) = addr.checked_add(len - 1) else {
+        return false;
+    };
+
+    let mut page = addr & !(PAGE_SIZE - 1);
+    loop {
+        let writable = match current_page_flags(page) {
+            Some(flags) => flags & PageTableFlag::Writable as u32 != 0,
+            None => return false,
+        };
+        if write && !writable {
+            return false;
+        }
+        if last - page < PAGE_SIZE {
+            return true;
+        }
+        page += PAGE_SIZE;
+    }
+}
+
+fn parse_hex(digits: &[u8]) -> Option<u32> {
+    if digits.is_empty() || digits.len() > 8 {
+        return None;
+    }
+    digits.iter().try_fold(0u32, |value, digit| {
+        let digit = (*digit as char).to_digit(16)?;
+        Some(value << 4 | digit)
+    })
+}
+
+// addr,len
+fn parse_addr_len(args: &[u8]) -> Option<(usize, usize)> {
+    let comma = args.iter().position(|byte| *byte == b',')?;
+    let addr = parse_hex(&args[..comma])?;
+    let len = parse_hex(&args[comma + 1..])?;
+    Some((addr as usize, len as usize))
+}
+
+// on the host, see test_on_host.sh
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_hex_digits() {
+        assert_eq!(Some(0x1f), parse_hex(b"1f"));
+        assert_eq!(Some(0xDEADBEEF), parse_hex(b"DEADbeef"));
+        assert_eq!(None, parse_hex(b""));
+        assert_eq!(None, parse_hex(b"123456789"));
+        assert_eq!(None, parse_hex(b"12g4"));
+    }
+
+    #[test]
+    fn parse_addr_and_len() {
+        assert_eq!(Some((0xc0de, 0x10)), parse_addr_len(b"c0de,10"));
+        assert_eq!(None, parse_addr_len(b"c0de"));
+        assert_eq!(None, parse_addr_len(b"c0de,"));
+        assert_eq!(None, parse_addr_len(b",10"));
+    }
+
+    #[test]
+    fn packet_checksum() {
+        // $OK#9a
+        assert_eq!(0x9a, checksum(b"OK"));
+        assert_eq!(0, checksum(b""));
+        // modulo 256
+        assert_eq!(0xfe, checksum(&[0xff, 0xff]));
+    }
+
+    #[test]
+    fn decode_every_register() {
+        let values: [u32; REGISTERS] = core::array::from_fn(|i| 0x01020304 * i as u32);
+        let mut packet = Packet::new();
+        for value in values {
+            value
+                .to_le_bytes()
+                .iter()
+                .for_each(|byte| packet.push_hex(*byte));
+        }
+        assert_eq!(Some(values), decode_registers(packet.as_slice()));
+
+        // one digit missing
+        assert_eq!(None, decode_registers(&packet.as_slice()[..packet.len - 1]));
+        packet.data[3] = b'x';
+        assert_eq!(None, decode_registers(packet.as_slice()));
+    }
+}
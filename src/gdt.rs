@@ -4,7 +4,15 @@ const KERNEL_CODE_SEGMENT_FLAGS: u8 = 0x9A; // 10011010
 const KERNEL_DATA_SEGMENT_FLAGS: u8 = 0x92; // 10010010
 const USER_CODE_SEGMENT_FLAGS: u8 = 0xFA; // 11110010
 const USER_DATA_SEGMENT_FLAGS: u8 = 0xF2; // 11110010
-const TASK_STATE_SEGMENT_FLAGS: u8 = 0xF2; // 11110010
+const TASK_STATE_SEGMENT_FLAGS: u8 = 0x89; // 10001001 -> present, 32 bit available TSS
+
+// Selectors of the segments inside the GDT, reloadSegments (start.s) use the same values
+// the user ones have the RPL (last 2 bits) set to 3
+pub const KERNEL_CODE_SELECTOR: u16 = 0x08;
+pub const KERNEL_DATA_SELECTOR: u16 = 0x10;
+pub const USER_CODE_SELECTOR: u16 = 0x18 | 3;
+pub const USER_DATA_SELECTOR: u16 = 0x20 | 3;
+pub const TSS_SELECTOR: u16 = 0x28;
                                            
 extern {
     // this is extern "C" unsafe
@@ -82,6 +90,18 @@ impl SegmentDescriptor {
             high_base: ((base >> 24) & 0xFF) as u8
         }
     }
+
+    /// System segments (like the TSS) want the flags to zero, so the limit is in bytes
+    fn new_system(base: u32, limit: u32, access_type: u8) -> Self {
+        SegmentDescriptor {
+            low_limit: (limit & 0xFFFF) as u16,
+            low_base: (base & 0xFFFF) as u16,
+            mid_base: ((base >> 16) & 0xFF) as u8,
+            access_type,
+            high_limit_and_flags: HighLimitAndFlags::new(limit, 0),
+            high_base: ((base >> 24) & 0xFF) as u8
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -92,8 +112,8 @@ impl HighLimitAndFlags {
     /// Limit is surely under 20 bit
     /// Flags has 4 bit
     pub fn new(limit: u32, flags: u8) -> Self {
-        //HighLimitAndFlags((((limit >> 16 & 0xF) | (flags << 4) as u32) & 0xFF) as u8)
-        HighLimitAndFlags((((limit >> 16 & 0xF) << 4 | flags as u32) & 0xFF) as u8)
+        // the limit goes in the low 4 bits and the flags in the high ones
+        HighLimitAndFlags((((limit >> 16 & 0xF) | (flags << 4) as u32) & 0xFF) as u8)
     }
 }
 
@@ -114,9 +134,81 @@ pub struct GDT {
     //unused_sd: SegmentDescriptor,
     k_code_sd: SegmentDescriptor, // k = kernel
     k_data_sd: SegmentDescriptor,
-    //u_code_sd: SegmentDescriptor, // u = user
-    //u_data_sd: SegmentDescriptor,
-    //task_state_sd: SegmentDescriptor,
+    u_code_sd: SegmentDescriptor, // u = user
+    u_data_sd: SegmentDescriptor,
+    task_state_sd: SegmentDescriptor,
+}
+
+/// Only esp0 and ss0 are used, hardware task switching is not used.
+/// They are the kernel stack loaded by the cpu when an interrupt arrives in ring 3
+#[derive(Debug)]
+#[repr(C)]
+pub struct TaskStateSegment {
+    prev_tss: u32,
+    esp0: u32,
+    ss0: u32,
+    esp1: u32,
+    ss1: u32,
+    esp2: u32,
+    ss2: u32,
+    cr3: u32,
+    eip: u32,
+    eflags: u32,
+    eax: u32,
+    ecx: u32,
+    edx: u32,
+    ebx: u32,
+    esp: u32,
+    ebp: u32,
+    esi: u32,
+    edi: u32,
+    es: u32,
+    cs: u32,
+    ss: u32,
+    ds: u32,
+    fs: u32,
+    gs: u32,
+    ldt: u32,
+    trap: u16,
+    iomap_base: u16,
+}
+
+// There is only one cpu so one TSS is enough,
+// esp0 is updated by the scheduler at every switch
+static mut TSS: TaskStateSegment = TaskStateSegment {
+    prev_tss: 0,
+    esp0: 0,
+    ss0: KERNEL_DATA_SELECTOR as u32,
+    esp1: 0,
+    ss1: 0,
+    esp2: 0,
+    ss2: 0,
+    cr3: 0,
+    eip: 0,
+    eflags: 0,
+    eax: 0,
+    ecx: 0,
+    edx: 0,
+    ebx: 0,
+    esp: 0,
+    ebp: 0,
+    esi: 0,
+    edi: 0,
+    es: 0,
+    cs: 0,
+    ss: 0,
+    ds: 0,
+    fs: 0,
+    gs: 0,
+    ldt: 0,
+    trap: 0,
+    // no io permission bitmap
+    iomap_base: core::mem::size_of::<TaskStateSegment>() as u16,
+};
+
+/// Set the stack used when an interrupt (or a syscall) arrives from ring 3
+pub fn set_kernel_stack(esp0: u32) {
+    unsafe { (*core::ptr::addr_of_mut!(TSS)).esp0 = esp0 };
 }
 
 #[repr(C, packed(2))]
@@ -135,9 +227,14 @@ impl GDT {
             //unused_sd: SegmentDescriptor::new(0, 0, 0),
             k_code_sd: SegmentDescriptor::new(0, 0xFFFFFFFF, KERNEL_CODE_SEGMENT_FLAGS), 
             k_data_sd: SegmentDescriptor::new(0, 0xFFFFFFFF, KERNEL_DATA_SEGMENT_FLAGS),
-            //u_code_sd: SegmentDescriptor::new(0, 0x00FFFFFF, USER_CODE_SEGMENT_FLAGS), 
-            //u_data_sd: SegmentDescriptor::new(0, 0x00FFFFFF, USER_DATA_SEGMENT_FLAGS), 
-            //task_state_sd: SegmentDescriptor::new((64*1024*1024 * 4) + 1, 0, TASK_STATE_SEGMENT_FLAGS), // size = 64KiB
+            // flat as the kernel ones, the user space is protected by paging
+            u_code_sd: SegmentDescriptor::new(0, 0xFFFFFFFF, USER_CODE_SEGMENT_FLAGS), 
+            u_data_sd: SegmentDescriptor::new(0, 0xFFFFFFFF, USER_DATA_SEGMENT_FLAGS), 
+            task_state_sd: SegmentDescriptor::new_system(
+                core::ptr::addr_of!(TSS) as u32,
+                core::mem::size_of::<TaskStateSegment>() as u32 - 1,
+                TASK_STATE_SEGMENT_FLAGS
+            ),
         }
     }
 
@@ -154,6 +251,7 @@ impl GDT {
             core::arch::asm!("lgdt [{}]", in(reg) &gdt, options(readonly, nostack, preserves_flags));
             //Self::print_gdt();
             reloadSegments();
+            core::arch::asm!("ltr {0:x}", in(reg) TSS_SELECTOR, options(nomem, nostack, preserves_flags));
         }
     }
 
@@ -182,7 +280,7 @@ impl GDT {
                 }
             }
 
-            let n_segments = 3;
+            let n_segments = (gdt.limit as isize + 1) / 8;
             for index_segment in 0..n_segments {
                 let base_segment_offset = index_segment * 8;
                 let limit = (*(gdt.base as *const u16) as u32) & 
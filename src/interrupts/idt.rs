@@ -1,4 +1,8 @@
 use super::{ *, interrupt_manager::*};
+use super::interrupt_frame::InterruptFrame;
+use crate::symbols::Symbolized;
+use crate::task::{process, scheduler};
+use crate::{info, warn};
 use core::arch::asm;
 
 // TODO make a macro to create all the handlers
@@ -42,6 +46,9 @@ extern {
     pub fn handleInterruptRequest0x0D();
     pub fn handleInterruptRequest0x0E();
     pub fn handleInterruptRequest0x0F();
+
+    pub fn handleSoftwareInterrupt0x80();
+    pub fn handleSoftwareInterrupt0x81();
 }
 
 // TODO change in a future
@@ -115,8 +122,19 @@ impl IDT {
         let mut handlers: [Option<fn(&IDT, u32) -> u32>; 256] = [None; 256];
 
         // set up handlers
+        handlers[0x0E] = Some(handle_page_fault);
         handlers[(interrupt_offset + 0x00) as usize] = Some(handle_pit);
         handlers[(interrupt_offset + 0x01) as usize] = Some(handle_keyboard_interrupt);
+        handlers[(interrupt_offset + crate::serial::COM1_IRQ) as usize] = Some(handle_serial_interrupt);
+        handlers[SYSCALL_INTERRUPT as usize] = Some(handle_syscall);
+        handlers[YIELD_INTERRUPT as usize] = Some(handle_yield);
+        #[cfg(feature = "gdb_stub")]
+        {
+            // single step and breakpoints
+            handlers[0x01] = Some(crate::gdb_stub::handle_debug_exception);
+            handlers[0x03] = Some(crate::gdb_stub::handle_debug_exception);
+            handlers[(interrupt_offset + crate::serial::COM2_IRQ) as usize] = Some(crate::gdb_stub::handle_serial_interrupt);
+        }
 
         let mut idt_struct = IDT {
             idt: [GateDescritor::new(interruptIgnore, code_segment, 0, 0xE); 256],
@@ -175,6 +193,10 @@ impl IDT {
         idt_struct.idt[(interrupt_offset + 0x0D) as usize].update(handleInterruptRequest0x0D, code_segment, 0, 0xE);
         idt_struct.idt[(interrupt_offset + 0x0E) as usize].update(handleInterruptRequest0x0E, code_segment, 0, 0xE);
         idt_struct.idt[(interrupt_offset + 0x0F) as usize].update(handleInterruptRequest0x0F, code_segment, 0, 0xE);
+
+        // ring 3 to be reachable from user mode, trap gate (0xF) to not disable the interrupts
+        idt_struct.idt[SYSCALL_INTERRUPT as usize].update(handleSoftwareInterrupt0x80, code_segment, 3, 0xF);
+        idt_struct.idt[YIELD_INTERRUPT as usize].update(handleSoftwareInterrupt0x81, code_segment, 0, 0xE);
         
         //// Comunicate with PIC master and slave
         idt_struct.pic_master_command.write(0x11);
@@ -226,7 +248,7 @@ impl IDT {
             // init also the drivers => not the best place in the future
             init_drivers();
         }
-        println!("Activated interrupts!");
+        info!("Activated interrupts!");
     }
     
     /// Disable interrupts.
@@ -250,8 +272,27 @@ impl IDT {
 
         if let Some(handler) = self.handlers[interrupt_number as usize] {
                 new_esp = handler(self, esp);
+        } else if interrupt_number < 0x20 {
+            // cpu exception, returning would run the faulting instruction again
+            let frame = unsafe { InterruptFrame::from_esp(esp) };
+            if frame.as_user_frame().is_some() {
+                warn!(
+                    "Exception 0x{:02x} not managed: task {} killed, eip: 0x{:X}, error code: 0x{:X}",
+                    interrupt_number,
+                    scheduler::current_id().get(),
+                    frame.eip,
+                    frame.error_code
+                );
+                process::exit(FAULT_EXIT_STATUS);
+            }
+            panic!(
+                "Exception 0x{:02x} not managed! eip: {}, error code: 0x{:X}",
+                interrupt_number,
+                Symbolized(frame.eip as usize),
+                frame.error_code
+            );
         } else {
-            println!("Interrupt 0x{:02x} not managed!", interrupt_number);
+            warn!("Interrupt 0x{:02x} not managed!", interrupt_number);
             new_esp = esp;
         }
     
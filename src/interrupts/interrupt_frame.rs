@@ -0,0 +1,72 @@
+/// Layout of the stack built by interrupt_first_handler (interrupt_handlers.s),
+/// the esp passed to every handler point to the beginning of this struct
+///
+/// From the lowest address:
+/// + segments pushed by hand
+/// + general purpose registers pushed by pushad
+/// + interrupt number pushed by the stub
+/// + error code, pushed by the cpu or a fake one pushed by the stub
+/// + eip, cs, eflags pushed by the cpu
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct InterruptFrame {
+    pub gs: u32,
+    pub fs: u32,
+    pub es: u32,
+    pub ds: u32,
+
+    pub edi: u32,
+    pub esi: u32,
+    pub ebp: u32,
+    // value of esp before pushad, ignored by popad
+    pub esp: u32,
+    pub ebx: u32,
+    pub edx: u32,
+    pub ecx: u32,
+    pub eax: u32,
+
+    pub interrupt_number: u32,
+    pub error_code: u32,
+
+    pub eip: u32,
+    pub cs: u32,
+    pub eflags: u32,
+}
+
+/// When the interrupt arrives from ring 3 the cpu switch to the
+/// kernel stack (TSS esp0) and push also the user stack
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct UserInterruptFrame {
+    pub frame: InterruptFrame,
+    pub user_esp: u32,
+    pub user_ss: u32,
+}
+
+/// Interrupt Flag inside eflags
+pub const EFLAGS_IF: u32 = 0x200;
+/// Bit 1 of eflags is reserved and always 1
+pub const EFLAGS_RESERVED: u32 = 0x2;
+
+impl InterruptFrame {
+    /// Get the frame saved on the stack
+    ///
+    /// esp MUST be the value passed to an interrupt handler
+    pub unsafe fn from_esp<'a>(esp: u32) -> &'a mut InterruptFrame {
+        &mut *(esp as usize as *mut InterruptFrame)
+    }
+
+    /// The interrupted code was running in ring 3
+    pub fn from_user(&self) -> bool {
+        self.cs & 0x3 == 3
+    }
+
+    /// The whole frame with the user stack, None if the interrupt did not come from ring 3
+    pub fn as_user_frame(&mut self) -> Option<&mut UserInterruptFrame> {
+        if !self.from_user() {
+            return None;
+        }
+        // the cpu pushed user esp and ss just after eflags
+        Some(unsafe { &mut *(self as *mut InterruptFrame as *mut UserInterruptFrame) })
+    }
+}
@@ -9,18 +9,39 @@
 
 .section .text
 
+    // push a fake error code so every frame on the stack has the same layout
+    // the interrupt number is pushed on the stack and not saved in a global variable,
+    // the syscall gate leave the interrupts enabled so handlers can be nested
     .macro HandleException num
     .global handleException\num
     handleException\num:
-        mov byte ptr [interruptnumber], \num
+        push 0
+        push \num
+        jmp interrupt_first_handler
+    .endm
+
+    // the cpu already pushed the error code
+    .macro HandleExceptionWithErrorCode num
+    .global handleException\num
+    handleException\num:
+        push \num
         jmp interrupt_first_handler
     .endm
-    
     
     .macro HandleInterruptRequest num
     .global handleInterruptRequest\num
     handleInterruptRequest\num:
-        mov byte ptr [interruptnumber], \num + IRQ_BASE
+        push 0
+        push \num + IRQ_BASE
+        jmp interrupt_first_handler
+    .endm
+
+    // software interrupts, the number is already the vector
+    .macro HandleSoftwareInterrupt num
+    .global handleSoftwareInterrupt\num
+    handleSoftwareInterrupt\num:
+        push 0
+        push \num
         jmp interrupt_first_handler
     .endm
 
@@ -32,16 +53,16 @@
     HandleException 0x05
     HandleException 0x06
     HandleException 0x07
-    HandleException 0x08
+    HandleExceptionWithErrorCode 0x08
     HandleException 0x09
-    HandleException 0x0A
-    HandleException 0x0B
-    HandleException 0x0C
-    HandleException 0x0D
-    HandleException 0x0E
+    HandleExceptionWithErrorCode 0x0A
+    HandleExceptionWithErrorCode 0x0B
+    HandleExceptionWithErrorCode 0x0C
+    HandleExceptionWithErrorCode 0x0D
+    HandleExceptionWithErrorCode 0x0E
     HandleException 0x0F
     HandleException 0x10
-    HandleException 0x11
+    HandleExceptionWithErrorCode 0x11
     HandleException 0x12
     HandleException 0x13
     
@@ -61,7 +82,9 @@
     HandleInterruptRequest 0x0D
     HandleInterruptRequest 0x0E
     HandleInterruptRequest 0x0F
-    HandleInterruptRequest 0x31
+
+    HandleSoftwareInterrupt 0x80 // syscall
+    HandleSoftwareInterrupt 0x81 // yield
 
     interrupt_first_handler:
        pushad // -> 32 bit general purpose registers; pusha -> 16 bit 
@@ -69,9 +92,16 @@
        push es
        push fs
        push gs
+
+       // the interrupt could come from ring 3, use the kernel data segment (see gdt.rs)
+       mov ax, 0x10
+       mov ds, ax
+       mov es, ax
+       mov fs, ax
+       mov gs, ax
     
        push esp
-       push interruptnumber // automatically deferenciate
+       push dword ptr [esp + 52] // interrupt number, over esp, 4 segments and 8 registers
        call handle_interrupts
     
        // theorically this will jump over the old esp value, the interruptnumber and pointing to the last element
@@ -83,10 +113,10 @@
        pop es
        pop ds
        popad 
+
+       add esp, 8 // skip the interrupt number and the error code
+       iret
     
 .global interruptIgnore
     interruptIgnore:
        iret
-
-.section .data
-    interruptnumber: .byte 0
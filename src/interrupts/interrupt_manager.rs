@@ -1,16 +1,101 @@
-use super::{*, idt::IDT };
-use crate::print;
+use super::{*, idt::IDT, interrupt_frame::InterruptFrame };
+use crate::concurrency::{spin_mutex::SpinMutex, wait_queue::WaitQueue};
+use crate::memory_manager::paging::VirtualAddr;
+use crate::symbols::Symbolized;
+use crate::{debug, print, warn};
+use crate::task::{process, scheduler};
+use alloc::collections::VecDeque;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 pub fn init_drivers() {
+    init_pit();
     init_keyboard();
+    crate::serial::init();
 }
 
 // pit = programmable interrupt timer
+pub const PIT_FREQUENCY: u32 = 100; // Hz
+const PIT_BASE_FREQUENCY: u32 = 1193182; // Hz
+
+pub fn init_pit() {
+    let command_port = Port8Bit::new(0x43);
+    let channel_0_port = Port8Bit::new(0x40);
+
+    let divisor = PIT_BASE_FREQUENCY / PIT_FREQUENCY;
+
+    command_port.write(0x36); // channel 0, low byte than high byte, square wave
+    channel_0_port.write((divisor & 0xFF) as u8);
+    channel_0_port.write(((divisor >> 8) & 0xFF) as u8);
+}
+
+// pit ticks from the activation of the interrupts, outside of the scheduler
+// so it can be read while holding any lock
+static PIT_TICKS: AtomicUsize = AtomicUsize::new(0);
+
+pub fn uptime_ticks() -> usize {
+    PIT_TICKS.load(Ordering::Relaxed)
+}
+
 pub fn handle_pit(_idt: &IDT, esp: u32) -> u32 {
     //print!(".");
+    PIT_TICKS.fetch_add(1, Ordering::Relaxed);
+    scheduler::schedule(esp)
+}
+
+// bits of the page fault error code
+const PAGE_FAULT_WRITE: u32 = 0x2;
+
+// exit status of a process killed by an invalid access or by an exception
+pub(super) const FAULT_EXIT_STATUS: i32 = -1;
+
+/// Faults on the user space are resolved by the address space of the running task
+/// (demand paging and copy on write), everything else kills the user process
+/// or is a kernel bug
+pub fn handle_page_fault(_idt: &IDT, esp: u32) -> u32 {
+    let frame = unsafe { InterruptFrame::from_esp(esp) };
+    let addr: usize;
+    unsafe { asm!("mov {}, cr2", out(reg) addr, options(nomem, nostack, preserves_flags)) };
+    let virt = VirtualAddr::new(addr);
+
+    let write = frame.error_code & PAGE_FAULT_WRITE != 0;
+    let resolved = scheduler::with_current_address_space(|address_space| {
+        address_space.handle_page_fault(&virt, write).is_ok()
+    });
+    if resolved == Some(true) {
+        return esp;
+    }
+
+    if frame.from_user() {
+        warn!(
+            "Segmentation fault: task {} at 0x{:X}, eip: 0x{:X}, error code: 0x{:X}",
+            scheduler::current_id().get(),
+            addr,
+            frame.eip,
+            frame.error_code
+        );
+        process::exit(FAULT_EXIT_STATUS);
+    }
+
+    panic!(
+        "Page fault at 0x{:X}, eip: {}, error code: 0x{:X}",
+        addr,
+        Symbolized(frame.eip as usize),
+        frame.error_code
+    );
+}
+
+/// Number and arguments are in the saved registers,
+/// the return value is written back in the saved eax
+pub fn handle_syscall(_idt: &IDT, esp: u32) -> u32 {
+    let frame = unsafe { InterruptFrame::from_esp(esp) };
+    crate::syscall::dispatch(frame);
     esp
 }
 
+pub fn handle_yield(_idt: &IDT, esp: u32) -> u32 {
+    scheduler::reschedule(esp)
+}
+
 // this function should only need the data and command port but still get all the idt -> do it
 // better in the future
 pub fn handle_keyboard_interrupt(idt: &IDT, esp: u32) -> u32 {
@@ -26,71 +111,123 @@ pub fn handle_keyboard_interrupt(idt: &IDT, esp: u32) -> u32 {
 
     // for now avoid releasing
 
-    unsafe {
+    let key = unsafe {
         match scancode {
-            0x02 => if !shift { print!("1") } else { print!("!") } ,
-            0x03 => if !shift { print!("2") } else { print!("@") },
-            0x04 => if !shift { print!("3") } else { print!("#") },
-            0x05 => if !shift { print!("4") } else { print!("$") },
-            0x06 => if !shift { print!("5") } else { print!("%") },
-            0x07 => if !shift { print!("6") } else { print!("^") },
-            0x08 => if !shift { print!("7") } else { print!("&") },
-            0x09 => if !shift { print!("8") } else { print!("*") },
-            0x0A => if !shift { print!("9") } else { print!("(") },
-            0x0B => if !shift { print!("0") } else { print!(")") },
-
-            0x10 => if !shift { print!("q") } else { print!("Q") },
-            0x11 => if !shift { print!("w") } else { print!("W") },
-            0x12 => if !shift { print!("e") } else { print!("E") },
-            0x13 => if !shift { print!("r") } else { print!("R") },
-            0x14 => if !shift { print!("t") } else { print!("T") },
-            0x15 => if !shift { print!("z") } else { print!("Z") },
-            0x16 => if !shift { print!("u") } else { print!("U") },
-            0x17 => if !shift { print!("i") } else { print!("I") },
-            0x18 => if !shift { print!("o") } else { print!("O") },
-            0x19 => if !shift { print!("p") } else { print!("P") },
-
-            0x1E => if !shift { print!("a") } else { print!("A") },
-            0x1F => if !shift { print!("s") } else { print!("S") },
-            0x20 => if !shift { print!("d") } else { print!("D") },
-            0x21 => if !shift { print!("f") } else { print!("F") },
-            0x22 => if !shift { print!("g") } else { print!("G") },
-            0x23 => if !shift { print!("h") } else { print!("H") },
-            0x24 => if !shift { print!("j") } else { print!("J") },
-            0x25 => if !shift { print!("k") } else { print!("K") },
-            0x26 => if !shift { print!("l") } else { print!("L") },
-
-            0x2C => if !shift { print!("y") } else { print!("Y") },
-            0x2D => if !shift { print!("x") } else { print!("X") },
-            0x2E => if !shift { print!("c") } else { print!("C") },
-            0x2F => if !shift { print!("v") } else { print!("V") },
-            0x30 => if !shift { print!("b") } else { print!("B") },
-            0x31 => if !shift { print!("n") } else { print!("N") },
-            0x32 => if !shift { print!("m") } else { print!("M") },
-            0x33 => if !shift { print!(",") } else { print!("<") },
-            0x34 => if !shift { print!(".") } else { print!(">") },
-            0x35 => if !shift { print!("-") } else { print!("_") },
+            0x02 => if !shift { Some('1') } else { Some('!') },
+            0x03 => if !shift { Some('2') } else { Some('@') },
+            0x04 => if !shift { Some('3') } else { Some('#') },
+            0x05 => if !shift { Some('4') } else { Some('$') },
+            0x06 => if !shift { Some('5') } else { Some('%') },
+            0x07 => if !shift { Some('6') } else { Some('^') },
+            0x08 => if !shift { Some('7') } else { Some('&') },
+            0x09 => if !shift { Some('8') } else { Some('*') },
+            0x0A => if !shift { Some('9') } else { Some('(') },
+            0x0B => if !shift { Some('0') } else { Some(')') },
+
+            0x10 => if !shift { Some('q') } else { Some('Q') },
+            0x11 => if !shift { Some('w') } else { Some('W') },
+            0x12 => if !shift { Some('e') } else { Some('E') },
+            0x13 => if !shift { Some('r') } else { Some('R') },
+            0x14 => if !shift { Some('t') } else { Some('T') },
+            0x15 => if !shift { Some('z') } else { Some('Z') },
+            0x16 => if !shift { Some('u') } else { Some('U') },
+            0x17 => if !shift { Some('i') } else { Some('I') },
+            0x18 => if !shift { Some('o') } else { Some('O') },
+            0x19 => if !shift { Some('p') } else { Some('P') },
+
+            0x1E => if !shift { Some('a') } else { Some('A') },
+            0x1F => if !shift { Some('s') } else { Some('S') },
+            0x20 => if !shift { Some('d') } else { Some('D') },
+            0x21 => if !shift { Some('f') } else { Some('F') },
+            0x22 => if !shift { Some('g') } else { Some('G') },
+            0x23 => if !shift { Some('h') } else { Some('H') },
+            0x24 => if !shift { Some('j') } else { Some('J') },
+            0x25 => if !shift { Some('k') } else { Some('K') },
+            0x26 => if !shift { Some('l') } else { Some('L') },
+
+            0x2C => if !shift { Some('y') } else { Some('Y') },
+            0x2D => if !shift { Some('x') } else { Some('X') },
+            0x2E => if !shift { Some('c') } else { Some('C') },
+            0x2F => if !shift { Some('v') } else { Some('V') },
+            0x30 => if !shift { Some('b') } else { Some('B') },
+            0x31 => if !shift { Some('n') } else { Some('N') },
+            0x32 => if !shift { Some('m') } else { Some('M') },
+            0x33 => if !shift { Some(',') } else { Some('<') },
+            0x34 => if !shift { Some('.') } else { Some('>') },
+            0x35 => if !shift { Some('-') } else { Some('_') },
             // to be added more signes
 
-            0x1C => print!("\n"),
-            0x39 => print!(" "),
+            0x1C => Some('\n'),
+            0x39 => Some(' '),
 
-            0x2A => shift = true, // press shift
-            0xAA => shift = false, // release shift
+            0x2A => { shift = true; None }, // press shift
+            0xAA => { shift = false; None }, // release shift
 
             _ => {
                 // avodi dealing with releas keycode
                 if scancode < 0x80 {
-                    println!("{:02x}", scancode);
+                    debug!("Unknown scancode 0x{:02x}", scancode);
                 }
+                None
             }
 
         }
+    };
+
+    if let Some(key) = key {
+        print!("{}", key);
+        push_key(key);
+    }
+
+    esp
+}
+
+/// What is typed on the terminal of COM1 is read like the keyboard
+pub fn handle_serial_interrupt(_idt: &IDT, esp: u32) -> u32 {
+    // the uart keeps the irq raised until everything received is read
+    loop {
+        // the lock can't be held while printing, the console is mirrored on the same port
+        let byte = crate::serial::SERIAL1.lock().read_byte();
+        let key = match byte {
+            Some(b'\r') | Some(b'\n') => '\n',
+            Some(byte @ 0x20..=0x7E) => byte as char,
+            Some(_) => continue,
+            None => break,
+        };
+
+        print!("{}", key);
+        push_key(key);
     }
 
     esp
 }
 
+// Keys not read yet by anyone, the oldest are discarded if nobody is reading
+const KEYBOARD_BUFFER_SIZE: usize = 256;
+static KEYBOARD_BUFFER: SpinMutex<VecDeque<char>> = SpinMutex::new(VecDeque::new());
+static KEYBOARD_WAITERS: WaitQueue = WaitQueue::new();
+
+fn push_key(key: char) {
+    {
+        let mut buffer = KEYBOARD_BUFFER.lock();
+        if buffer.len() == KEYBOARD_BUFFER_SIZE {
+            buffer.pop_front();
+        }
+        buffer.push_back(key);
+    }
+    KEYBOARD_WAITERS.notify_one();
+}
+
+/// Return the next key pressed, block the task until something is avaiable
+pub fn read_key() -> char {
+    without_interrupts(|| loop {
+        if let Some(key) = KEYBOARD_BUFFER.lock().pop_front() {
+            return key;
+        }
+        KEYBOARD_WAITERS.wait();
+    })
+}
+
 pub fn init_keyboard() {
     let data_port = Port8Bit::new(0x60);
     let command_port = Port8Bit::new(0x64);
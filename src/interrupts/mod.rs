@@ -1,7 +1,59 @@
-use super::vga_buffer::println;
 use super::gdt::GDT;
 use super::port::Port8Bit;
 
 pub mod idt;
+pub mod interrupt_frame;
 // not give access to interrupt_manager outside of this module
 mod interrupt_manager;
+
+pub use interrupt_manager::{read_key, uptime_ticks, PIT_FREQUENCY};
+
+use core::arch::asm;
+
+/// Software interrupt used by user programs to call the kernel (see syscall),
+/// handleSoftwareInterrupt0x80 in interrupt_handlers.s
+pub const SYSCALL_INTERRUPT: u8 = 0x80;
+
+/// Software interrupt used by a task to give up the cpu (scheduler::yield_now),
+/// handleSoftwareInterrupt0x81 in interrupt_handlers.s
+pub const YIELD_INTERRUPT: u8 = 0x81;
+
+/// Return true if the Interrupt Flag is set in eflags
+#[cfg(any(not(test), target_os = "none"))]
+pub fn are_enabled() -> bool {
+    let eflags: u32;
+    unsafe {
+        asm!("pushfd", "pop {}", out(reg) eflags, options(nomem, preserves_flags));
+    }
+    (eflags & interrupt_frame::EFLAGS_IF) != 0
+}
+
+// the host tests run in user mode, the interrupts are never touched there
+#[cfg(all(test, not(target_os = "none")))]
+pub fn are_enabled() -> bool {
+    false
+}
+
+/// Run f with interrupts disabled, the previous state is restored at the end
+///
+/// Used everytime something is shared with an interrupt handler, otherwise
+/// taking a SpinMutex and being interrupted by someone that need the same lock
+/// is a dead lock
+pub fn without_interrupts<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    let were_enabled = are_enabled();
+
+    if were_enabled {
+        unsafe { asm!("cli", options(nomem, nostack)) };
+    }
+
+    let ret = f();
+
+    if were_enabled {
+        unsafe { asm!("sti", options(nomem, nostack)) };
+    }
+
+    ret
+}
@@ -1,6 +1,13 @@
-#![no_std] // don't link the Rust standard library
-#![no_main] // disable all Rust-level entry points
-#![feature(alloc_error_handler)]
+#![cfg_attr(any(not(test), target_os = "none"), no_std)] // don't link the Rust standard library
+#![cfg_attr(any(not(test), target_os = "none"), no_main)] // disable all Rust-level entry points
+#![cfg_attr(any(not(test), target_os = "none"), feature(alloc_error_handler))]
+// cargo test on the host (see test_on_host.sh) tests only the allocators with #[test],
+// inside the kernel (see build_and_test.sh) the tests are #[test_case] run by test_framework.
+// kernel_main is not built on the host: the modules and the items used only from it
+// allow dead code there, everything else must be used also on the host
+#![cfg_attr(all(test, target_os = "none"), feature(custom_test_frameworks))]
+#![cfg_attr(all(test, target_os = "none"), test_runner(crate::test_framework::test_runner))]
+#![cfg_attr(all(test, target_os = "none"), reexport_test_harness_main = "test_main")]
 // TODO: remove this feature, used only once for stupid thing
 #![feature(pointer_byte_offsets)]
 
@@ -10,37 +17,70 @@
 // src/main.rs
 
 mod concurrency;
+mod elf;
+#[cfg(feature = "gdb_stub")]
+#[cfg_attr(all(test, not(target_os = "none")), allow(dead_code, unused_imports))]
+mod gdb_stub;
+#[cfg_attr(all(test, not(target_os = "none")), allow(dead_code, unused_imports))]
 mod gdt;
+#[cfg_attr(all(test, not(target_os = "none")), allow(dead_code, unused_imports))]
 mod init;
+#[cfg_attr(all(test, not(target_os = "none")), allow(dead_code, unused_imports))]
 mod interrupts;
+#[cfg_attr(all(test, not(target_os = "none")), allow(dead_code, unused_imports))]
+mod log;
 mod memory_manager;
+#[cfg_attr(all(test, not(target_os = "none")), allow(dead_code, unused_imports))]
 mod multiboot;
+#[cfg(any(not(test), target_os = "none"))]
+mod panic;
+#[cfg_attr(all(test, not(target_os = "none")), allow(dead_code, unused_imports))]
 mod port;
 mod runtime_static;
+#[cfg_attr(all(test, not(target_os = "none")), allow(dead_code, unused_imports))]
+mod serial;
+#[cfg_attr(all(test, not(target_os = "none")), allow(dead_code))]
+mod stack_frames;
+mod symbols;
+#[cfg_attr(all(test, not(target_os = "none")), allow(dead_code, unused_imports))]
+mod syscall;
+#[cfg_attr(all(test, not(target_os = "none")), allow(dead_code, unused_imports))]
+mod task;
+#[cfg(all(test, target_os = "none"))]
+mod test_framework;
+#[cfg_attr(all(test, not(target_os = "none")), allow(dead_code, unused_imports))]
 mod vga_buffer;
 
 #[macro_use]
 extern crate alloc;
 
 use concurrency::spin_mutex::SpinMutex;
+#[cfg(any(not(test), target_os = "none"))]
 use core::panic::PanicInfo;
-use memory_manager::heap_allocator::HeapAllocator;
+use memory_manager::heap_allocator::GlobalHeap;
 use runtime_static::RuntimeStatic;
 
 /// This function is called on panic.
+#[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    println!("{}", info);
-    loop {}
+    panic::kernel_panic(info)
 }
 
-#[global_allocator]
-static GLOBAL_ALLOC: RuntimeStatic<SpinMutex<HeapAllocator>> = RuntimeStatic::get_uninit();
+#[cfg(all(test, target_os = "none"))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    test_framework::test_panic_handler(info)
+}
 
-#[alloc_error_handler]
-fn handle_alloc_error(layout: core::alloc::Layout) -> ! {
-    let _alloc = GLOBAL_ALLOC.lock();
+// on the host the tests use the allocator of std
+#[cfg_attr(any(not(test), target_os = "none"), global_allocator)]
+static GLOBAL_ALLOC: RuntimeStatic<SpinMutex<GlobalHeap>> = RuntimeStatic::get_uninit();
 
+#[cfg_attr(any(not(test), target_os = "none"), alloc_error_handler)]
+#[cfg_attr(all(test, not(target_os = "none")), allow(dead_code))]
+fn handle_alloc_error(layout: core::alloc::Layout) -> ! {
+    // no heap lock, this could be reached from inside the allocator
     panic!(
         "Allocator failed to allocate: size: {}, align: {}",
         layout.size(),
@@ -48,6 +88,7 @@ fn handle_alloc_error(layout: core::alloc::Layout) -> ! {
     );
 }
 
+#[cfg(any(not(test), target_os = "none"))]
 #[no_mangle]
 pub extern "C" fn kernel_main(
     multiboot_magic_number: usize,
@@ -66,25 +107,37 @@ pub extern "C" fn kernel_main(
     //vga_buffer::WRITER.lock().clear_screen();
     vga_buffer::Writer::init();
 
-    println!("Vga Buffer Ready!");
+    info!("Vga Buffer Ready!");
 
     // All of the following code should finish in some init wrapper
     let gdt = gdt::GDT::new();
     gdt.load();
 
-    println!("GDT loaded!");
+    info!("GDT loaded!");
 
     let idt = interrupts::idt::IDT::new(0x20, &gdt);
     idt.load();
 
-    println!("IDT loaded!");
+    info!("IDT loaded!");
 
-    println!("Activation interrupts!");
+    info!("Activation interrupts!");
     idt.enable();
 
     let boot_info =
         multiboot::BootInfo::new(multiboot_magic_number, multiboot_information_address).unwrap();
 
+    match symbols::init(&boot_info) {
+        Ok(count) => info!("Kernel symbols: {}", count),
+        Err(msg) => warn!("Backtraces without names: {}", msg),
+    }
+
+    #[cfg(feature = "gdb_stub")]
+    {
+        gdb_stub::init();
+        info!("Waiting for gdb on COM2");
+        gdb_stub::breakpoint();
+    }
+
     /* IDK - for now simply use the stack_top as starting poitn
     println!("{:?}", boot_info);
 
@@ -99,63 +152,92 @@ pub extern "C" fn kernel_main(
     */
 
     // Init heap
-    println!("");
-    println!("heap base: 0x{:X}", heap_kernel_bottom);
-    println!("heap top: 0x{:X}", heap_kernel_top);
-
-    GLOBAL_ALLOC.init(SpinMutex::new(HeapAllocator::new(
-        heap_kernel_bottom,
-        heap_kernel_top,
-    )));
-    println!("Initialized Heap Allocator!");
-
-    //memory_manager::heap_allocator::tests::home_made_test();
-
-    println!("");
+    debug!("heap base: 0x{:X}", heap_kernel_bottom);
+    debug!("heap top: 0x{:X}", heap_kernel_top);
+
+    // only the boot heap for now, the rest of the heap can grow once paging is enabled
+    // the heap is inside the .bss, zeroed by the boot loader
+    GLOBAL_ALLOC.init(SpinMutex::new(unsafe {
+        GlobalHeap::new(heap_kernel_bottom, heap_kernel_top)
+    }));
+    info!("Initialized Heap Allocator!");
+
+    // The modules and the symbols are loaded after the kernel,
+    // the frame allocator must not give them away
+    let kernel_end = boot_info
+        .modules()
+        .map(|module| module.end)
+        .chain(
+            boot_info
+                .elf_sections()
+                .iter()
+                .map(|section| (section.addr + section.size) as usize),
+        )
+        .fold(heap_kernel_top, usize::max);
 
     // Init memory manager (enable paging)
-    let mut memory_manager = memory_manager::MemoryManager::new(&boot_info);
+    let mut memory_manager = memory_manager::MemoryManager::new(&boot_info, kernel_end);
     // TODO change witha  lamda
-    match memory_manager.set_up_identity_paging(heap_kernel_top) {
+    match memory_manager.set_up_identity_paging(memory_manager::KERNEL_HEAP_START) {
         Ok(_) => (),
         Err(msg) => panic!("{}", msg),
     };
+    memory_manager
+        .set_up_kernel_heap()
+        .expect("Impossible set up the kernel heap");
     unsafe {
         memory_manager.enable_paging();
     }
 
-    println!("Paging Enabled!");
-    println!("");
-    println!("Testing Paging switching vga_buffer pointer!");
-
-    use memory_manager::paging::{PageDirectoryFlag, PageTableFlag, PhysicalAddr, VirtualAddr};
-    let virtual_vga_buffer = VirtualAddr::new(0x40000000);
-    let physical_vga_buffer = PhysicalAddr::new(0xb8000);
-
-    memory_manager
-        .map_addr_without_paging(
-            virtual_vga_buffer.clone(),
-            physical_vga_buffer,
-            PageDirectoryFlag::Present as u32
-                | PageDirectoryFlag::Writable as u32
-                | PageDirectoryFlag::NotCacheable as u32,
-            PageTableFlag::Present as u32
-                | PageTableFlag::Writable as u32
-                | PageTableFlag::NotCacheable as u32,
-        )
-        .expect("Impossible address mapping");
-
-    // There is a mapping from 0x40000000 to 0xb8000 inside the memory_manager constructor
-    let vga_virtual = VirtualAddr::new(0x40000000);
-    let vga_physical = PhysicalAddr::new(0xb8000);
-
-    let switch_vga_buffer = |addr: usize| unsafe {
-        vga_buffer::WRITER.lock().change_ptr_buffer(addr);
-    };
-    switch_vga_buffer(vga_virtual.get());
-    println!("Switched from 0xB8000 to 0x40000000!");
-    switch_vga_buffer(vga_physical.get());
-    println!("Returned to original pointer");
+    info!("Paging Enabled!");
+    memory_manager::MEMORY_MANAGER.init(SpinMutex::new(memory_manager));
+
+    task::scheduler::init();
+    info!("Scheduler Ready!");
+
+    // built with cargo test the kernel only runs the #[test_case]s, then qemu exits
+    #[cfg(test)]
+    test_main();
+
+    task::spawn_kernel_thread(|| {
+        task::scheduler::sleep(100);
+        println!("Hello from a kernel thread!");
+    });
+
+    // blocked on the keyboard until a whole line is typed
+    task::spawn_kernel_thread(|| loop {
+        let mut line = alloc::string::String::new();
+        loop {
+            match interrupts::read_key() {
+                '\n' => break,
+                key => line.push(key),
+            }
+        }
+        match line.as_str() {
+            "heap" => GLOBAL_ALLOC.dump(),
+            "dmesg" => print!("{}", log::dmesg()),
+            _ => println!("line read: {}", line),
+        }
+    });
+
+    // every module is a program that can be executed, the name is the first word
+    // of the command line. The first module is the init process
+    for module in boot_info.modules() {
+        info!(
+            "module: 0x{:X} - 0x{:X} {}",
+            module.start, module.end, module.cmd_line
+        );
+        let name = module.cmd_line.split_whitespace().next().unwrap_or("");
+        task::process::register_program(name, module.as_slice());
+    }
+    if let Some(module) = boot_info.modules().next() {
+        let argv: alloc::vec::Vec<&str> = module.cmd_line.split_whitespace().collect();
+        match task::spawn_user_process(module.as_slice(), &argv) {
+            Ok(id) => info!("User process {} started!", id.get()),
+            Err(msg) => error!("Impossible start the user process: {}", msg),
+        }
+    }
 
-    loop {}
+    // nothing more to do, from now on the idle task will take care of the cpu
+    task::scheduler::exit_current();
 }
@@ -0,0 +1,339 @@
+use crate::concurrency::spin_mutex::SpinMutex;
+use crate::interrupts::{self, without_interrupts, PIT_FREQUENCY};
+use crate::port::Port8Bit;
+use alloc::string::String;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicU8, Ordering};
+
+// Leveled kernel log
+//
+// A record is formatted only once, on the stack (nothing is allocated, so the allocators
+// and the interrupt handlers can log too), then it is kept in the dmesg ring buffer and
+// written on every sink that accepts its level:
+//
+// [    1.230] INFO  kernel::memory_manager: Paging enabled
+//
+// A record is written if its level is not above the level of its module (the longest
+// prefix set with set_module_level) or the global one
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Level {
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+    Trace = 5,
+}
+
+impl Level {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+}
+
+/// Where the records are written, every sink has its own level
+pub trait Sink: Sync {
+    fn write_str(&self, s: &str);
+}
+
+/// Text mode screen, without the mirror of print! on the serial port
+pub struct VgaSink;
+
+impl Sink for VgaSink {
+    fn write_str(&self, s: &str) {
+        unsafe { crate::vga_buffer::WRITER.lock().write_string(s) };
+    }
+}
+
+/// COM1
+pub struct SerialSink;
+
+impl Sink for SerialSink {
+    fn write_str(&self, s: &str) {
+        let _ = crate::serial::SERIAL1.lock().write_str(s);
+    }
+}
+
+/// Port 0xE9 of qemu (-debugcon stdio) and bochs, nothing happens without them
+pub struct DebugconSink;
+
+const DEBUGCON_PORT: u16 = 0xE9;
+
+impl Sink for DebugconSink {
+    fn write_str(&self, s: &str) {
+        let port = Port8Bit::new(DEBUGCON_PORT);
+        s.bytes().for_each(|byte| port.write(byte));
+    }
+}
+
+pub static VGA_SINK: VgaSink = VgaSink;
+pub static SERIAL_SINK: SerialSink = SerialSink;
+pub static DEBUGCON_SINK: DebugconSink = DebugconSink;
+
+const MAX_SINKS: usize = 4;
+const MAX_MODULE_LEVELS: usize = 16;
+// longer records are truncated
+const MAX_RECORD_SIZE: usize = 256;
+const DMESG_SIZE: usize = 64 * 1024;
+
+struct Logger {
+    // used by the modules without a level
+    level: Level,
+    module_levels: [Option<(&'static str, Level)>; MAX_MODULE_LEVELS],
+    sinks: [Option<(&'static dyn Sink, Level)>; MAX_SINKS],
+}
+
+impl Logger {
+    fn level_of(&self, module: &str) -> Level {
+        let mut level = self.level;
+        let mut longest_prefix = 0;
+
+        for (prefix, prefix_level) in self.module_levels.iter().flatten() {
+            let matches = module == *prefix
+                || (module.starts_with(prefix) && module[prefix.len()..].starts_with("::"));
+            if matches && prefix.len() > longest_prefix {
+                longest_prefix = prefix.len();
+                level = *prefix_level;
+            }
+        }
+        level
+    }
+
+    // the highest level that can be written, to discard the records without the lock
+    fn max_level(&self) -> Level {
+        self.module_levels
+            .iter()
+            .flatten()
+            .map(|(_, level)| *level)
+            .fold(self.level, Level::max)
+    }
+}
+
+static LOGGER: SpinMutex<Logger> = SpinMutex::new(Logger {
+    level: Level::Info,
+    module_levels: [None; MAX_MODULE_LEVELS],
+    sinks: [
+        Some((&VGA_SINK, Level::Info)),
+        Some((&SERIAL_SINK, Level::Trace)),
+        Some((&DEBUGCON_SINK, Level::Trace)),
+        None,
+    ],
+});
+
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+/// Last records written, the oldest are overwritten
+struct RingBuffer {
+    data: [u8; DMESG_SIZE],
+    start: usize,
+    len: usize,
+}
+
+impl RingBuffer {
+    fn push(&mut self, s: &str) {
+        for byte in s.bytes() {
+            if self.len == DMESG_SIZE {
+                self.start = (self.start + 1) % DMESG_SIZE;
+            } else {
+                self.len += 1;
+            }
+            // only ascii, so dmesg never splits a character
+            let byte = if byte.is_ascii() { byte } else { b'?' };
+            self.data[(self.start + self.len - 1) % DMESG_SIZE] = byte;
+        }
+    }
+}
+
+// all zero, in the .bss
+static DMESG: SpinMutex<RingBuffer> = SpinMutex::new(RingBuffer {
+    data: [0; DMESG_SIZE],
+    start: 0,
+    len: 0,
+});
+
+/// Record formatted on the stack
+struct Record {
+    data: [u8; MAX_RECORD_SIZE],
+    len: usize,
+}
+
+impl Record {
+    fn as_str(&self) -> &str {
+        // write_str never cuts a character
+        core::str::from_utf8(&self.data[..self.len]).unwrap_or("")
+    }
+}
+
+impl Write for Record {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        // keep the space for the last new line
+        let space = MAX_RECORD_SIZE - 1 - self.len;
+        let mut size = s.len().min(space);
+        while !s.is_char_boundary(size) {
+            size -= 1;
+        }
+        self.data[self.len..self.len + size].copy_from_slice(&s.as_bytes()[..size]);
+        self.len += size;
+        Ok(())
+    }
+}
+
+pub fn set_level(level: Level) {
+    without_interrupts(|| {
+        let mut logger = LOGGER.lock();
+        logger.level = level;
+        MAX_LEVEL.store(logger.max_level() as u8, Ordering::Relaxed);
+    });
+}
+
+/// Level of module and of its submodules (like "kernel::memory_manager")
+pub fn set_module_level(module: &'static str, level: Level) -> Result<(), &'static str> {
+    without_interrupts(|| {
+        let mut logger = LOGGER.lock();
+        let slot = match logger
+            .module_levels
+            .iter()
+            .position(|slot| matches!(slot, Some((prefix, _)) if *prefix == module))
+        {
+            Some(index) => index,
+            None => logger
+                .module_levels
+                .iter()
+                .position(|slot| slot.is_none())
+                .ok_or("Too many module levels")?,
+        };
+
+        logger.module_levels[slot] = Some((module, level));
+        MAX_LEVEL.store(logger.max_level() as u8, Ordering::Relaxed);
+        Ok(())
+    })
+}
+
+/// The sink will receive the records with a level not above level
+pub fn add_sink(sink: &'static dyn Sink, level: Level) -> Result<(), &'static str> {
+    without_interrupts(|| {
+        let mut logger = LOGGER.lock();
+        let slot = logger
+            .sinks
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or("Too many log sinks")?;
+        *slot = Some((sink, level));
+        Ok(())
+    })
+}
+
+pub fn remove_sink(sink: &'static dyn Sink) {
+    without_interrupts(|| {
+        for slot in LOGGER.lock().sinks.iter_mut() {
+            if matches!(slot, Some((other, _)) if core::ptr::addr_eq(*other, sink)) {
+                *slot = None;
+            }
+        }
+    });
+}
+
+/// Copy of the records still in the ring buffer, the oldest first
+pub fn dmesg() -> String {
+    let mut records = String::with_capacity(DMESG_SIZE);
+
+    without_interrupts(|| {
+        let dmesg = DMESG.lock();
+        let end = dmesg.start + dmesg.len;
+        let (first, second) = if end <= DMESG_SIZE {
+            (&dmesg.data[dmesg.start..end], &dmesg.data[..0])
+        } else {
+            (&dmesg.data[dmesg.start..], &dmesg.data[..end - DMESG_SIZE])
+        };
+
+        // the buffer contains only ascii
+        for part in [first, second] {
+            records.push_str(unsafe { core::str::from_utf8_unchecked(part) });
+        }
+    });
+
+    // the oldest record could be cut, it starts after the first new line
+    if records.len() == DMESG_SIZE {
+        let first_line = records.find('\n').map_or(records.len(), |end| end + 1);
+        records.drain(..first_line);
+    }
+    records
+}
+
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => (
+        $crate::log::_log($level, module_path!(), format_args!($($arg)*))
+    );
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Error, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Warn, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Info, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Debug, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Trace, $($arg)*));
+}
+
+#[doc(hidden)]
+pub fn _log(level: Level, module: &'static str, args: fmt::Arguments) {
+    if level as u8 > MAX_LEVEL.load(Ordering::Relaxed) {
+        return;
+    }
+
+    without_interrupts(|| {
+        let logger = LOGGER.lock();
+        if level > logger.level_of(module) {
+            return;
+        }
+
+        let ticks = interrupts::uptime_ticks();
+        let mut record = Record {
+            data: [0; MAX_RECORD_SIZE],
+            len: 0,
+        };
+        let _ = write!(
+            record,
+            "[{:5}.{:03}] {:5} {}: {}",
+            ticks / PIT_FREQUENCY as usize,
+            ticks % PIT_FREQUENCY as usize * 1000 / PIT_FREQUENCY as usize,
+            level.name(),
+            module,
+            args
+        );
+        record.data[record.len] = b'\n';
+        record.len += 1;
+
+        let record = record.as_str();
+        DMESG.lock().push(record);
+        for (sink, sink_level) in logger.sinks.iter().flatten() {
+            if level <= *sink_level {
+                sink.write_str(record);
+            }
+        }
+    });
+}
@@ -0,0 +1,605 @@
+use super::{frame_allocator::Frame, paging::*, vma::VmaList, *};
+
+/// Virtual memory of a process
+///
+/// The kernel entries of the page directory are copied from the kernel page directory,
+/// so the kernel page tables are shared between every AddressSpace. Everything mapped in
+/// the user space (page tables and frames) is owned by the AddressSpace and is freed on drop
+///
+/// Pages can be mapped directly (map_page, alloc_page) or reserved inside a Vma,
+/// in the latter case the frame is allocated by the page fault handler on the first access
+pub struct AddressSpace {
+    page_directory: PageDirectory,
+    vmas: VmaList,
+    // the heap of the program is [heap_start, program_break)
+    heap_start: usize,
+    program_break: usize,
+}
+
+impl AddressSpace {
+    pub fn new() -> Result<Self, &'static str> {
+        with_memory_manager(|mm| {
+            let frame = mm
+                .allocate_zeroed_frame()
+                .ok_or("Impossible alloc a frame for the page directory")?;
+            let mut page_directory = PageDirectory::from_physical_address(frame.get_physical_addr());
+
+            let kernel_page_directory = mm.kernel_page_directory();
+            for i in 0..KERNEL_PD_ENTRIES {
+                page_directory[i] = kernel_page_directory[i];
+            }
+
+            Ok(Self {
+                page_directory,
+                vmas: VmaList::new(),
+                heap_start: USER_SPACE_START,
+                program_break: USER_SPACE_START,
+            })
+        })
+    }
+
+    /// Create a copy of the address space (used by fork), every user frame is shared.
+    /// The writable pages become read only with the CopyOnWrite flag in both the
+    /// address spaces, the first write will copy the frame (see resolve_cow)
+    pub fn duplicate(&mut self) -> Result<Self, &'static str> {
+        let mut copy = Self::new()?;
+        copy.vmas = self.vmas.clone();
+        copy.heap_start = self.heap_start;
+        copy.program_break = self.program_break;
+
+        for pd_index in KERNEL_PD_ENTRIES..ENTRIES_PER_PAGE {
+            if !self.page_directory[pd_index].is_valid_flag(PageDirectoryFlag::Present as u32) {
+                continue;
+            }
+
+            let mut table = self.page_directory[pd_index].get_page_table();
+            for pt_index in 0..ENTRIES_PER_PAGE {
+                let pte = &mut table[pt_index];
+                if !pte.is_valid_flag(PageTableFlag::Present as u32) {
+                    continue;
+                }
+
+                if pte.is_valid_flag(PageTableFlag::Writable as u32) {
+                    pte.del_attribute(PageTableFlag::Writable as u32);
+                    pte.add_attribute(PageTableFlag::CopyOnWrite as u32);
+                }
+
+                let virt = VirtualAddr::new((pd_index * ENTRIES_PER_PAGE + pt_index) * PAGE_SIZE);
+                let flags = pte.get_flags() & !(PageTableFlag::Present as u32);
+                let frame = pte.get_frame();
+
+                // if this fails copy is dropped and every reference taken is released
+                with_memory_manager(|mm| {
+                    copy.map_page_inner(mm, &virt, frame.clone(), flags)?;
+                    mm.frame_allocator().add_reference(&frame);
+                    Ok::<(), &'static str>(())
+                })?;
+                self.flush(&virt);
+            }
+        }
+
+        Ok(copy)
+    }
+
+    /// Called by the page fault handler, resolve the fault allocating the page of a Vma
+    /// or copying a CopyOnWrite page. Err if the access is invalid
+    pub fn handle_page_fault(&mut self, virt: &VirtualAddr, write: bool) -> Result<(), &'static str> {
+        let page = VirtualAddr::new(virt.get() & !(PAGE_SIZE - 1));
+        check_user_page(&page)?;
+
+        match self.get_page_flags(&page) {
+            Some(_) if write => self.resolve_cow(&page),
+            Some(_) => Err("Protection violation"),
+            None => self.populate(&page, write),
+        }
+    }
+
+    /// Reserve [start, start + len) for anonymous memory, the pages will be
+    /// allocated (zeroed) with flags on the first access. Without PageTableFlag::User
+    /// the area is reserved but every access is invalid
+    ///
+    /// The range must be free, touching areas with the same flags are merged
+    pub fn reserve(&mut self, start: usize, len: usize, flags: u32) -> Result<(), &'static str> {
+        let end = start.checked_add(len).ok_or("Area overflow the address space")?;
+        if start < USER_SPACE_START || end > USER_SPACE_END {
+            return Err("Area outside of the user space");
+        }
+
+        if !self.vmas.is_free(start, end) {
+            return Err("Area overlaps another one");
+        }
+        // pages mapped directly (like the ELF segments) are not inside any area
+        if self.next_mapped_page(start, end).is_some() {
+            return Err("Area overlaps mapped pages");
+        }
+
+        self.vmas.insert(start, end, flags)
+    }
+
+    /// Remove [start, start + len) from the areas, the pages already allocated are freed
+    pub fn unreserve(&mut self, start: usize, len: usize) -> Result<(), &'static str> {
+        let end = start.checked_add(len).ok_or("Area overflow the address space")?;
+        if start % PAGE_SIZE != 0 || end % PAGE_SIZE != 0 {
+            return Err("Area not page aligned");
+        }
+
+        for vma in self.vmas.remove(start, end) {
+            self.unmap_range(vma.start, vma.end)?;
+        }
+        Ok(())
+    }
+
+    /// Replace everything inside [start, start + len), areas and pages mapped directly,
+    /// with a new reserved area (mmap with MAP_FIXED). Nothing changes if it fails
+    pub fn replace(&mut self, start: usize, len: usize, flags: u32) -> Result<(), &'static str> {
+        let end = start.checked_add(len).ok_or("Area overflow the address space")?;
+        if start < USER_SPACE_START || end > USER_SPACE_END {
+            return Err("Area outside of the user space");
+        }
+
+        let removed = self.vmas.remove(start, end);
+        if let Err(err) = self.vmas.insert(start, end, flags) {
+            for vma in removed {
+                self.vmas.insert(vma.start, vma.end, vma.flags)?;
+            }
+            return Err(err);
+        }
+
+        // the new area is in place, the old pages can go
+        self.unmap_range(start, end)
+    }
+
+    /// true if [start, start + len) is inside the user space and nothing there
+    /// is reserved or mapped
+    pub fn is_free(&self, start: usize, len: usize) -> bool {
+        match start.checked_add(len) {
+            Some(end) => {
+                start >= USER_SPACE_START
+                    && end <= USER_SPACE_END
+                    && self.vmas.is_free(start, end)
+                    && self.next_mapped_page(start, end).is_none()
+            }
+            None => false,
+        }
+    }
+
+    pub fn vmas(&self) -> &VmaList {
+        &self.vmas
+    }
+
+    /// Start of a free range of len bytes between the heap and the end of the user space,
+    /// the highest one is chosen so the heap can grow
+    pub fn find_free_area(&self, len: usize) -> Option<usize> {
+        self.vmas
+            .find_free(len, page_align_up(self.program_break), USER_SPACE_END)
+    }
+
+    /// First access to a page not present, allocate it if inside a Vma
+    fn populate(&mut self, page: &VirtualAddr, write: bool) -> Result<(), &'static str> {
+        let vma = *self.vmas.find(page.get()).ok_or("Address not mapped")?;
+        if vma.flags & PageTableFlag::User as u32 == 0 {
+            return Err("Area not accessible");
+        }
+        if write && vma.flags & PageTableFlag::Writable as u32 == 0 {
+            return Err("Write on a read only area");
+        }
+
+        self.alloc_page(page.clone(), vma.flags)?;
+        Ok(())
+    }
+
+    /// Resolve a write on a CopyOnWrite page, the page becomes writable again
+    /// and, if the frame is still shared, it is replaced with a private copy
+    fn resolve_cow(&mut self, page: &VirtualAddr) -> Result<(), &'static str> {
+        let mut table = self.get_page_table(&page).ok_or("Page not mapped")?;
+        let pte = &mut table[page.get_pt_index()];
+        if !pte.is_valid_flag(PageTableFlag::Present as u32 | PageTableFlag::CopyOnWrite as u32) {
+            return Err("Page not copy on write");
+        }
+
+        with_memory_manager(|mm| {
+            let frame = pte.get_frame();
+            let flags = (pte.get_flags() | PageTableFlag::Writable as u32)
+                & !(PageTableFlag::CopyOnWrite as u32);
+
+            // the last one that uses the frame can keep it
+            if mm.frame_allocator().reference_count(&frame) > 1 {
+                let new_frame = mm
+                    .allocate_frame()
+                    .ok_or("Impossible alloc a frame for the copy")?;
+                unsafe {
+                    core::ptr::copy_nonoverlapping(
+                        frame.get_physical_addr().get() as *const u8,
+                        new_frame.get_physical_addr().get() as *mut u8,
+                        PAGE_SIZE,
+                    );
+                }
+                mm.deallocate_frame(frame);
+
+                pte.clear();
+                pte.set_frame(new_frame);
+            } else {
+                pte.clear();
+                pte.set_frame(frame);
+            }
+            pte.add_attribute(flags);
+
+            Ok::<(), &'static str>(())
+        })?;
+
+        self.flush(page);
+        Ok(())
+    }
+
+    pub fn get_physical_addr(&self) -> PhysicalAddr {
+        self.page_directory.get_physical_addr()
+    }
+
+    /// Load the page directory in CR3
+    ///
+    /// Unsafe because everything that is not in the kernel space will change
+    pub unsafe fn activate(&self) {
+        change_page_directory(self.get_physical_addr().get());
+    }
+
+    pub fn is_active(&self) -> bool {
+        unsafe { current_page_directory() == self.get_physical_addr().get() }
+    }
+
+    /// Map virt to frame, from now on the frame is owned by the AddressSpace
+    ///
+    /// flags are PageTableFlag, Present is always added
+    pub fn map_page(&mut self, virt: VirtualAddr, frame: Frame, flags: u32) -> Result<(), &'static str> {
+        check_user_page(&virt)?;
+        with_memory_manager(|mm| self.map_page_inner(mm, &virt, frame, flags))
+    }
+
+    /// Allocate a zeroed frame and map it at virt
+    pub fn alloc_page(&mut self, virt: VirtualAddr, flags: u32) -> Result<Frame, &'static str> {
+        check_user_page(&virt)?;
+        with_memory_manager(|mm| {
+            let frame = mm
+                .allocate_zeroed_frame()
+                .ok_or("Impossible alloc a frame for the page")?;
+
+            if let Err(err) = self.map_page_inner(mm, &virt, frame.clone(), flags) {
+                mm.deallocate_frame(frame);
+                return Err(err);
+            }
+
+            Ok(frame)
+        })
+    }
+
+    /// Remove the mapping and give back the frame to the frame allocator
+    pub fn unmap_page(&mut self, virt: VirtualAddr) -> Result<(), &'static str> {
+        check_user_page(&virt)?;
+        with_memory_manager(|mm| {
+            let mut table = self
+                .get_page_table(&virt)
+                .ok_or("Page not mapped")?;
+
+            if !table[virt.get_pt_index()].is_valid_flag(PageTableFlag::Present as u32) {
+                return Err("Page not mapped");
+            }
+
+            table.free_page(mm.frame_allocator(), virt.get_pt_index());
+            self.flush(&virt);
+            Ok(())
+        })
+    }
+
+    /// Add flags (PageTableFlag) to a page already mapped
+    pub fn add_page_flags(&mut self, virt: &VirtualAddr, flags: u32) -> Result<(), &'static str> {
+        let mut table = self.get_page_table(virt).ok_or("Page not mapped")?;
+        let pte = &mut table[virt.get_pt_index()];
+
+        if !pte.is_valid_flag(PageTableFlag::Present as u32) {
+            return Err("Page not mapped");
+        }
+
+        pte.add_attribute(flags);
+        self.flush(virt);
+        Ok(())
+    }
+
+    /// Copy data inside the address space starting from virt,
+    /// every touched page MUST be mapped or inside a Vma
+    ///
+    /// The AddressSpace does not need to be active, frames are written
+    /// throught the identity mapping of the kernel space
+    pub fn write(&mut self, virt: VirtualAddr, data: &[u8]) -> Result<(), &'static str> {
+        self.for_each_chunk(virt, data.len(), true, |phys, done, len| unsafe {
+            core::ptr::copy_nonoverlapping(data[done..].as_ptr(), phys, len);
+        })
+    }
+
+    /// Copy into buf what is inside the address space starting from virt,
+    /// every touched page MUST be mapped or inside a Vma
+    pub fn read(&mut self, virt: VirtualAddr, buf: &mut [u8]) -> Result<(), &'static str> {
+        self.for_each_chunk(virt, buf.len(), false, |phys, done, len| unsafe {
+            core::ptr::copy_nonoverlapping(phys, buf[done..].as_mut_ptr(), len);
+        })
+    }
+
+    /// Set len bytes to value starting from virt, every touched page MUST be mapped or inside a Vma
+    pub fn fill(&mut self, virt: VirtualAddr, value: u8, len: usize) -> Result<(), &'static str> {
+        self.for_each_chunk(virt, len, true, |phys, _, len| unsafe {
+            core::ptr::write_bytes(phys, value, len);
+        })
+    }
+
+    /// Split [virt, virt + len) in chunks that does not cross a page and call f
+    /// with the physical address of the chunk, the bytes already done and the chunk len
+    ///
+    /// Every page is made present and, with write, private like after a write from user mode
+    fn for_each_chunk<F>(
+        &mut self,
+        virt: VirtualAddr,
+        len: usize,
+        write: bool,
+        mut f: F,
+    ) -> Result<(), &'static str>
+    where
+        F: FnMut(*mut u8, usize, usize),
+    {
+        let mut done = 0;
+        while done < len {
+            let addr = VirtualAddr::new(virt.get() + done);
+            let page = VirtualAddr::new(addr.get() & !(PAGE_SIZE - 1));
+            match self.get_page_flags(&page) {
+                None => self.populate(&page, false)?,
+                Some(flags) if write && flags & PageTableFlag::CopyOnWrite as u32 != 0 => {
+                    self.resolve_cow(&page)?
+                }
+                Some(_) => (),
+            }
+
+            let phys = self.translate(&addr).ok_or("Page not mapped")?;
+            let chunk_len = (PAGE_SIZE - addr.get_offset()).min(len - done);
+
+            f(phys.get() as *mut u8, done, chunk_len);
+            done += chunk_len;
+        }
+        Ok(())
+    }
+
+    /// Check that [addr, addr + len) is inside the user space and every page is
+    /// mapped and accessible from ring 3, used to validate pointers received from user mode
+    pub fn check_user_buffer(&self, addr: usize, len: usize, writable: bool) -> Result<(), &'static str> {
+        let end = addr.checked_add(len).ok_or("Buffer overflow the address space")?;
+        if addr < USER_SPACE_START || end > USER_SPACE_END {
+            return Err("Buffer outside of the user space");
+        }
+        if len == 0 {
+            return Ok(());
+        }
+
+        // the areas are checked whole, only the pages mapped directly one by one
+        let mut page = addr & !(PAGE_SIZE - 1);
+        while page < end {
+            // a page not present yet will be allocated by the page fault handler,
+            // the ones present have the flags of their area (or CopyOnWrite)
+            let (flags, next) = match self.vmas.find(page) {
+                Some(vma) => (vma.flags, vma.end),
+                None => (
+                    self.get_page_flags(&VirtualAddr::new(page))
+                        .ok_or("Buffer not mapped")?,
+                    page + PAGE_SIZE,
+                ),
+            };
+            if flags & PageTableFlag::User as u32 == 0 {
+                return Err("Buffer not accessible from user mode");
+            }
+            // a copy on write page will be copied by the page fault handler
+            let write_flags = PageTableFlag::Writable as u32 | PageTableFlag::CopyOnWrite as u32;
+            if writable && flags & write_flags == 0 {
+                return Err("Buffer not writable");
+            }
+            page = next;
+        }
+        Ok(())
+    }
+
+    /// Set where the heap of the program starts, the heap is empty
+    pub fn init_program_break(&mut self, heap_start: usize) {
+        self.heap_start = heap_start;
+        self.program_break = heap_start;
+    }
+
+    pub fn program_break(&self) -> usize {
+        self.program_break
+    }
+
+    /// Move the end of the heap, the heap is a Vma so growing is free,
+    /// the pages are allocated on the first access. Return the new break
+    pub fn set_program_break(&mut self, new_break: usize) -> Result<usize, &'static str> {
+        if new_break < self.heap_start || new_break > USER_SPACE_END {
+            return Err("Program break outside of the heap");
+        }
+
+        let old_end = page_align_up(self.program_break);
+        let new_end = page_align_up(new_break);
+
+        if new_end > old_end {
+            // fails if the heap reaches another area (like the stack)
+            self.reserve(
+                old_end,
+                new_end - old_end,
+                PageTableFlag::Writable as u32 | PageTableFlag::User as u32,
+            )?;
+        } else if new_end < old_end {
+            self.unreserve(new_end, old_end - new_end)?;
+        }
+
+        self.program_break = new_break;
+        Ok(new_break)
+    }
+
+    /// Return the physical address where virt is mapped, if present
+    pub fn translate(&self, virt: &VirtualAddr) -> Option<PhysicalAddr> {
+        let table = self.get_page_table(virt)?;
+        let pte = &table[virt.get_pt_index()];
+
+        if !pte.is_valid_flag(PageTableFlag::Present as u32) {
+            return None;
+        }
+
+        Some(PhysicalAddr::new(pte.get_page().get() + virt.get_offset()))
+    }
+
+    /// Return the PageTableFlag of the page that contains virt, if present
+    pub fn get_page_flags(&self, virt: &VirtualAddr) -> Option<u32> {
+        let table = self.get_page_table(virt)?;
+        let pte = &table[virt.get_pt_index()];
+
+        if !pte.is_valid_flag(PageTableFlag::Present as u32) {
+            return None;
+        }
+
+        Some(pte.get_flags())
+    }
+
+    /// Unmap every page present inside [start, end)
+    fn unmap_range(&mut self, start: usize, end: usize) -> Result<(), &'static str> {
+        let mut page = start;
+        while let Some(mapped) = self.next_mapped_page(page, end) {
+            self.unmap_page(VirtualAddr::new(mapped))?;
+            page = mapped + PAGE_SIZE;
+        }
+        Ok(())
+    }
+
+    /// First present page inside [from, end), the ranges without a page table are skipped whole
+    fn next_mapped_page(&self, from: usize, end: usize) -> Option<usize> {
+        let mut page = from & !(PAGE_SIZE - 1);
+        while page < end {
+            let virt = VirtualAddr::new(page);
+            match self.get_page_table(&virt) {
+                Some(table) if table[virt.get_pt_index()].is_valid_flag(PageTableFlag::Present as u32) => {
+                    return Some(page)
+                }
+                Some(_) => page += PAGE_SIZE,
+                None => page = (page | (ENTRIES_PER_PAGE * PAGE_SIZE - 1)).checked_add(1)?,
+            }
+        }
+        None
+    }
+
+    /// Page table that cover virt, None if not allocated
+    fn get_page_table(&self, virt: &VirtualAddr) -> Option<PageTable> {
+        let pde = &self.page_directory[virt.get_pd_index()];
+        if !pde.is_valid_flag(PageDirectoryFlag::Present as u32) {
+            return None;
+        }
+        Some(pde.get_page_table())
+    }
+
+    fn map_page_inner(
+        &mut self,
+        mm: &mut MemoryManager,
+        virt: &VirtualAddr,
+        frame: Frame,
+        flags: u32,
+    ) -> Result<(), &'static str> {
+        let mut table = match self.get_page_table(virt) {
+            Some(table) => table,
+            // the real permissions are decided by the page table entries
+            None => self.page_directory.alloc_new_page_table(
+                mm.frame_allocator(),
+                virt.get_pd_index(),
+                PageDirectoryFlag::Present as u32
+                    | PageDirectoryFlag::Writable as u32
+                    | PageDirectoryFlag::User as u32,
+            )?,
+        };
+
+        let pte = &mut table[virt.get_pt_index()];
+        if pte.is_valid_flag(PageTableFlag::Present as u32) {
+            return Err("Page already present, should be deallocated and managed");
+        }
+
+        pte.add_attribute(flags | PageTableFlag::Present as u32);
+        pte.set_frame(frame);
+
+        Ok(())
+    }
+
+    fn flush(&self, virt: &VirtualAddr) {
+        if self.is_active() {
+            unsafe { flush_tlb_entry(virt.get()) };
+        }
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        with_memory_manager(|mm| {
+            // never free the page directory in use
+            if self.is_active() {
+                let kernel_page_directory = mm.kernel_page_directory().clone();
+                unsafe { mm.switch_page_directory(&kernel_page_directory) };
+            }
+
+            for pd_index in KERNEL_PD_ENTRIES..ENTRIES_PER_PAGE {
+                if !self.page_directory[pd_index].is_valid_flag(PageDirectoryFlag::Present as u32) {
+                    continue;
+                }
+
+                let mut table = self.page_directory[pd_index].get_page_table();
+                for pt_index in 0..ENTRIES_PER_PAGE {
+                    table.free_page(mm.frame_allocator(), pt_index);
+                }
+                self.page_directory.free_page_table(mm.frame_allocator(), pd_index);
+            }
+
+            mm.deallocate_frame(Frame::from_physical_address(
+                self.page_directory.get_physical_addr(),
+            ));
+        });
+    }
+}
+
+fn page_align_up(addr: usize) -> usize {
+    (addr + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+fn check_user_page(virt: &VirtualAddr) -> Result<(), &'static str> {
+    if virt.get() < USER_SPACE_START || virt.get() >= USER_SPACE_END {
+        return Err("Address outside of the user space");
+    }
+    if virt.get_offset() != 0 {
+        return Err("Address not page aligned");
+    }
+    Ok(())
+}
+
+#[cfg(all(test, target_os = "none"))]
+mod kernel_tests {
+    use super::*;
+    use crate::interrupts::without_interrupts;
+
+    #[test_case]
+    fn user_page_is_usable_once_activated() {
+        let mut address_space = AddressSpace::new().expect("Impossible create an address space");
+        let user_page = VirtualAddr::new(USER_SPACE_START);
+
+        address_space
+            .alloc_page(
+                user_page.clone(),
+                PageTableFlag::Writable as u32 | PageTableFlag::User as u32,
+            )
+            .expect("Impossible alloc a user page");
+
+        // a task switch would change the page directory
+        without_interrupts(|| {
+            unsafe {
+                address_space.activate();
+                *(user_page.get() as *mut u32) = 42;
+                assert_eq!(42, *(user_page.get() as *const u32));
+            }
+            // the drop will restore the kernel page directory
+            drop(address_space);
+        });
+    }
+}
@@ -1,5 +1,6 @@
 use super::*;
-use core::alloc::{GlobalAlloc, Layout};
+use alloc::boxed::Box;
+use core::alloc::Layout;
 
 // Start allocating frame from stack_top + stack_frame_max
 
@@ -39,6 +40,8 @@ impl Frame {
     }
 }
 
+// stack_top is one element after the end of the buffer,
+// stack_ptr point to the last pushed element
 #[derive(Debug)]
 struct Stack<T: Default> {
     stack_top: *const T,
@@ -60,17 +63,19 @@ impl<T: Default> Stack<T> {
             return None;
         }
 
+        let val = unsafe { core::mem::take(&mut *self.stack_ptr) };
         self.stack_ptr = ((self.stack_ptr as usize) + core::mem::size_of::<T>()) as *mut T;
         //crate::println!("stack ptr is now: {:?}", self.stack_ptr);
-        Some(unsafe { core::mem::take(&mut *self.stack_ptr) })
+        Some(val)
     }
 
-    // should be manage the overflow???
+    // the stack has space for every frame, and a frame can't be free twice,
+    // so it can't overflow
     fn push(&mut self, val: T) {
         //crate::println!("stack ptr is: {:?}", self.stack_ptr);
         //crate::println!("somethign pushed");
-        unsafe { *self.stack_ptr = val };
         self.stack_ptr = ((self.stack_ptr as usize) - core::mem::size_of::<T>()) as *mut T;
+        unsafe { *self.stack_ptr = val };
         //crate::println!("stack ptr is now: {:?}", self.stack_ptr);
     }
 }
@@ -86,11 +91,17 @@ pub struct FrameAllocator {
     pub first_avaiable_frame: Frame,
     current_frame: Frame,
     stack: Stack<usize>,
+    // how many mappings use each frame (shared by copy on write),
+    // the frame is free again only when it reaches 0
+    ref_counts: Box<[u16]>,
 }
 
 impl FrameAllocator {
     // create a new frame allocator object and a stack to manage it
-    pub fn new(boot_info: &BootInfo) -> FrameAllocator {
+    //
+    // first_free_addr is the first physical address not used by the kernel,
+    // every frame before it will never be allocated
+    pub fn new(boot_info: &BootInfo, first_free_addr: usize) -> FrameAllocator {
         // Extract the number of total frame
         // mem_upper and lower are in kilobytes
 
@@ -100,9 +111,16 @@ impl FrameAllocator {
                 .mem_upper
                 .expect("Mem Upper not present in multiboot information")
                 * 0x400);
-        //crate::println!("tot mem: {}", total_memory);
-        let max_frame = total_memory / FRAME_SIZE;
-        //crate::println!("max frame {}", max_frame);
+        crate::debug!("total memory: {} KiB", total_memory / 1024);
+        Self::with_total_memory(total_memory, first_free_addr)
+    }
+
+    /// Like new but with the size of the memory already known
+    pub fn with_total_memory(total_memory: usize, first_free_addr: usize) -> FrameAllocator {
+        // the kernel access frames throught the identity mapping,
+        // so frames after it (where the kernel heap starts) can't be used
+        let max_frame = total_memory.min(KERNEL_HEAP_START) / FRAME_SIZE;
+        crate::debug!("frames: {}", max_frame);
 
         // set up the stack ptr
         // starting from the starting_point we need to reserve the space for a stack
@@ -114,23 +132,23 @@ impl FrameAllocator {
         //unsafe { (starting_point + (max_frame * core::mem::size_of::<usize>())) as *mut usize };
 
         let stack_size = max_frame * core::mem::size_of::<usize>();
-        let stack_top = unsafe {
-            crate::GLOBAL_ALLOC.alloc(
-                Layout::from_size_align(stack_size, 4)
+        let stack_bottom = unsafe {
+            alloc::alloc::alloc(
+                Layout::from_size_align(stack_size, core::mem::align_of::<usize>())
                     .expect("Layout creation for frame allocato failed"),
             )
         } as *mut usize;
 
-        if stack_top.is_null() {
+        if stack_bottom.is_null() {
             panic!("This allocation cannot fail");
         }
 
-        let stack = Stack::new(stack_top);
+        let stack = Stack::new(unsafe { stack_bottom.add(max_frame) });
 
         // The frame start from 0
         // Starting from the next frame from the position indicated by the starting point
         // Of course there is some internal framgemntation between starting_point and the next init frame
-        let current_frame = Frame::from_physical_address(PhysicalAddr::new(stack_top as usize));
+        let current_frame = Frame::from_physical_address(PhysicalAddr::new(first_free_addr));
         let first_avaiable_frame = current_frame.clone();
 
         Self {
@@ -138,24 +156,151 @@ impl FrameAllocator {
             first_avaiable_frame,
             current_frame,
             stack,
+            ref_counts: vec![0u16; max_frame].into_boxed_slice(),
+        }
+    }
+
+    /// One more mapping uses frame, it will need one more deallocate to be freed
+    pub fn add_reference(&mut self, frame: &Frame) {
+        let count = &mut self.ref_counts[frame.number];
+        if *count == 0 {
+            panic!("Reference added to a free frame: {}", frame.number);
         }
+        *count = count.checked_add(1).expect("Too many references to a frame");
+    }
+
+    /// Number of mappings that use frame, 0 if free
+    pub fn reference_count(&self, frame: &Frame) -> usize {
+        self.ref_counts[frame.number] as usize
     }
 }
 
 impl Allocator for FrameAllocator {
     fn allocate(&mut self) -> Option<Frame> {
-        if self.max_frame == self.current_frame.number {
+        let frame = if self.max_frame == self.current_frame.number {
             // the counter is end, search in the the stack
             // panic if all frames are allocated for now
-            self.stack.pop().map(|n| Frame::from_frame_number(n))
+            self.stack.pop().map(|n| Frame::from_frame_number(n))?
         } else {
             let new_frame = self.current_frame.clone();
             self.current_frame = self.current_frame.next();
-            Some(new_frame)
-        }
+            new_frame
+        };
+
+        self.ref_counts[frame.number] = 1;
+        Some(frame)
     }
 
+    /// Drop one reference, the frame is really freed only when nobody uses it anymore
     fn deallocate(&mut self, to_deallocate: Frame) {
-        self.stack.push(to_deallocate.number);
+        let count = &mut self.ref_counts[to_deallocate.number];
+        match *count {
+            0 => panic!("Frame freed twice: {}", to_deallocate.number),
+            1 => {
+                *count = 0;
+                self.stack.push(to_deallocate.number);
+            }
+            _ => *count -= 1,
+        }
+    }
+}
+
+// on the host, see test_on_host.sh
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::super::test_support::Random;
+    use super::*;
+    use std::collections::HashSet;
+
+    const TOTAL_FRAMES: usize = 64;
+    const FIRST_FREE_FRAME: usize = 8;
+
+    fn frame_allocator() -> FrameAllocator {
+        FrameAllocator::with_total_memory(TOTAL_FRAMES * FRAME_SIZE, FIRST_FREE_FRAME * FRAME_SIZE)
+    }
+
+    #[test]
+    fn allocate_every_frame_after_the_kernel() {
+        let mut frame_allocator = frame_allocator();
+
+        for number in FIRST_FREE_FRAME..TOTAL_FRAMES {
+            assert_eq!(
+                Some(Frame::from_frame_number(number)),
+                frame_allocator.allocate()
+            );
+        }
+        assert_eq!(None, frame_allocator.allocate());
+    }
+
+    #[test]
+    fn deallocated_frame_is_reused() {
+        let mut frame_allocator = frame_allocator();
+        while frame_allocator.allocate().is_some() {}
+
+        frame_allocator.deallocate(Frame::from_frame_number(20));
+        assert_eq!(
+            0,
+            frame_allocator.reference_count(&Frame::from_frame_number(20))
+        );
+        assert_eq!(
+            Some(Frame::from_frame_number(20)),
+            frame_allocator.allocate()
+        );
+        assert_eq!(None, frame_allocator.allocate());
+    }
+
+    #[test]
+    fn shared_frame_is_freed_by_the_last_reference() {
+        let mut frame_allocator = frame_allocator();
+        let frame = frame_allocator.allocate().unwrap();
+
+        frame_allocator.add_reference(&frame);
+        assert_eq!(2, frame_allocator.reference_count(&frame));
+
+        frame_allocator.deallocate(frame.clone());
+        assert_eq!(1, frame_allocator.reference_count(&frame));
+        frame_allocator.deallocate(frame.clone());
+        assert_eq!(0, frame_allocator.reference_count(&frame));
+    }
+
+    #[test]
+    #[should_panic(expected = "Frame freed twice")]
+    fn double_free_panics() {
+        let mut frame_allocator = frame_allocator();
+        let frame = frame_allocator.allocate().unwrap();
+
+        frame_allocator.deallocate(frame.clone());
+        frame_allocator.deallocate(frame);
+    }
+
+    #[test]
+    fn frames_capped_at_the_kernel_heap() {
+        let frame_allocator = FrameAllocator::with_total_memory(2 * KERNEL_HEAP_START, 0);
+        assert_eq!(KERNEL_HEAP_START / FRAME_SIZE, frame_allocator.max_frame);
+    }
+
+    #[test]
+    fn random_allocate_deallocate_never_gives_a_frame_twice() {
+        let mut frame_allocator = frame_allocator();
+        let mut allocated = HashSet::new();
+        let mut random = Random(0x9E3779B9);
+
+        for _ in 0..10000 {
+            if random.below(3) != 0 {
+                if let Some(frame) = frame_allocator.allocate() {
+                    assert!(frame.number >= FIRST_FREE_FRAME && frame.number < TOTAL_FRAMES);
+                    assert!(
+                        allocated.insert(frame.number),
+                        "frame {} given twice",
+                        frame.number
+                    );
+                } else {
+                    assert_eq!(TOTAL_FRAMES - FIRST_FREE_FRAME, allocated.len());
+                }
+            } else if let Some(number) = allocated.iter().next().copied() {
+                allocated.remove(&number);
+                frame_allocator.deallocate(Frame::from_frame_number(number));
+            }
+        }
     }
 }
@@ -0,0 +1,759 @@
+use super::heap_allocator::HeapRegion;
+use super::heap_stats::FreeSpace;
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    cell::UnsafeCell,
+    mem::size_of,
+    ptr::null_mut,
+};
+
+/// Segregated fit allocator, every free block is inside the bin of its size class:
+/// bin i contains the free blocks with size in [2^i, 2^(i+1))
+///
+/// Every block starts with a BlockHeader, a free block also keeps the links of its
+/// bin in the first bytes of the payload. The header knows the size of the previous
+/// block when that is free, so a freed block is merged with both the neighbours
+/// without walking anything. The last block is a sentinel, always allocated and empty
+///
+/// |header|payload...|header|free...........|header|payload...|sentinel|
+///
+/// alloc looks only at the first block of a bin (the bitmap says which bins are
+/// not empty) and dealloc touches only the neighbours, both are bounded-time
+///
+/// alloc_zeroed doesn't clear the memory never written
+pub struct FreeListAllocator {
+    inner: UnsafeCell<FreeList>,
+}
+
+struct FreeList {
+    start: usize,
+    end: usize,
+    // null while the region is too small to contain a block
+    sentinel: *mut BlockHeader,
+    bins: [*mut FreeBlock; BIN_COUNT],
+    // bit i is set if bins[i] is not empty
+    bitmap: usize,
+    // nothing at or after this was written, apart from the sentinel header
+    untouched_from: usize,
+}
+
+#[repr(C)]
+struct BlockHeader {
+    // size of the block (header included) | FREE | PREV_FREE
+    size: usize,
+    // size of the previous block, valid only with PREV_FREE
+    prev_size: usize,
+}
+
+#[repr(C)]
+struct FreeBlock {
+    header: BlockHeader,
+    next: *mut FreeBlock,
+    prev: *mut FreeBlock,
+}
+
+const FREE: usize = 0x1;
+const PREV_FREE: usize = 0x2;
+const FLAGS: usize = 0x7;
+
+const HEADER_SIZE: usize = size_of::<BlockHeader>();
+// every block starts (and so every payload) aligned to this
+const BLOCK_ALIGN: usize = HEADER_SIZE;
+const MIN_BLOCK_SIZE: usize = size_of::<FreeBlock>();
+const BIN_COUNT: usize = usize::BITS as usize;
+
+fn align_up(addr: usize, align: usize) -> Option<usize> {
+    Some(addr.checked_add(align - 1)? & !(align - 1))
+}
+
+fn align_down(addr: usize, align: usize) -> usize {
+    addr & !(align - 1)
+}
+
+// size of the block (header included) with a payload of size bytes,
+// the payload of a free block contains the links of the bin
+fn block_size(size: usize) -> Option<usize> {
+    Some(align_up(size.max(MIN_BLOCK_SIZE - HEADER_SIZE), BLOCK_ALIGN)? + HEADER_SIZE)
+}
+
+// floor(log2(size))
+fn bin_index(size: usize) -> usize {
+    (usize::BITS - 1 - size.leading_zeros()) as usize
+}
+
+impl BlockHeader {
+    fn size(&self) -> usize {
+        self.size & !FLAGS
+    }
+
+    fn is_free(&self) -> bool {
+        self.size & FREE != 0
+    }
+
+    fn is_prev_free(&self) -> bool {
+        self.size & PREV_FREE != 0
+    }
+
+    unsafe fn next(block: *mut BlockHeader) -> *mut BlockHeader {
+        (block as usize + (*block).size()) as *mut BlockHeader
+    }
+
+    unsafe fn prev(block: *mut BlockHeader) -> *mut BlockHeader {
+        (block as usize - (*block).prev_size) as *mut BlockHeader
+    }
+
+    fn payload(block: *mut BlockHeader) -> *mut u8 {
+        (block as usize + HEADER_SIZE) as *mut u8
+    }
+
+    fn from_payload(ptr: *mut u8) -> *mut BlockHeader {
+        (ptr as usize - HEADER_SIZE) as *mut BlockHeader
+    }
+}
+
+impl FreeList {
+    /// Create the first free block and the sentinel if there is enough space
+    unsafe fn init_blocks(&mut self) {
+        let first = match align_up(self.start, BLOCK_ALIGN) {
+            Some(first) => first,
+            None => return,
+        };
+        let sentinel = align_down(self.end, BLOCK_ALIGN).saturating_sub(HEADER_SIZE);
+        if sentinel < first || sentinel - first < MIN_BLOCK_SIZE {
+            return;
+        }
+
+        self.sentinel = sentinel as *mut BlockHeader;
+        self.sentinel.write(BlockHeader {
+            size: 0,
+            prev_size: 0,
+        });
+        self.make_free(first as *mut BlockHeader, sentinel - first);
+    }
+
+    /// Write a free block of size at block and put it in its bin,
+    /// the previous block MUST not be free (free blocks are always merged)
+    unsafe fn make_free(&mut self, block: *mut BlockHeader, size: usize) {
+        // the header and the links
+        self.touch(block as usize + MIN_BLOCK_SIZE);
+        block.write(BlockHeader {
+            size: size | FREE,
+            prev_size: 0,
+        });
+
+        let next = BlockHeader::next(block);
+        (*next).size |= PREV_FREE;
+        (*next).prev_size = size;
+
+        self.insert(block as *mut FreeBlock);
+    }
+
+    unsafe fn insert(&mut self, block: *mut FreeBlock) {
+        let index = bin_index((*block).header.size());
+
+        (*block).prev = null_mut();
+        (*block).next = self.bins[index];
+        if !self.bins[index].is_null() {
+            (*self.bins[index]).prev = block;
+        }
+        self.bins[index] = block;
+        self.bitmap |= 1 << index;
+    }
+
+    unsafe fn remove(&mut self, block: *mut FreeBlock) {
+        let index = bin_index((*block).header.size());
+
+        if !(*block).next.is_null() {
+            (*(*block).next).prev = (*block).prev;
+        }
+        if (*block).prev.is_null() {
+            self.bins[index] = (*block).next;
+        } else {
+            (*(*block).prev).next = (*block).next;
+        }
+
+        if self.bins[index].is_null() {
+            self.bitmap &= !(1 << index);
+        }
+    }
+
+    fn touch(&mut self, end: usize) {
+        if end > self.untouched_from {
+            self.untouched_from = end;
+        }
+    }
+
+    /// A free block of at least size, without removing it from its bin
+    unsafe fn find_free(&self, size: usize) -> *mut FreeBlock {
+        // every block of a bin after the one of size is big enough
+        let exact = bin_index(size);
+        let first_fitting = if size.is_power_of_two() {
+            exact
+        } else {
+            exact + 1
+        };
+        if first_fitting < BIN_COUNT {
+            let fitting_bins = self.bitmap & !((1 << first_fitting) - 1);
+            if fitting_bins != 0 {
+                return self.bins[fitting_bins.trailing_zeros() as usize];
+            }
+        }
+
+        // only the first block of the bin of size is checked, to stay bounded
+        let block = self.bins[exact];
+        if !block.is_null() && (*block).header.size() >= size {
+            block
+        } else {
+            null_mut()
+        }
+    }
+
+    unsafe fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        if self.sentinel.is_null() {
+            return null_mut();
+        }
+
+        let align = layout.align().max(BLOCK_ALIGN);
+        let used = match block_size(layout.size()) {
+            Some(used) => used,
+            None => return null_mut(),
+        };
+        // a bigger alignment could need a free block before the allocated one
+        let needed = match align > BLOCK_ALIGN {
+            true => used.checked_add(align + MIN_BLOCK_SIZE),
+            false => Some(used),
+        };
+        let needed = match needed {
+            Some(needed) => needed,
+            None => return null_mut(),
+        };
+
+        let free_block = self.find_free(needed);
+        if free_block.is_null() {
+            return null_mut();
+        }
+        self.remove(free_block);
+
+        let mut block = free_block as *mut BlockHeader;
+        let mut size = (*block).size();
+
+        let payload = BlockHeader::payload(block) as usize;
+        if payload % align != 0 {
+            let mut aligned_payload = align_up(payload, align).unwrap();
+            // the space before must be big enough for a free block,
+            // align >= 2 * BLOCK_ALIGN = MIN_BLOCK_SIZE so one more align is always enough
+            if aligned_payload - payload < MIN_BLOCK_SIZE {
+                aligned_payload += align;
+            }
+
+            let gap = aligned_payload - payload;
+            let aligned_block = BlockHeader::from_payload(aligned_payload as *mut u8);
+            size -= gap;
+            aligned_block.write(BlockHeader {
+                size: size | FREE,
+                prev_size: 0,
+            });
+            self.make_free(block, gap);
+            block = aligned_block;
+        }
+
+        // give back what is not used
+        if size - used >= MIN_BLOCK_SIZE {
+            (*block).size = used | ((*block).size & PREV_FREE);
+            self.make_free(BlockHeader::next(block), size - used);
+        } else {
+            (*block).size &= !FREE;
+            (*BlockHeader::next(block)).size &= !PREV_FREE;
+        }
+
+        let payload = BlockHeader::payload(block);
+        self.touch(payload as usize + layout.size());
+        payload
+    }
+
+    unsafe fn alloc_zeroed(&mut self, layout: Layout) -> *mut u8 {
+        let untouched_from = self.untouched_from;
+        let ptr = self.alloc(layout);
+
+        // only what is before untouched_from could be dirty
+        if !ptr.is_null() && (ptr as usize) < untouched_from {
+            let dirty = (untouched_from - ptr as usize).min(layout.size());
+            core::ptr::write_bytes(ptr, 0, dirty);
+        }
+        ptr
+    }
+
+    /// Resize the block of ptr taking (or giving back) the space of the next block,
+    /// false if it is not free or not big enough
+    unsafe fn realloc_in_place(&mut self, ptr: *mut u8, layout: Layout, new_size: usize) -> bool {
+        let block = Self::allocated_block(ptr, layout);
+        let used = match block_size(new_size) {
+            Some(used) => used,
+            None => return false,
+        };
+
+        let mut size = (*block).size();
+        let next = BlockHeader::next(block);
+        if used > size {
+            if !(*next).is_free() || used > size + (*next).size() {
+                return false;
+            }
+            self.remove(next as *mut FreeBlock);
+            size += (*next).size();
+        } else if (*next).is_free() {
+            // the space given back is merged with it
+            self.remove(next as *mut FreeBlock);
+            size += (*next).size();
+        }
+
+        if size - used >= MIN_BLOCK_SIZE {
+            (*block).size = used | ((*block).size & PREV_FREE);
+            self.make_free(BlockHeader::next(block), size - used);
+        } else {
+            (*block).size = size | ((*block).size & PREV_FREE);
+            (*BlockHeader::next(block)).size &= !PREV_FREE;
+        }
+
+        self.touch(ptr as usize + new_size);
+        true
+    }
+
+    // the header of ptr, panic if it is not allocated or too small for layout
+    unsafe fn allocated_block(ptr: *mut u8, layout: Layout) -> *mut BlockHeader {
+        let block = BlockHeader::from_payload(ptr);
+        if (*block).is_free() || (*block).size() - HEADER_SIZE < layout.size() {
+            // the heap lock is held, the panic handler doesn't take it
+            panic!(
+                "Heap corruption, double free or free with the wrong size of {:p}: size {}, align {}",
+                ptr,
+                layout.size(),
+                layout.align()
+            );
+        }
+        block
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        let mut block = Self::allocated_block(ptr, layout);
+        let mut size = (*block).size();
+
+        let next = BlockHeader::next(block);
+        if (*next).is_free() {
+            self.remove(next as *mut FreeBlock);
+            size += (*next).size();
+        }
+
+        if (*block).is_prev_free() {
+            let prev = BlockHeader::prev(block);
+            self.remove(prev as *mut FreeBlock);
+            size += (*prev).size();
+            block = prev;
+        }
+
+        self.make_free(block, size);
+    }
+
+    unsafe fn is_last(&self, ptr: *mut u8, layout: Layout) -> bool {
+        let next = BlockHeader::next(Self::allocated_block(ptr, layout));
+        next == self.sentinel || ((*next).is_free() && BlockHeader::next(next) == self.sentinel)
+    }
+
+    unsafe fn set_end(&mut self, new_end: usize) {
+        if self.sentinel.is_null() {
+            self.end = new_end;
+            self.init_blocks();
+            return;
+        }
+
+        // the free block at the end (if any) is rebuilt with the new size
+        let mut free_start = self.sentinel as usize;
+        if (*self.sentinel).is_prev_free() {
+            let last = BlockHeader::prev(self.sentinel);
+            self.remove(last as *mut FreeBlock);
+            free_start = last as usize;
+        }
+
+        self.end = new_end;
+        if new_end < self.untouched_from {
+            self.untouched_from = new_end;
+        }
+        let new_sentinel = align_down(new_end, BLOCK_ALIGN) - HEADER_SIZE;
+        // the old sentinel ends up inside the free block, it must look never written
+        if new_sentinel > self.sentinel as usize && free_start != self.sentinel as usize {
+            core::ptr::write_bytes(self.sentinel as *mut u8, 0, HEADER_SIZE);
+        }
+        if new_sentinel >= free_start + MIN_BLOCK_SIZE {
+            self.sentinel = new_sentinel as *mut BlockHeader;
+            self.sentinel.write(BlockHeader {
+                size: 0,
+                prev_size: 0,
+            });
+            self.make_free(free_start as *mut BlockHeader, new_sentinel - free_start);
+        } else {
+            // no space for a free block, the block before is allocated
+            self.sentinel = free_start as *mut BlockHeader;
+            self.sentinel.write(BlockHeader {
+                size: 0,
+                prev_size: 0,
+            });
+        }
+    }
+
+    unsafe fn used_end(&self) -> usize {
+        if self.sentinel.is_null() {
+            return self.start;
+        }
+
+        // the last free block can shrink but it can't disappear
+        // if there is no space for the sentinel in its place
+        if (*self.sentinel).is_prev_free() {
+            BlockHeader::prev(self.sentinel) as usize + MIN_BLOCK_SIZE + HEADER_SIZE
+        } else {
+            self.sentinel as usize + HEADER_SIZE
+        }
+    }
+}
+
+impl FreeListAllocator {
+    /// [start_heap, end_heap) MUST be zeroed (like the .bss),
+    /// so alloc_zeroed can skip the memory never used
+    pub unsafe fn new_zeroed(start_heap: usize, end_heap: usize) -> Self {
+        let mut free_list = FreeList {
+            start: start_heap,
+            end: end_heap,
+            sentinel: null_mut(),
+            bins: [null_mut(); BIN_COUNT],
+            bitmap: 0,
+            untouched_from: start_heap,
+        };
+        free_list.init_blocks();
+
+        Self {
+            inner: UnsafeCell::new(free_list),
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for FreeListAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        (*self.inner.get()).alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        (*self.inner.get()).alloc_zeroed(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        (*self.inner.get()).dealloc(ptr, layout)
+    }
+}
+
+impl HeapRegion for FreeListAllocator {
+    // the block header and a free block before the allocated one
+    const BLOCK_OVERHEAD: usize = HEADER_SIZE + MIN_BLOCK_SIZE;
+
+    unsafe fn new(start_heap: usize, end_heap: usize) -> Self {
+        FreeListAllocator::new_zeroed(start_heap, end_heap)
+    }
+
+    fn contains(&self, ptr: *mut u8) -> bool {
+        let free_list = unsafe { &*self.inner.get() };
+        free_list.start <= ptr as usize && (ptr as usize) < free_list.end
+    }
+
+    fn end_heap(&self) -> usize {
+        unsafe { (*self.inner.get()).end }
+    }
+
+    fn used_end(&self) -> usize {
+        unsafe { (*self.inner.get()).used_end() }
+    }
+
+    unsafe fn is_last(&self, ptr: *mut u8, layout: Layout) -> bool {
+        (*self.inner.get()).is_last(ptr, layout)
+    }
+
+    unsafe fn set_end_heap(&mut self, new_end: usize) {
+        self.inner.get_mut().set_end(new_end)
+    }
+
+    unsafe fn realloc_in_place(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> bool {
+        (*self.inner.get()).realloc_in_place(ptr, layout, new_size)
+    }
+
+    fn free_space(&self) -> FreeSpace {
+        let free_list = unsafe { &*self.inner.get() };
+        let mut free_space = FreeSpace::default();
+
+        for bin in free_list.bins.iter() {
+            let mut block = *bin;
+            while !block.is_null() {
+                let header = unsafe { &(*block).header };
+                free_space.add_block(header.size() - HEADER_SIZE);
+                block = unsafe { (*block).next };
+            }
+        }
+        free_space
+    }
+
+    fn print_blocks(&self) {
+        let free_list = unsafe { &*self.inner.get() };
+        if free_list.sentinel.is_null() {
+            crate::println!("EMPTY heap");
+            return;
+        }
+
+        let mut block = align_up(free_list.start, BLOCK_ALIGN).unwrap() as *mut BlockHeader;
+        let mut i = 0;
+        while block != free_list.sentinel {
+            let header = unsafe { &*block };
+            let state = if header.is_free() { "free" } else { "used" };
+            crate::println!("{} -> {} dim: {}", i, state, header.size() - HEADER_SIZE);
+            block = unsafe { BlockHeader::next(block) };
+            i += 1;
+        }
+        crate::println!("");
+    }
+}
+
+// on the host, see test_on_host.sh
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::super::test_support::{self, layout, Random};
+    use super::*;
+    use crate::memory_manager::PAGE_SIZE;
+    use std::vec::Vec;
+
+    const HEAP_SIZE: usize = 1024 * 1024;
+
+    type TestHeap = test_support::TestHeap<FreeListAllocator>;
+
+    impl TestHeap {
+        fn free_list(&self) -> &FreeList {
+            unsafe { &*self.heap.inner.get() }
+        }
+    }
+
+    // size of the block (header included) of an allocation of payload bytes
+    fn block_size(payload: usize) -> usize {
+        align_up(payload, BLOCK_ALIGN).unwrap() + HEADER_SIZE
+    }
+
+    #[test]
+    #[should_panic(expected = "double free")]
+    fn double_free_panics() {
+        let test_heap = TestHeap::new(HEAP_SIZE);
+        let ptr = test_heap.alloc(64);
+        test_heap.dealloc(ptr, 64);
+        test_heap.dealloc(ptr, 64);
+    }
+
+    #[test]
+    fn bin_of_every_size() {
+        assert_eq!(bin_index(1), 0);
+        assert_eq!(
+            bin_index(MIN_BLOCK_SIZE),
+            MIN_BLOCK_SIZE.trailing_zeros() as usize
+        );
+        assert_eq!(bin_index(127), 6);
+        assert_eq!(bin_index(128), 7);
+        assert_eq!(bin_index(255), 7);
+        assert_eq!(bin_index(usize::MAX), BIN_COUNT - 1);
+    }
+
+    #[test]
+    fn alloc_takes_the_first_fitting_bin() {
+        let test_heap = TestHeap::new(HEAP_SIZE);
+
+        // two free blocks in different bins, kept apart by allocated ones
+        let small = test_heap.alloc(112);
+        let _ = test_heap.alloc(16);
+        let big = test_heap.alloc(496);
+        let _ = test_heap.alloc(16);
+        test_heap.dealloc(small, 112);
+        test_heap.dealloc(big, 496);
+
+        let small_bin = bin_index(block_size(112));
+        let big_bin = bin_index(block_size(496));
+        assert_eq!(small_bin, 7);
+        assert_eq!(big_bin, 9);
+        let bitmap = test_heap.free_list().bitmap;
+        assert!(bitmap & (1 << small_bin) != 0);
+        assert!(bitmap & (1 << big_bin) != 0);
+        assert!(bitmap & (1 << 8) == 0);
+
+        // the exact size of the small block, its own bin
+        assert_eq!(test_heap.alloc(112), small);
+        assert!(test_heap.free_list().bitmap & (1 << small_bin) == 0);
+        // a bin 8 block is needed: the first not empty bin after it is the big block
+        assert_eq!(test_heap.alloc(300), big);
+    }
+
+    #[test]
+    fn free_merges_with_both_neighbours() {
+        let test_heap = TestHeap::new(HEAP_SIZE);
+
+        let before = test_heap.alloc(100);
+        let middle = test_heap.alloc(200);
+        let after = test_heap.alloc(300);
+        // keeps the merged block apart from the free space at the end
+        let _ = test_heap.alloc(16);
+        let tail = test_heap.heap.free_space();
+
+        test_heap.dealloc(before, 100);
+        test_heap.dealloc(after, 300);
+        assert!(test_heap.heap.free_space().fragmentation() > 0);
+
+        test_heap.dealloc(middle, 200);
+
+        let merged = block_size(100) + block_size(200) + block_size(300);
+        let free_space = test_heap.heap.free_space();
+        assert_eq!(
+            free_space.free_bytes,
+            tail.free_bytes + merged - HEADER_SIZE
+        );
+
+        // a single block in its bin, it starts where the first one did
+        let block = BlockHeader::from_payload(before);
+        let bin = bin_index(merged);
+        assert_eq!(test_heap.free_list().bins[bin] as usize, block as usize);
+        unsafe {
+            assert!((*block).is_free());
+            assert_eq!((*block).size(), merged);
+            assert!((*test_heap.free_list().bins[bin]).next.is_null());
+            let next = BlockHeader::next(block);
+            assert!((*next).is_prev_free());
+            assert_eq!((*next).prev_size, merged);
+        }
+    }
+
+    #[test]
+    fn realloc_in_place_takes_the_next_free_block() {
+        let test_heap = TestHeap::new(HEAP_SIZE);
+        let realloc_in_place = |ptr, size, new_size| unsafe {
+            test_heap.heap.realloc_in_place(ptr, layout(size), new_size)
+        };
+
+        let ptr = test_heap.alloc(100);
+        let next = test_heap.alloc(200);
+        // keeps the blocks apart from the free space at the end
+        let _ = test_heap.alloc(16);
+        assert!(!realloc_in_place(ptr, 100, 150));
+
+        test_heap.dealloc(next, 200);
+        let both = block_size(100) + block_size(200);
+        assert!(realloc_in_place(ptr, 100, 250));
+        let block = BlockHeader::from_payload(ptr);
+        unsafe {
+            assert_eq!((*block).size(), block_size(250));
+            // what is left is still a free block
+            let rest = BlockHeader::next(block);
+            assert!((*rest).is_free());
+            assert_eq!((*rest).size(), both - block_size(250));
+        }
+        assert!(!realloc_in_place(ptr, 250, 1000));
+
+        // the space given back is merged with the free block after
+        assert!(realloc_in_place(ptr, 250, 100));
+        unsafe {
+            assert_eq!((*block).size(), block_size(100));
+            let rest = BlockHeader::next(block);
+            assert!((*rest).is_free());
+            assert_eq!((*rest).size(), block_size(200));
+        }
+    }
+
+    #[test]
+    fn alloc_zeroed_clears_only_the_used_memory() {
+        let test_heap = TestHeap::new(HEAP_SIZE);
+
+        // never written, nothing to clear
+        let first = test_heap.alloc_zeroed(1000);
+        let touched = test_heap.free_list().untouched_from;
+        assert!(touched <= first as usize + block_size(1000) + MIN_BLOCK_SIZE);
+
+        // dirty memory freed and taken again
+        let mut random = Random(0x2545F4914F6CDD1D);
+        let mut live = Vec::new();
+        for _ in 0..2000 {
+            if random.below(2) == 0 || live.is_empty() {
+                let size = 1 + random.below(1024);
+                let ptr = test_heap.alloc_zeroed(size);
+                unsafe { core::ptr::write_bytes(ptr, 0xFF, size) };
+                live.push((ptr, size));
+            } else {
+                let (ptr, size) = live.swap_remove(random.below(live.len()));
+                test_heap.dealloc(ptr, size);
+            }
+        }
+    }
+
+    #[test]
+    fn grown_heap_hides_the_old_sentinel() {
+        let mut test_heap = TestHeap::new(HEAP_SIZE);
+        let start = test_heap.start();
+
+        unsafe { test_heap.heap.set_end_heap(start + PAGE_SIZE) };
+        // the free block before the sentinel grows over it
+        let small = test_heap.alloc_zeroed(16);
+        test_heap.dealloc(small, 16);
+        unsafe { test_heap.heap.set_end_heap(start + HEAP_SIZE) };
+
+        test_heap.alloc_zeroed(4 * PAGE_SIZE);
+    }
+
+    #[test]
+    fn random_alloc_free_never_overlap() {
+        let test_heap = TestHeap::new(HEAP_SIZE);
+        let start = test_heap.start();
+        let free_bytes = test_heap.heap.free_space().free_bytes;
+        let mut random = Random(0x2545F4914F6CDD1D);
+        // ptr, layout, tag
+        let mut live: Vec<(*mut u8, Layout, u8)> = Vec::new();
+
+        for i in 0..20000 {
+            // alloc a bit more often than free, so the heap gets full sometimes
+            if random.below(3) != 0 || live.is_empty() {
+                let size = 1 + random.below(2048);
+                let align = 1 << random.below(8);
+                let layout = Layout::from_size_align(size, align).unwrap();
+
+                let ptr = unsafe { test_heap.heap.alloc(layout) };
+                if ptr.is_null() {
+                    continue;
+                }
+
+                let addr = ptr as usize;
+                assert!(start <= addr && addr + size <= start + HEAP_SIZE);
+                assert_eq!(0, addr % align);
+                for (other, other_layout, _) in live.iter() {
+                    let other = *other as usize;
+                    assert!(
+                        addr + size <= other || other + other_layout.size() <= addr,
+                        "0x{:X} ({} bytes) overlaps 0x{:X} ({} bytes)",
+                        addr,
+                        size,
+                        other,
+                        other_layout.size()
+                    );
+                }
+
+                unsafe { core::ptr::write_bytes(ptr, i as u8, size) };
+                live.push((ptr, layout, i as u8));
+            } else {
+                let (ptr, layout, tag) = live.swap_remove(random.below(live.len()));
+                let data = unsafe { core::slice::from_raw_parts(ptr, layout.size()) };
+                assert!(data.iter().all(|byte| *byte == tag));
+                unsafe { test_heap.heap.dealloc(ptr, layout) };
+            }
+        }
+
+        for (ptr, layout, _) in live.drain(..) {
+            unsafe { test_heap.heap.dealloc(ptr, layout) };
+        }
+        // everything merged back into the single block it started with
+        let free_space = test_heap.heap.free_space();
+        assert_eq!(free_bytes, free_space.free_bytes);
+        assert_eq!(free_bytes, free_space.largest_free_block);
+    }
+}
@@ -1,9 +1,17 @@
+#[cfg(feature = "heap_debug")]
+use super::heap_debug;
+use super::heap_stats::{FreeSpace, HeapStats};
+#[cfg(feature = "heap_track")]
+use super::heap_tracker::AllocationTracker;
 use super::paging::VirtualAddr;
+use super::slab::{RawSlabCache, SlabFrames, SlabStats, KERNEL_FRAMES};
+use super::{KERNEL_HEAP_END, KERNEL_HEAP_START, PAGE_SIZE};
 use crate::concurrency::spin_mutex::SpinMutex;
+use crate::interrupts::without_interrupts;
 use crate::runtime_static::RuntimeStatic;
 use core::{
     alloc::{GlobalAlloc, Layout},
-    cell::UnsafeCell,
+    cell::{Cell, UnsafeCell},
     mem::size_of,
 };
 
@@ -22,6 +30,8 @@ pub struct HeapAllocator {
     start_heap: VirtualAddr,
     head_of_heap_head: UnsafeCell<Option<*mut HeapHead>>,
     end_heap: VirtualAddr,
+    // nothing was ever written after this, so the memory there is still zero
+    untouched_from: Cell<usize>,
 }
 
 #[derive(Clone)]
@@ -182,8 +192,6 @@ impl HeapHead {
             return Err("Too big needed padding");
         }
         let offset_ptr = offset_ptr as u8;
-        // this does not require a feature
-        *(new_allocated_space.offset(-1)) = offset_ptr;
 
         //crate::println!("End avaiable space: 0x{:X}", end_avaiable_space);
 
@@ -200,6 +208,10 @@ impl HeapHead {
         }
         //crate::println!("YES");
 
+        // only now, before the check the byte could be inside the next allocated space
+        // this does not require a feature
+        *(new_allocated_space.offset(-1)) = offset_ptr;
+
         let current_near_heap_head = Near::HeapHead(new_heap_head_pos);
         match from {
             From::HeapHead(heap_head) => {
@@ -321,13 +333,97 @@ impl HeapAllocator {
             start_heap: VirtualAddr::new(start_heap),
             head_of_heap_head: UnsafeCell::new(None),
             end_heap: VirtualAddr::new(end_heap),
+            untouched_from: Cell::new(end_heap),
         }
     }
-}
 
-/// Let's start creating a bump allocator
-unsafe impl GlobalAlloc for HeapAllocator {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+    /// Like new but [start_heap, end_heap) MUST be zeroed (like the .bss),
+    /// so alloc_zeroed can skip the memory never used
+    pub unsafe fn new_zeroed(start_heap: usize, end_heap: usize) -> HeapAllocator {
+        let heap = Self::new(start_heap, end_heap);
+        heap.untouched_from.set(start_heap);
+        heap
+    }
+
+    pub fn contains(&self, ptr: *mut u8) -> bool {
+        self.start_heap.get() <= ptr as usize && (ptr as usize) < self.end_heap.get()
+    }
+
+    pub fn end_heap(&self) -> usize {
+        self.end_heap.get()
+    }
+
+    /// First address after the last allocated space, start_heap if the heap is empty
+    pub fn used_end(&self) -> usize {
+        match self.last_heap_head() {
+            Some(last) => unsafe { (*last).get_end_of_allocated_space() },
+            None => self.start_heap.get(),
+        }
+    }
+
+    /// Move the end of the heap, new_end MUST NOT be lower than used_end
+    /// and the memory added MUST be zeroed
+    pub unsafe fn set_end_heap(&mut self, new_end: usize) {
+        self.end_heap = VirtualAddr::new(new_end);
+        if new_end < self.untouched_from.get() {
+            self.untouched_from.set(new_end);
+        }
+
+        // the last HeapHead is the only one that knows where the heap ends
+        if let Some(last) = self.last_heap_head() {
+            ((&mut (*last).next) as *mut Near).write_volatile(Near::Tail(self.end_heap.clone()));
+        }
+    }
+
+    fn last_heap_head(&self) -> Option<*mut HeapHead> {
+        let hohh = unsafe { (*self.head_of_heap_head.get())? };
+        unsafe { &mut *hohh }.into_iter().last()
+    }
+
+    // the offset to the HeapHead is stored just before the allocated space
+    unsafe fn heap_head_of(ptr: *mut u8, layout: Layout) -> &'static mut HeapHead {
+        let offset = *ptr.offset(-1) as isize * (-1);
+        //crate::println!("{}", offset);
+        let heap_head = &mut *(ptr.offset(offset) as *mut HeapHead);
+
+        // a wrong offset byte would point to something that is not the HeapHead of ptr
+        if heap_head.allocated_space != ptr
+            || layout.size() != heap_head.dim
+            || heap_head.allocated_space.align_offset(layout.align()) != 0
+        {
+            // the heap lock is held, the panic handler doesn't take it
+            panic!(
+                "Heap corruption, free of {:p} with size {} and align {} not allocated like that",
+                ptr,
+                layout.size(),
+                layout.align()
+            );
+        }
+        heap_head
+    }
+
+    fn touch(&self, end: usize) {
+        if end > self.untouched_from.get() {
+            self.untouched_from.set(end);
+        }
+    }
+
+    /// Resize the allocated space of ptr without moving it,
+    /// false if the free space after it is not enough
+    pub unsafe fn realloc_in_place(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> bool {
+        let heap_head = Self::heap_head_of(ptr, layout);
+
+        // the free space ends where the next HeapHead (or the heap) starts
+        if new_size > heap_head.next.get_ptr_usize() - ptr as usize {
+            return false;
+        }
+
+        heap_head.dim = new_size;
+        self.touch(ptr as usize + new_size);
+        true
+    }
+
+    unsafe fn insert(&self, layout: Layout) -> *mut u8 {
         macro_rules! try_insert_from_start_heap {
             ($next: expr) => {
                 match HeapHead::try_insert(
@@ -384,21 +480,49 @@ unsafe impl GlobalAlloc for HeapAllocator {
 
         return core::ptr::null_mut();
     }
+}
+
+/// Let's start creating a bump allocator
+unsafe impl GlobalAlloc for HeapAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.insert(layout);
+        if !ptr.is_null() {
+            self.touch(ptr as usize + layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let untouched_from = self.untouched_from.get();
+        let ptr = self.alloc(layout);
+
+        // only what is before untouched_from could be dirty
+        if !ptr.is_null() && (ptr as usize) < untouched_from {
+            let dirty = (untouched_from - ptr as usize).min(layout.size());
+            core::ptr::write_bytes(ptr, 0, dirty);
+        }
+        ptr
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if self.realloc_in_place(ptr, layout, new_size) {
+            return ptr;
+        }
+
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        let new_ptr = self.alloc(new_layout);
+        if !new_ptr.is_null() {
+            core::ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+            self.dealloc(ptr, layout);
+        }
+        new_ptr
+    }
 
     // How can I manage deallc better than O(n)
     // Knowing the pointer I can go directly to the start of the allocated space
     // so I can store just before it the pointer to the allocated space
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        let offset = *ptr.offset(-1) as isize * (-1);
-        //crate::println!("{}", offset);
-        let heap_head = &mut *(ptr.offset(offset) as *mut HeapHead);
-
-        if layout.size() != heap_head.dim
-            || heap_head.allocated_space.align_offset(layout.align()) != 0
-        {
-            //panic!("LLLOOLLLL");
-            crate::handle_alloc_error(layout);
-        }
+        let heap_head = Self::heap_head_of(ptr, layout);
 
         let to_update_next = heap_head.next.clone();
         //crate::println!("to_update_next: {:?}", to_update_next);
@@ -426,177 +550,1039 @@ unsafe impl GlobalAlloc for HeapAllocator {
     }
 }
 
-unsafe impl GlobalAlloc for RuntimeStatic<SpinMutex<HeapAllocator>> {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        loop {
-            let hhof = match self.get_head_of_heap_heads() {
-                Some(ptr) => unsafe { &mut *ptr },
-                None => {
-                    crate::println!("EMPTY heap heads list");
-                    break;
-                }
-            };
-            for (i, h) in hhof.into_iter().enumerate() {
-                let h = unsafe { &*h };
-                crate::println!("{} -> {:?}", i, h);
+/// What KernelHeap needs from the allocator of one of its regions
+pub trait HeapRegion: GlobalAlloc {
+    // bytes needed by an allocation other than its size and its alignment
+    const BLOCK_OVERHEAD: usize;
+
+    /// [start_heap, end_heap) MUST be zeroed and not used by anything else
+    unsafe fn new(start_heap: usize, end_heap: usize) -> Self;
+    fn contains(&self, ptr: *mut u8) -> bool;
+    fn end_heap(&self) -> usize;
+    /// The heap can't end before this
+    fn used_end(&self) -> usize;
+    /// Nothing is allocated after ptr, only freeing it can lower used_end
+    unsafe fn is_last(&self, ptr: *mut u8, layout: Layout) -> bool;
+    /// Move the end of the heap, new_end MUST NOT be lower than used_end
+    /// and the memory added MUST be zeroed
+    unsafe fn set_end_heap(&mut self, new_end: usize);
+    fn free_space(&self) -> FreeSpace;
+    fn print_blocks(&self);
+
+    /// Resize the allocation without moving it, false if it is not possible
+    unsafe fn realloc_in_place(&self, _ptr: *mut u8, _layout: Layout, _new_size: usize) -> bool {
+        false
+    }
+}
+
+impl HeapRegion for HeapAllocator {
+    // a new HeapHead after the last allocated space, plus its padding
+    const BLOCK_OVERHEAD: usize = 2 * size_of::<HeapHead>();
+
+    unsafe fn new(start_heap: usize, end_heap: usize) -> Self {
+        HeapAllocator::new_zeroed(start_heap, end_heap)
+    }
+
+    fn contains(&self, ptr: *mut u8) -> bool {
+        HeapAllocator::contains(self, ptr)
+    }
+
+    fn end_heap(&self) -> usize {
+        HeapAllocator::end_heap(self)
+    }
+
+    fn used_end(&self) -> usize {
+        HeapAllocator::used_end(self)
+    }
+
+    // used_end walks the whole list, this doesn't
+    unsafe fn is_last(&self, ptr: *mut u8, layout: Layout) -> bool {
+        matches!(Self::heap_head_of(ptr, layout).next, Near::Tail(_))
+    }
+
+    unsafe fn set_end_heap(&mut self, new_end: usize) {
+        HeapAllocator::set_end_heap(self, new_end)
+    }
+
+    unsafe fn realloc_in_place(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> bool {
+        HeapAllocator::realloc_in_place(self, ptr, layout, new_size)
+    }
+
+    // the spaces between the allocations, a new one needs also space for its HeapHead
+    fn free_space(&self) -> FreeSpace {
+        let mut free_space = FreeSpace::default();
+
+        let hohh = match unsafe { *self.head_of_heap_head.get() } {
+            Some(hohh) => hohh,
+            None => {
+                free_space.add_block(self.end_heap.get() - self.start_heap.get());
+                return free_space;
             }
-            crate::println!("");
-            break;
+        };
+
+        free_space.add_block(hohh as usize - self.start_heap.get());
+        for heap_head in unsafe { &mut *hohh }.into_iter() {
+            let heap_head = unsafe { &*heap_head };
+            free_space
+                .add_block(heap_head.next.get_ptr_usize() - heap_head.get_end_of_allocated_space());
         }
-        self.lock().alloc(layout)
+        free_space
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        self.lock().dealloc(ptr, layout)
+    fn print_blocks(&self) {
+        let hhof = match unsafe { *self.head_of_heap_head.get() } {
+            Some(ptr) => unsafe { &mut *ptr },
+            None => {
+                crate::println!("EMPTY heap heads list");
+                return;
+            }
+        };
+        for (i, h) in hhof.into_iter().enumerate() {
+            let h = unsafe { &*h };
+            crate::println!("{} -> {:?}", i, h);
+        }
+        crate::println!("");
     }
 }
 
-impl RuntimeStatic<SpinMutex<HeapAllocator>> {
-    fn get_head_of_heap_heads(&self) -> Option<*mut HeapHead> {
-        unsafe { *self.lock().head_of_heap_head.get() }
-    }
+// the segregated fit allocator is the default, the HeapHead list can still be chosen with a feature
+#[cfg(not(feature = "list_heap"))]
+pub type GlobalHeap = KernelHeap<super::free_list_allocator::FreeListAllocator>;
+#[cfg(feature = "list_heap")]
+pub type GlobalHeap = KernelHeap<HeapAllocator>;
+
+// small allocations are served by the slab of the first class big enough
+pub(super) const SLAB_SIZE_CLASSES: [usize; 6] = [16, 32, 64, 128, 256, 512];
+
+/// The kernel heap is made of two regions:
+///
+/// + the boot heap, reserved in the .bss by start.s, the only memory usable
+///   before paging is enabled (the frame allocator lives there)
+/// + the growable heap in [KERNEL_HEAP_START, KERNEL_HEAP_END), empty at boot.
+///   When an allocation does not fit anywhere new pages are mapped at its end,
+///   and the free pages at its end are given back when they are too many
+///
+/// The small allocations go first to the slab caches (one for every size class)
+/// and only if they fail to the regions
+pub struct KernelHeap<R: HeapRegion> {
+    boot_heap: R,
+    growable_heap: R,
+    memory: HeapMemory,
+    slabs: [RawSlabCache; SLAB_SIZE_CLASSES.len()],
+    stats: HeapStats,
+    #[cfg(feature = "heap_track")]
+    tracker: AllocationTracker,
 }
 
-pub mod tests {
-    pub fn home_made_test() {
-        use crate::println;
-        use crate::GLOBAL_ALLOC;
-        use core::alloc::{GlobalAlloc, Layout};
+/// Memory the KernelHeap takes from the memory manager: the range where the growable heap
+/// can be, the pages mapped at its end and the frames of the slabs
+#[derive(Debug, Clone, Copy)]
+pub struct HeapMemory {
+    pub start: usize,
+    pub end: usize,
+    /// The page MUST be zeroed
+    pub map_page: fn(usize) -> Result<(), &'static str>,
+    pub unmap_page: fn(usize),
+    pub slab_frames: SlabFrames,
+}
+
+const KERNEL_HEAP_MEMORY: HeapMemory = HeapMemory {
+    start: KERNEL_HEAP_START,
+    end: KERNEL_HEAP_END,
+    map_page: super::map_kernel_heap_page,
+    unmap_page: super::unmap_kernel_heap_page,
+    slab_frames: KERNEL_FRAMES,
+};
+
+// grow at least of this amount, otherwise a lot of small allocations
+// will map one page at time
+const MIN_HEAP_GROWTH: usize = 16 * PAGE_SIZE;
+// free space tolerated at the end of the growable heap, bigger than
+// MIN_HEAP_GROWTH to avoid mapping and unmapping the same pages continuously
+const MAX_HEAP_TRAILING_FREE: usize = 4 * MIN_HEAP_GROWTH;
+
+impl<R: HeapRegion> KernelHeap<R> {
+    /// The boot heap MUST be zeroed, like the .bss
+    #[cfg_attr(all(test, not(target_os = "none")), allow(dead_code))]
+    pub unsafe fn new(boot_heap_start: usize, boot_heap_end: usize) -> Self {
+        Self::with_memory(boot_heap_start, boot_heap_end, KERNEL_HEAP_MEMORY)
+    }
+
+    /// Like new, with the growable heap and the slabs inside memory
+    pub unsafe fn with_memory(
+        boot_heap_start: usize,
+        boot_heap_end: usize,
+        memory: HeapMemory,
+    ) -> Self {
+        Self {
+            boot_heap: R::new(boot_heap_start, boot_heap_end),
+            growable_heap: R::new(memory.start, memory.start),
+            memory,
+            slabs: SLAB_SIZE_CLASSES
+                .map(|size| RawSlabCache::with_frames(size, size, memory.slab_frames)),
+            stats: HeapStats::new(),
+            #[cfg(feature = "heap_track")]
+            tracker: AllocationTracker::new(),
+        }
+    }
 
-        println!("");
-        println!("HOME MADE TEST");
+    pub fn stats(&self) -> HeapStats {
+        self.stats
+    }
 
-        //println!("HeapHead dimension: {}", size_of::<super::HeapHead>());
+    /// Print the counters, the free space of the regions, the slabs used
+    /// and (with heap_track) every live allocation
+    pub fn dump(&self) {
+        let stats = self.stats;
+        crate::println!(
+            "Kernel heap: {} bytes in use (peak {}) in {} allocations",
+            stats.bytes_in_use,
+            stats.peak_bytes_in_use,
+            stats.live_allocations
+        );
+        crate::println!(
+            "  allocations: {}, frees: {}, failed: {}",
+            stats.allocations,
+            stats.frees,
+            stats.failed_allocations
+        );
+
+        let growable_size = self.growable_heap.end_heap() - self.memory.start;
+        for (name, region) in [
+            ("boot heap", &self.boot_heap),
+            ("growable heap", &self.growable_heap),
+        ] {
+            let free_space = region.free_space();
+            crate::println!(
+                "  {}: {} bytes free, largest free block {}, fragmentation {}%",
+                name,
+                free_space.free_bytes,
+                free_space.largest_free_block,
+                free_space.fragmentation()
+            );
+        }
+        crate::println!("  growable heap mapped: {} bytes", growable_size);
+
+        for slab in self.slab_stats().filter(|slab| slab.allocations != 0) {
+            crate::println!(
+                "  slab {}: {} slabs, {} objects in use, {} allocations",
+                slab.object_size,
+                slab.slabs,
+                slab.objects_in_use,
+                slab.allocations
+            );
+        }
 
-        let print_allocated_spaces = || {
-            let hhof = match GLOBAL_ALLOC.get_head_of_heap_heads() {
-                Some(ptr) => unsafe { &mut *ptr },
-                None => {
-                    println!("EMPTY heap heads list");
-                    return;
+        #[cfg(feature = "heap_track")]
+        {
+            crate::println!("  live allocations (address, size, return addresses):");
+            for allocation in self.tracker.iter() {
+                crate::print!("    0x{:X} {}", allocation.ptr, allocation.size);
+                for address in allocation.site.iter().take_while(|address| **address != 0) {
+                    crate::print!(" 0x{:X}", address);
                 }
-            };
-            for (i, h) in hhof.into_iter().enumerate() {
-                let h = unsafe { &*h };
-                println!("{} -> {:?}", i, h);
+                crate::println!("");
             }
-            println!("");
-        };
+            if self.tracker.untracked != 0 {
+                crate::println!("  {} allocations not tracked", self.tracker.untracked);
+            }
+        }
+    }
 
-        unsafe {
-            // use a differenst scope to drop GLOBAL_ALLOC, otherwise could couse a dead_lock
+    fn record_alloc(&mut self, ptr: *mut u8, size: usize) {
+        if ptr.is_null() {
+            self.stats.failed_allocations += 1;
+            return;
+        }
 
-            let alloc = |size: usize, align: usize| -> *mut u8 {
-                let ptr = GLOBAL_ALLOC.alloc(
-                    Layout::from_size_align(size, align).expect("This creation should not fail"),
-                );
-                if ptr.is_null() {
-                    panic!("ALLOCATION GONE WRONG");
-                }
-                //print_allocated_spaces();
-                ptr
-            };
+        self.stats.record_alloc(size);
+        #[cfg(feature = "heap_track")]
+        self.tracker.insert(ptr, size);
+    }
 
-            // allocate for blocks
-            let start_allocation = alloc(3, 2);
-            let middle_1_allocation = alloc(5, 4);
-            let middle_2_allocation = alloc(6, 4);
-            let end_allocation = alloc(9, 8);
+    fn record_free(&mut self, _ptr: *mut u8, size: usize) {
+        self.stats.record_free(size);
+        #[cfg(feature = "heap_track")]
+        self.tracker.remove(_ptr);
+    }
 
-            print_allocated_spaces();
+    fn record_realloc(&mut self, ptr: *mut u8, new_ptr: *mut u8, size: usize, new_size: usize) {
+        if new_ptr.is_null() {
+            self.stats.failed_allocations += 1;
+            return;
+        }
 
-            println!("FINISH ALLOCATION, now start deallocation");
+        self.stats.record_resize(size, new_size);
+        #[cfg(feature = "heap_track")]
+        if new_ptr == ptr {
+            self.tracker.resize(ptr, new_size);
+        } else {
+            self.tracker.remove(ptr);
+            self.tracker.insert(new_ptr, new_size);
+        }
+        #[cfg(not(feature = "heap_track"))]
+        let _ = ptr;
+    }
 
-            // deallocate the firs one
-            GLOBAL_ALLOC.dealloc(
-                start_allocation,
-                Layout::from_size_align(3, 2).expect("This creation should not fail"),
-            );
-            print_allocated_spaces();
+    pub fn slab_stats(&self) -> impl Iterator<Item = SlabStats> + '_ {
+        self.slabs.iter().map(|slab| slab.stats())
+    }
 
-            let start_allocation = alloc(3, 2);
-            print_allocated_spaces();
+    // the classes are powers of two, so every object of a class is aligned to its size
+    fn slab_class(layout: Layout) -> Option<usize> {
+        let size = layout.size().max(layout.align());
+        SLAB_SIZE_CLASSES.iter().position(|class| size <= *class)
+    }
 
-            GLOBAL_ALLOC.dealloc(
-                middle_1_allocation,
-                Layout::from_size_align(5, 4).expect("This creation should not fail"),
-            );
-            print_allocated_spaces();
-            GLOBAL_ALLOC.dealloc(
-                start_allocation,
-                Layout::from_size_align(3, 2).expect("This creation should not fail"),
-            );
-            print_allocated_spaces();
-            GLOBAL_ALLOC.dealloc(
-                end_allocation,
-                Layout::from_size_align(9, 8).expect("This creation should not fail"),
-            );
-            print_allocated_spaces();
-            GLOBAL_ALLOC.dealloc(
-                middle_2_allocation,
-                Layout::from_size_align(6, 4).expect("This creation should not fail"),
-            );
-            print_allocated_spaces();
+    // With heap_debug every block is surrounded by red zones, checked when it is freed
+    #[cfg(feature = "heap_debug")]
+    unsafe fn alloc(&mut self, layout: Layout, zeroed: bool) -> *mut u8 {
+        match heap_debug::outer_layout(layout) {
+            Some(outer) => heap_debug::init_block(self.alloc_block(outer, false), layout, zeroed),
+            None => core::ptr::null_mut(),
         }
+    }
 
-        println!("Allocation layouts test OK");
+    #[cfg(feature = "heap_debug")]
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        let block = heap_debug::check_block(ptr, layout);
+        let outer = heap_debug::outer_layout(layout).expect("Layout allocated before");
+        self.dealloc_block(block, outer);
+    }
 
-        {
-            let new_box = alloc::boxed::Box::new(1);
-            assert_eq!(1, *new_box);
-            println!("Box test OK");
-
-            let n = 1000;
-            let mut vec = alloc::vec::Vec::new();
-            for i in 0..n {
-                vec.push(i);
+    // the red zones can't be moved, always copy
+    #[cfg(feature = "heap_debug")]
+    unsafe fn realloc(&mut self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        self.realloc_by_copy(ptr, layout, new_size)
+    }
+
+    #[cfg(not(feature = "heap_debug"))]
+    unsafe fn alloc(&mut self, layout: Layout, zeroed: bool) -> *mut u8 {
+        self.alloc_block(layout, zeroed)
+    }
+
+    #[cfg(not(feature = "heap_debug"))]
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        self.dealloc_block(ptr, layout)
+    }
+
+    #[cfg(not(feature = "heap_debug"))]
+    unsafe fn realloc(&mut self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+
+        let in_place = if self.growable_heap.contains(ptr) {
+            self.growable_heap.realloc_in_place(ptr, layout, new_size)
+        } else if self.boot_heap.contains(ptr) {
+            self.boot_heap.realloc_in_place(ptr, layout, new_size)
+        } else {
+            // inside a slab, the slot is big enough for every size of its class
+            Self::slab_class(layout) == Self::slab_class(new_layout)
+        };
+        if in_place {
+            if self.growable_heap.contains(ptr) && self.growable_heap.is_last(ptr, new_layout) {
+                self.shrink();
             }
-            assert_eq!(vec.iter().sum::<u32>(), (n - 1) * n / 2);
-            println!("Vec test OK");
+            return ptr;
+        }
 
-            crate::print!("test print vec: ");
-            vec.iter().for_each(|i| crate::print!("{}", i));
-            crate::println!("");
+        self.realloc_by_copy(ptr, layout, new_size)
+    }
 
-            let str = alloc::string::String::from("Test");
-            crate::println!("{}", str);
-            let str_2 = alloc::string::String::from(" - format test");
-            crate::println!("{}", format!("{}{}", str, str_2));
+    unsafe fn realloc_by_copy(&mut self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        let new_ptr = self.alloc(new_layout, false);
+        if !new_ptr.is_null() {
+            core::ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+            self.dealloc(ptr, layout);
+        }
+        new_ptr
+    }
 
-            print_allocated_spaces();
+    unsafe fn alloc_block(&mut self, layout: Layout, zeroed: bool) -> *mut u8 {
+        if let Some(class) = Self::slab_class(layout) {
+            let ptr = self.slabs[class].alloc();
+            if !ptr.is_null() {
+                // the slots are always reused
+                if zeroed {
+                    core::ptr::write_bytes(ptr, 0, layout.size());
+                }
+                return ptr;
+            }
         }
 
-        print_allocated_spaces();
+        // the regions know which memory was never used
+        let region_alloc = |region: &R| match zeroed {
+            true => region.alloc_zeroed(layout),
+            false => region.alloc(layout),
+        };
 
-        let new_box = alloc::boxed::Box::new("ciao");
-        assert_eq!("ciao", *new_box);
-        println!("Box test OK");
+        let ptr = region_alloc(&self.boot_heap);
+        if !ptr.is_null() {
+            return ptr;
+        }
 
-        print_allocated_spaces();
+        let ptr = region_alloc(&self.growable_heap);
+        if !ptr.is_null() || self.grow(layout).is_err() {
+            return ptr;
+        }
+        region_alloc(&self.growable_heap)
+    }
+
+    unsafe fn dealloc_block(&mut self, ptr: *mut u8, layout: Layout) {
+        if self.growable_heap.contains(ptr) {
+            // the free pages at the end can only come from the last allocation
+            let last = self.growable_heap.is_last(ptr, layout);
+            self.growable_heap.dealloc(ptr, layout);
+            if last {
+                self.shrink();
+            }
+        } else if self.boot_heap.contains(ptr) {
+            self.boot_heap.dealloc(ptr, layout);
+        } else {
+            // outside the regions, it can only be inside a slab
+            match Self::slab_class(layout) {
+                Some(class) => self.slabs[class].free(ptr),
+                None => panic!("dealloc of {:p} outside the heap", ptr),
+            }
+        }
+    }
+
+    /// Map enough new pages at the end of the growable heap to fit layout,
+    /// fails before paging is enabled
+    fn grow(&mut self, layout: Layout) -> Result<(), &'static str> {
+        let needed = layout
+            .size()
+            .checked_add(layout.align() + R::BLOCK_OVERHEAD)
+            .ok_or("Allocation too big")?
+            .max(MIN_HEAP_GROWTH);
+
+        let old_end = self.growable_heap.end_heap();
+        let new_end = old_end
+            .checked_add(needed)
+            .and_then(|end| end.checked_add(PAGE_SIZE - 1))
+            .map(|end| end & !(PAGE_SIZE - 1))
+            .filter(|end| *end <= self.memory.end)
+            .ok_or("Kernel heap exhausted")?;
+
+        for page in (old_end..new_end).step_by(PAGE_SIZE) {
+            if let Err(msg) = (self.memory.map_page)(page) {
+                // give back what was already mapped
+                (old_end..page)
+                    .step_by(PAGE_SIZE)
+                    .for_each(self.memory.unmap_page);
+                return Err(msg);
+            }
+        }
 
-        println!("Allocation test FINISHED");
+        unsafe { self.growable_heap.set_end_heap(new_end) };
+        Ok(())
+    }
+
+    /// Unmap the free pages after the last allocated space of the growable heap
+    fn shrink(&mut self) {
+        let end = self.growable_heap.end_heap();
+        let used_end = (self.growable_heap.used_end() + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        if end - used_end <= MAX_HEAP_TRAILING_FREE {
+            return;
+        }
+
+        // keep some space for the next allocations
+        let new_end = used_end + MIN_HEAP_GROWTH;
+        unsafe { self.growable_heap.set_end_heap(new_end) };
+        (new_end..end)
+            .step_by(PAGE_SIZE)
+            .for_each(self.memory.unmap_page);
     }
 }
 
-/* TODO find a way to use cargo TEST
-#[cfg(test)]
-mod tests {
-    use alloc::{ boxed::Box, vec::Vec };
+// The lock is always taken with the interrupts disabled, otherwise a task
+// could be preempted while holding it and the next one will spin forever
+unsafe impl<R: HeapRegion> GlobalAlloc for RuntimeStatic<SpinMutex<KernelHeap<R>>> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        without_interrupts(|| {
+            let mut heap = self.lock();
+            let ptr = heap.alloc(layout, false);
+            heap.record_alloc(ptr, layout.size());
+            ptr
+        })
+    }
 
-    #[test]
-    fn box_allocation_test() {
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        without_interrupts(|| {
+            let mut heap = self.lock();
+            let ptr = heap.alloc(layout, true);
+            heap.record_alloc(ptr, layout.size());
+            ptr
+        })
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        without_interrupts(|| {
+            let mut heap = self.lock();
+            let new_ptr = heap.realloc(ptr, layout, new_size);
+            heap.record_realloc(ptr, new_ptr, layout.size(), new_size);
+            new_ptr
+        })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        without_interrupts(|| {
+            let mut heap = self.lock();
+            heap.record_free(ptr, layout.size());
+            heap.dealloc(ptr, layout)
+        })
+    }
+}
+
+impl<R: HeapRegion> RuntimeStatic<SpinMutex<KernelHeap<R>>> {
+    #[cfg_attr(all(test, not(target_os = "none")), allow(dead_code))]
+    pub fn stats(&self) -> HeapStats {
+        without_interrupts(|| self.lock().stats())
+    }
+
+    #[cfg_attr(all(test, not(target_os = "none")), allow(dead_code))]
+    pub fn dump(&self) {
+        without_interrupts(|| self.lock().dump())
+    }
+}
+
+// inside the kernel, see build_and_test.sh
+#[cfg(all(test, target_os = "none"))]
+mod kernel_tests {
+    use crate::GLOBAL_ALLOC;
+    use alloc::{boxed::Box, string::String, vec::Vec};
+    use core::alloc::{GlobalAlloc, Layout};
+
+    #[test_case]
+    fn allocation_layouts() {
+        let layouts = [(3, 2), (5, 4), (6, 4), (9, 8)]
+            .map(|(size, align)| Layout::from_size_align(size, align).unwrap());
+        let alloc = |layout: Layout| {
+            let ptr = unsafe { GLOBAL_ALLOC.alloc(layout) };
+            assert!(!ptr.is_null());
+            assert_eq!(0, ptr.align_offset(layout.align()));
+            ptr
+        };
+
+        let mut ptrs = layouts.map(alloc);
+
+        // free the first one and take its place again, then free in a different order
+        unsafe { GLOBAL_ALLOC.dealloc(ptrs[0], layouts[0]) };
+        ptrs[0] = alloc(layouts[0]);
+
+        for i in [1, 0, 3, 2] {
+            unsafe { GLOBAL_ALLOC.dealloc(ptrs[i], layouts[i]) };
+        }
+    }
+
+    #[test_case]
+    fn box_allocation() {
         let new_box = Box::new(1);
         assert_eq!(1, *new_box);
+
+        let new_box = Box::new("ciao");
+        assert_eq!("ciao", *new_box);
     }
 
-    #[test]
+    #[test_case]
     fn large_vec() {
         let n = 1000;
         let mut vec = Vec::new();
         for i in 0..n {
             vec.push(i);
         }
-        assert_eq!(vec.iter().sum::<u64>(), (n-1)*n/2);
+        assert_eq!(vec.iter().sum::<u32>(), (n - 1) * n / 2);
+    }
+
+    #[test_case]
+    fn string_format() {
+        let str = String::from("Test");
+        let str_2 = String::from(" - format test");
+        assert_eq!("Test - format test", format!("{}{}", str, str_2));
+    }
+
+    #[test_case]
+    fn heap_grows_past_the_boot_heap() {
+        // bigger than the whole boot heap
+        let size = 8 * 1024 * 1024;
+        let mut vec = Vec::<u8>::with_capacity(size);
+        vec.resize(size, 0xAB);
+        assert!(vec.as_ptr() as usize >= super::KERNEL_HEAP_START);
+        assert_eq!(0xAB, vec[0]);
+        assert_eq!(0xAB, vec[size - 1]);
+        drop(vec);
+
+        // the pages are given back, the same allocation must work again
+        let vec = alloc::vec![0u8; size];
+        assert!(vec.iter().all(|byte| *byte == 0));
+    }
+}
+
+// on the host, see test_on_host.sh
+#[cfg(all(test, not(target_os = "none")))]
+mod host_tests {
+    use super::super::free_list_allocator::FreeListAllocator;
+    use super::super::test_support::{self, free_host_frames, Random, TestBuffer, HOST_FRAMES};
+    use super::*;
+    use std::vec::Vec;
+
+    const HEAP_SIZE: usize = 1024 * 1024;
+
+    type TestHeap = test_support::TestHeap<HeapAllocator>;
+
+    struct Allocation {
+        ptr: *mut u8,
+        layout: Layout,
+        tag: u8,
+    }
+
+    impl Allocation {
+        fn end(&self) -> usize {
+            self.ptr as usize + self.layout.size()
+        }
+
+        fn overlaps(&self, other: &Allocation) -> bool {
+            (self.ptr as usize) < other.end() && (other.ptr as usize) < self.end()
+        }
+
+        fn fill(&self) {
+            unsafe { core::ptr::write_bytes(self.ptr, self.tag, self.layout.size()) };
+        }
+
+        fn check(&self) {
+            let data = unsafe { core::slice::from_raw_parts(self.ptr, self.layout.size()) };
+            assert!(
+                data.iter().all(|byte| *byte == self.tag),
+                "allocation at 0x{:X} overwritten",
+                self.ptr as usize
+            );
+        }
+    }
+
+    fn check_new_allocation(test_heap: &TestHeap, live: &[Allocation], new: &Allocation) {
+        assert!(test_heap.start() <= new.ptr as usize && new.end() <= test_heap.end());
+        assert_eq!(0, new.ptr as usize % new.layout.align());
+        for allocation in live {
+            assert!(
+                !allocation.overlaps(new),
+                "0x{:X} ({} bytes) overlaps 0x{:X} ({} bytes)",
+                new.ptr as usize,
+                new.layout.size(),
+                allocation.ptr as usize,
+                allocation.layout.size()
+            );
+        }
+    }
+
+    #[test]
+    fn alloc_inside_heap_and_aligned() {
+        let test_heap = TestHeap::new(HEAP_SIZE);
+        let mut live = Vec::new();
+
+        for align in [1, 2, 4, 8, 16, 32, 64, 128] {
+            for size in [1, 3, 8, 100, 1000, 4096] {
+                let layout = Layout::from_size_align(size, align).unwrap();
+                let ptr = unsafe { test_heap.heap.alloc(layout) };
+                assert!(!ptr.is_null());
+
+                let allocation = Allocation {
+                    ptr,
+                    layout,
+                    tag: 0,
+                };
+                check_new_allocation(&test_heap, &live, &allocation);
+                live.push(allocation);
+            }
+        }
+    }
+
+    #[test]
+    fn too_big_alloc_is_null() {
+        let test_heap = TestHeap::new(PAGE_SIZE);
+        let layout = Layout::from_size_align(2 * PAGE_SIZE, 8).unwrap();
+
+        assert!(unsafe { test_heap.heap.alloc(layout) }.is_null());
+    }
+
+    #[test]
+    fn dealloc_gives_back_the_space() {
+        let test_heap = TestHeap::new(16 * PAGE_SIZE);
+        let layout = Layout::from_size_align(100, 8).unwrap();
+
+        for _ in 0..3 {
+            let mut ptrs = Vec::new();
+            loop {
+                let ptr = unsafe { test_heap.heap.alloc(layout) };
+                if ptr.is_null() {
+                    break;
+                }
+                ptrs.push(ptr);
+            }
+            assert!(ptrs.len() > 1);
+
+            for ptr in ptrs {
+                unsafe { test_heap.heap.dealloc(ptr, layout) };
+            }
+
+            let free_space = test_heap.heap.free_space();
+            assert_eq!(16 * PAGE_SIZE, free_space.free_bytes);
+            assert_eq!(0, free_space.fragmentation());
+        }
+    }
+
+    #[test]
+    fn alloc_zeroed_after_dirty_free() {
+        let test_heap = TestHeap::new(HEAP_SIZE);
+        let layout = Layout::from_size_align(512, 16).unwrap();
+
+        unsafe {
+            let ptr = test_heap.heap.alloc(layout);
+            core::ptr::write_bytes(ptr, 0xAA, layout.size());
+            test_heap.heap.dealloc(ptr, layout);
+
+            let ptr = test_heap.heap.alloc_zeroed(layout);
+            let data = core::slice::from_raw_parts(ptr, layout.size());
+            assert!(data.iter().all(|byte| *byte == 0));
+        }
+    }
+
+    #[test]
+    fn realloc_keeps_the_content() {
+        let test_heap = TestHeap::new(HEAP_SIZE);
+        let layout = Layout::from_size_align(64, 8).unwrap();
+
+        unsafe {
+            let ptr = test_heap.heap.alloc(layout);
+            core::ptr::write_bytes(ptr, 0x11, layout.size());
+
+            // the last allocation can grow in place
+            let grown = test_heap.heap.realloc(ptr, layout, 256);
+            assert_eq!(ptr, grown);
+
+            // now something is after it, so it has to move
+            let blocker = test_heap.heap.alloc(layout);
+            let grown_layout = Layout::from_size_align(256, 8).unwrap();
+            let moved = test_heap.heap.realloc(grown, grown_layout, 4096);
+            assert_ne!(grown, moved);
+            assert!((moved as usize) >= blocker as usize + layout.size());
+
+            let data = core::slice::from_raw_parts(moved, layout.size());
+            assert!(data.iter().all(|byte| *byte == 0x11));
+        }
+    }
+
+    #[test]
+    fn set_end_heap_adds_space() {
+        let mut test_heap = TestHeap::new(4 * PAGE_SIZE);
+        let start = test_heap.start();
+        unsafe { test_heap.heap.set_end_heap(start + PAGE_SIZE) };
+
+        let layout = Layout::from_size_align(PAGE_SIZE, 8).unwrap();
+        assert!(unsafe { test_heap.heap.alloc(layout) }.is_null());
+
+        unsafe { test_heap.heap.set_end_heap(start + 4 * PAGE_SIZE) };
+        assert!(!unsafe { test_heap.heap.alloc(layout) }.is_null());
+        assert!(test_heap.heap.used_end() <= test_heap.heap.end_heap());
+    }
+
+    #[test]
+    fn random_alloc_free_never_overlap() {
+        let test_heap = TestHeap::new(HEAP_SIZE);
+        let mut random = Random(0x2545F4914F6CDD1D);
+        let mut live: Vec<Allocation> = Vec::new();
+
+        for i in 0..20000 {
+            match random.below(4) {
+                // alloc a bit more often than free, so the heap gets full sometimes
+                0 | 1 => {
+                    let size = 1 + random.below(2048);
+                    let align = 1 << random.below(8);
+                    let layout = Layout::from_size_align(size, align).unwrap();
+
+                    let ptr = unsafe { test_heap.heap.alloc(layout) };
+                    if ptr.is_null() {
+                        continue;
+                    }
+
+                    let allocation = Allocation {
+                        ptr,
+                        layout,
+                        tag: i as u8,
+                    };
+                    check_new_allocation(&test_heap, &live, &allocation);
+                    allocation.fill();
+                    live.push(allocation);
+                }
+                2 if !live.is_empty() => {
+                    let allocation = live.swap_remove(random.below(live.len()));
+                    allocation.check();
+                    unsafe { test_heap.heap.dealloc(allocation.ptr, allocation.layout) };
+                }
+                3 if !live.is_empty() => {
+                    let index = random.below(live.len());
+                    let allocation = live.swap_remove(index);
+                    allocation.check();
+
+                    let new_size = 1 + random.below(4096);
+                    let ptr = unsafe {
+                        test_heap
+                            .heap
+                            .realloc(allocation.ptr, allocation.layout, new_size)
+                    };
+                    if ptr.is_null() {
+                        live.push(allocation);
+                        continue;
+                    }
+
+                    let resized = Allocation {
+                        ptr,
+                        layout: Layout::from_size_align(new_size, allocation.layout.align())
+                            .unwrap(),
+                        tag: allocation.tag,
+                    };
+                    // realloc keeps the content that fits in the new size
+                    let kept = resized.layout.size().min(allocation.layout.size());
+                    let data = unsafe { core::slice::from_raw_parts(ptr, kept) };
+                    assert!(data.iter().all(|byte| *byte == allocation.tag));
+
+                    check_new_allocation(&test_heap, &live, &resized);
+                    resized.fill();
+                    live.push(resized);
+                }
+                _ => (),
+            }
+        }
+
+        for allocation in live.drain(..) {
+            allocation.check();
+            unsafe { test_heap.heap.dealloc(allocation.ptr, allocation.layout) };
+        }
+        assert_eq!(HEAP_SIZE, test_heap.heap.free_space().free_bytes);
+    }
+
+    std::thread_local! {
+        // every test runs in its own thread
+        static MAPPED_PAGES: Cell<usize> = Cell::new(0);
+    }
+
+    const GROWABLE_PAGES: usize = 256;
+
+    /// KernelHeap with both the regions over buffers of the host, the pages of the
+    /// growable heap are always there: mapping only zeroes and counts them
+    struct TestKernelHeap<R: HeapRegion> {
+        heap: KernelHeap<R>,
+        boot: TestBuffer,
+        growable: TestBuffer,
+    }
+
+    impl<R: HeapRegion> TestKernelHeap<R> {
+        fn new(boot_pages: usize, slab_frames: SlabFrames) -> Self {
+            let boot = TestBuffer::new(boot_pages * PAGE_SIZE);
+            let growable = TestBuffer::new(GROWABLE_PAGES * PAGE_SIZE);
+            MAPPED_PAGES.with(|mapped| mapped.set(0));
+
+            let memory = HeapMemory {
+                start: growable.start(),
+                end: growable.end(),
+                map_page: |page| {
+                    unsafe { core::ptr::write_bytes(page as *mut u8, 0, PAGE_SIZE) };
+                    MAPPED_PAGES.with(|mapped| mapped.set(mapped.get() + 1));
+                    Ok(())
+                },
+                unmap_page: |_| MAPPED_PAGES.with(|mapped| mapped.set(mapped.get() - 1)),
+                slab_frames,
+            };
+            let heap = unsafe { KernelHeap::with_memory(boot.start(), boot.end(), memory) };
+
+            Self {
+                heap,
+                boot,
+                growable,
+            }
+        }
+
+        fn alloc(&mut self, size: usize, align: usize) -> *mut u8 {
+            let layout = Layout::from_size_align(size, align).unwrap();
+            unsafe { self.heap.alloc_block(layout, false) }
+        }
+
+        fn dealloc(&mut self, ptr: *mut u8, size: usize, align: usize) {
+            let layout = Layout::from_size_align(size, align).unwrap();
+            unsafe { self.heap.dealloc_block(ptr, layout) };
+        }
+
+        fn mapped_pages(&self) -> usize {
+            let mapped = MAPPED_PAGES.with(|mapped| mapped.get());
+            assert_eq!(
+                mapped * PAGE_SIZE,
+                self.heap.growable_heap.end_heap() - self.growable.start()
+            );
+            mapped
+        }
+
+        fn objects_in_use(&self, class: usize) -> usize {
+            self.heap.slabs[class].stats().objects_in_use
+        }
+    }
+
+    impl<R: HeapRegion> Drop for TestKernelHeap<R> {
+        fn drop(&mut self) {
+            free_host_frames();
+        }
+    }
+
+    fn check_slab_routing<R: HeapRegion>() {
+        let mut test_heap = TestKernelHeap::<R>::new(4, HOST_FRAMES);
+
+        // the first class that fits both the size and the alignment
+        let small = test_heap.alloc(24, 8);
+        assert!(!test_heap.boot.contains(small) && !test_heap.growable.contains(small));
+        assert_eq!(1, test_heap.objects_in_use(1));
+
+        let aligned = test_heap.alloc(16, 64);
+        assert_eq!(0, aligned as usize % 64);
+        assert_eq!(1, test_heap.objects_in_use(2));
+
+        // bigger than every class
+        let big = test_heap.alloc(SLAB_SIZE_CLASSES[SLAB_SIZE_CLASSES.len() - 1] + 1, 8);
+        assert!(test_heap.boot.contains(big));
+
+        test_heap.dealloc(small, 24, 8);
+        test_heap.dealloc(aligned, 16, 64);
+        assert_eq!(0, test_heap.objects_in_use(1));
+        assert_eq!(0, test_heap.objects_in_use(2));
+
+        // without frames (before paging) the regions serve also the small ones
+        let no_frames = SlabFrames {
+            allocate: || None,
+            deallocate: |_| panic!("No frame was allocated"),
+        };
+        let mut test_heap = TestKernelHeap::<R>::new(4, no_frames);
+        let small = test_heap.alloc(24, 8);
+        assert!(test_heap.boot.contains(small));
+        test_heap.dealloc(small, 24, 8);
+        assert_eq!(0, test_heap.objects_in_use(1));
+    }
+
+    #[test]
+    fn small_allocations_go_to_the_slabs() {
+        check_slab_routing::<HeapAllocator>();
+        check_slab_routing::<FreeListAllocator>();
+    }
+
+    #[test]
+    #[should_panic(expected = "outside the heap")]
+    fn dealloc_outside_the_heap_panics() {
+        let mut test_heap = TestKernelHeap::<FreeListAllocator>::new(1, HOST_FRAMES);
+        let mut outside = [0u8; 2 * PAGE_SIZE];
+        test_heap.dealloc(outside.as_mut_ptr(), outside.len(), 8);
+    }
+
+    // with heap_debug the red zones can't be moved, realloc always copies
+    #[cfg(not(feature = "heap_debug"))]
+    fn check_realloc_in_place<R: HeapRegion>() {
+        let mut test_heap = TestKernelHeap::<R>::new(4, HOST_FRAMES);
+        let layout = Layout::from_size_align(1024, 8).unwrap();
+
+        // like a Vec that grows, there is only free space after it
+        let ptr = test_heap.alloc(layout.size(), layout.align());
+        unsafe { core::ptr::write_bytes(ptr, 7, layout.size()) };
+        let grown = unsafe { test_heap.heap.realloc(ptr, layout, 2 * layout.size()) };
+        assert_eq!(ptr, grown);
+        let data = unsafe { core::slice::from_raw_parts(grown, layout.size()) };
+        assert!(data.iter().all(|byte| *byte == 7));
+
+        test_heap.dealloc(grown, 2 * layout.size(), layout.align());
+    }
+
+    #[test]
+    #[cfg(not(feature = "heap_debug"))]
+    fn growing_allocation_stays_in_place() {
+        check_realloc_in_place::<HeapAllocator>();
+        check_realloc_in_place::<FreeListAllocator>();
+    }
+
+    fn check_growth<R: HeapRegion>() {
+        let mut test_heap = TestKernelHeap::<R>::new(1, HOST_FRAMES);
+        assert_eq!(0, test_heap.mapped_pages());
+
+        // too big for the boot heap, the growable heap maps at least MIN_HEAP_GROWTH
+        let first = test_heap.alloc(2 * PAGE_SIZE, 8);
+        assert!(test_heap.growable.contains(first));
+        assert_eq!(MIN_HEAP_GROWTH / PAGE_SIZE, test_heap.mapped_pages());
+
+        // more than what is left, it grows of what is needed
+        let size = 20 * PAGE_SIZE;
+        let second = test_heap.alloc(size, 8);
+        assert!(test_heap.growable.contains(second));
+        let needed = (size + 8 + R::BLOCK_OVERHEAD + PAGE_SIZE - 1) / PAGE_SIZE;
+        assert_eq!(
+            MIN_HEAP_GROWTH / PAGE_SIZE + needed,
+            test_heap.mapped_pages()
+        );
+
+        // outside the growable heap, nothing is mapped
+        let mapped = test_heap.mapped_pages();
+        assert!(test_heap.alloc(GROWABLE_PAGES * PAGE_SIZE, 8).is_null());
+        assert_eq!(mapped, test_heap.mapped_pages());
+    }
+
+    #[test]
+    fn growable_heap_grows_of_at_least_min_growth() {
+        check_growth::<HeapAllocator>();
+        check_growth::<FreeListAllocator>();
+    }
+
+    fn check_shrink<R: HeapRegion>() {
+        let mut test_heap = TestKernelHeap::<R>::new(1, HOST_FRAMES);
+
+        let first = test_heap.alloc(2 * PAGE_SIZE, 8);
+        let big_size = MAX_HEAP_TRAILING_FREE + 16 * PAGE_SIZE;
+        let big = test_heap.alloc(big_size, 8);
+        let mapped = test_heap.mapped_pages();
+
+        // the free space at the end is not over MAX_HEAP_TRAILING_FREE, nothing changes
+        test_heap.dealloc(first, 2 * PAGE_SIZE, 8);
+        assert_eq!(mapped, test_heap.mapped_pages());
+
+        // now it is, only MIN_HEAP_GROWTH after the used space is kept
+        test_heap.dealloc(big, big_size, 8);
+        let used_end = test_heap.heap.growable_heap.used_end();
+        let used_pages = (used_end - test_heap.growable.start() + PAGE_SIZE - 1) / PAGE_SIZE;
+        assert_eq!(
+            used_pages + MIN_HEAP_GROWTH / PAGE_SIZE,
+            test_heap.mapped_pages()
+        );
+        assert!(test_heap.mapped_pages() < mapped);
+
+        // and the space left can be used again
+        let again = test_heap.alloc(PAGE_SIZE, 8);
+        assert!(test_heap.growable.contains(again));
+    }
+
+    #[test]
+    fn growable_heap_gives_back_the_trailing_free_pages() {
+        check_shrink::<HeapAllocator>();
+        check_shrink::<FreeListAllocator>();
+    }
+
+    fn check_is_last<R: HeapRegion>() {
+        let test_heap = test_support::TestHeap::<R>::new(HEAP_SIZE);
+        let first = test_heap.alloc(100);
+        let second = test_heap.alloc(100);
+        unsafe {
+            assert!(!test_heap.heap.is_last(first, test_support::layout(100)));
+            assert!(test_heap.heap.is_last(second, test_support::layout(100)));
+        }
+
+        // the free space after it doesn't count
+        test_heap.dealloc(second, 100);
+        assert!(unsafe { test_heap.heap.is_last(first, test_support::layout(100)) });
+    }
+
+    #[test]
+    fn only_the_last_allocation_is_last() {
+        check_is_last::<HeapAllocator>();
+        check_is_last::<FreeListAllocator>();
     }
 }
-*/
@@ -0,0 +1,167 @@
+use core::{alloc::Layout, mem::size_of};
+
+// Every allocation is surrounded by red zones filled with GUARD:
+//
+// |red zone|padding|size|data ...|red zone|
+//
+// The data is filled with UNINIT when allocated (not with alloc_zeroed) and the whole
+// block with FREED when deallocated. On dealloc the red zones and the size are checked,
+// a FREED red zone means the block was already freed
+const RED_ZONE: usize = 32;
+const GUARD: u8 = 0xFD;
+const UNINIT: u8 = 0xCD;
+const FREED: u8 = 0xDD;
+
+// the allocators write their links at the start of a freed block (free list, slabs),
+// so that part of the red zone can't be used to recognize a double free
+const LINKS_SIZE: usize = 2 * size_of::<usize>();
+
+// space before the data, it keeps the data aligned
+fn front_size(layout: Layout) -> usize {
+    let front = RED_ZONE + size_of::<usize>();
+    (front + layout.align() - 1) & !(layout.align() - 1)
+}
+
+/// Layout to ask to the allocator for a block containing layout and the red zones
+pub fn outer_layout(layout: Layout) -> Option<Layout> {
+    let size = layout
+        .size()
+        .checked_add(front_size(layout))?
+        .checked_add(RED_ZONE)?;
+    Layout::from_size_align(size, layout.align()).ok()
+}
+
+/// Write the red zones around the data inside the block allocated with
+/// outer_layout, return the pointer to the data (null if block is null)
+pub unsafe fn init_block(block: *mut u8, layout: Layout, zeroed: bool) -> *mut u8 {
+    if block.is_null() {
+        return block;
+    }
+
+    let front = front_size(layout);
+    let data = block.add(front);
+
+    core::ptr::write_bytes(block, GUARD, front - size_of::<usize>());
+    (data.sub(size_of::<usize>()) as *mut usize).write_unaligned(layout.size());
+    core::ptr::write_bytes(data, if zeroed { 0 } else { UNINIT }, layout.size());
+    core::ptr::write_bytes(data.add(layout.size()), GUARD, RED_ZONE);
+
+    data
+}
+
+/// Check the red zones of the data allocated with init_block and poison the whole block,
+/// return the block to give back to the allocator. Panic if something is wrong
+pub unsafe fn check_block(data: *mut u8, layout: Layout) -> *mut u8 {
+    let front = front_size(layout);
+    let block = data.sub(front);
+
+    let front_guard = core::slice::from_raw_parts(block.add(LINKS_SIZE), RED_ZONE - LINKS_SIZE);
+    if front_guard.iter().all(|byte| *byte == FREED) {
+        report("double free", data, layout.size());
+    }
+    if front_guard.iter().any(|byte| *byte != GUARD) {
+        report("underflow", data, layout.size());
+    }
+
+    let size = (data.sub(size_of::<usize>()) as *const usize).read_unaligned();
+    if size != layout.size() {
+        panic!(
+            "Heap corruption, free with the wrong size of the block at 0x{:X}: freed with size {}, allocated with size {}",
+            data as usize,
+            layout.size(),
+            size
+        );
+    }
+
+    let back_guard = core::slice::from_raw_parts(data.add(size), RED_ZONE);
+    if back_guard.iter().any(|byte| *byte != GUARD) {
+        report("overflow", data, size);
+    }
+
+    core::ptr::write_bytes(block, FREED, front + size + RED_ZONE);
+    block
+}
+
+fn report(problem: &str, data: *mut u8, size: usize) -> ! {
+    panic!(
+        "Heap corruption, {} of the block at 0x{:X} with size {}",
+        problem, data as usize, size
+    );
+}
+
+// on the host, see test_on_host.sh
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+    use std::alloc::{alloc, dealloc};
+
+    const SIZE: usize = 32;
+
+    fn layout() -> Layout {
+        Layout::from_size_align(SIZE, 8).unwrap()
+    }
+
+    // the data of a new block with the red zones, it is leaked by the tests that panic
+    fn new_data() -> *mut u8 {
+        unsafe {
+            let block = alloc(outer_layout(layout()).unwrap());
+            init_block(block, layout(), false)
+        }
+    }
+
+    #[test]
+    fn good_block_is_poisoned() {
+        let data = new_data();
+        unsafe {
+            assert!(core::slice::from_raw_parts(data, SIZE)
+                .iter()
+                .all(|byte| *byte == UNINIT));
+            core::ptr::write_bytes(data, 1, SIZE);
+
+            let block = check_block(data, layout());
+            let outer = outer_layout(layout()).unwrap();
+            assert!(core::slice::from_raw_parts(block, outer.size())
+                .iter()
+                .all(|byte| *byte == FREED));
+            dealloc(block, outer);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "double free")]
+    fn double_free_is_detected() {
+        let data = new_data();
+        unsafe {
+            check_block(data, layout());
+            check_block(data, layout());
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "underflow")]
+    fn underflow_is_detected() {
+        let data = new_data();
+        unsafe {
+            // the last byte of the red zone, just before the size
+            data.sub(size_of::<usize>() + 1).write(0);
+            check_block(data, layout());
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "overflow")]
+    fn overflow_is_detected() {
+        let data = new_data();
+        unsafe {
+            data.add(SIZE).write(0);
+            check_block(data, layout());
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "freed with size 48, allocated with size 32")]
+    fn free_with_the_wrong_size_is_detected() {
+        let data = new_data();
+        unsafe { check_block(data, Layout::from_size_align(SIZE + 16, 8).unwrap()) };
+    }
+}
@@ -0,0 +1,64 @@
+/// Counters of the kernel heap, updated on every allocation
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    pub bytes_in_use: usize,
+    pub peak_bytes_in_use: usize,
+    pub live_allocations: usize,
+    pub allocations: usize,
+    pub frees: usize,
+    pub failed_allocations: usize,
+}
+
+impl HeapStats {
+    pub const fn new() -> Self {
+        Self {
+            bytes_in_use: 0,
+            peak_bytes_in_use: 0,
+            live_allocations: 0,
+            allocations: 0,
+            frees: 0,
+            failed_allocations: 0,
+        }
+    }
+
+    pub fn record_alloc(&mut self, size: usize) {
+        self.allocations += 1;
+        self.live_allocations += 1;
+        self.bytes_in_use += size;
+        self.peak_bytes_in_use = self.peak_bytes_in_use.max(self.bytes_in_use);
+    }
+
+    pub fn record_free(&mut self, size: usize) {
+        self.frees += 1;
+        self.live_allocations -= 1;
+        self.bytes_in_use -= size;
+    }
+
+    pub fn record_resize(&mut self, old_size: usize, new_size: usize) {
+        self.bytes_in_use = self.bytes_in_use - old_size + new_size;
+        self.peak_bytes_in_use = self.peak_bytes_in_use.max(self.bytes_in_use);
+    }
+}
+
+/// Free space of a heap region, computed on demand
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FreeSpace {
+    pub free_bytes: usize,
+    pub largest_free_block: usize,
+}
+
+impl FreeSpace {
+    pub fn add_block(&mut self, size: usize) {
+        self.free_bytes += size;
+        self.largest_free_block = self.largest_free_block.max(size);
+    }
+
+    /// Percentage of the free space outside the largest free block,
+    /// 0 means that everything could be used by one allocation
+    pub fn fragmentation(&self) -> usize {
+        match self.free_bytes {
+            0 => 0,
+            free_bytes => 100 - self.largest_free_block * 100 / free_bytes,
+        }
+    }
+}
@@ -0,0 +1,123 @@
+use crate::stack_frames::StackFrames;
+
+// return addresses saved for every tracked allocation,
+// the first ones are always inside the allocator
+pub const SITE_DEPTH: usize = 8;
+// the tracker can't allocate, the allocations after this are not tracked
+const MAX_TRACKED: usize = 1024;
+// the table is never more than half full, so the probes stay short
+const SLOTS: usize = 2 * MAX_TRACKED;
+
+#[derive(Debug, Clone, Copy)]
+pub struct TrackedAllocation {
+    pub ptr: usize,
+    pub size: usize,
+    // the first return addresses of the call chain that allocated, 0 when it is shorter
+    pub site: [usize; SITE_DEPTH],
+}
+
+/// Live allocations with the place where they were allocated, used to find leaks
+///
+/// Open addressing hash table on the pointer, with linear probing
+pub struct AllocationTracker {
+    allocations: [Option<TrackedAllocation>; SLOTS],
+    tracked: usize,
+    // allocations not tracked because the table was full
+    pub untracked: usize,
+}
+
+impl AllocationTracker {
+    pub const fn new() -> Self {
+        Self {
+            allocations: [None; SLOTS],
+            tracked: 0,
+            untracked: 0,
+        }
+    }
+
+    pub fn insert(&mut self, ptr: *mut u8, size: usize) {
+        if self.tracked == MAX_TRACKED {
+            self.untracked += 1;
+            return;
+        }
+
+        let mut slot = home_slot(ptr as usize);
+        while self.allocations[slot].is_some() {
+            slot = (slot + 1) % SLOTS;
+        }
+        self.allocations[slot] = Some(TrackedAllocation {
+            ptr: ptr as usize,
+            size,
+            site: allocation_site(),
+        });
+        self.tracked += 1;
+    }
+
+    pub fn remove(&mut self, ptr: *mut u8) {
+        let mut hole = match self.find(ptr) {
+            Some(slot) => slot,
+            None => return,
+        };
+        self.allocations[hole] = None;
+        self.tracked -= 1;
+
+        // no tombstones, the next ones of the run that can't be found
+        // anymore past the hole are moved into it
+        let mut slot = hole;
+        loop {
+            slot = (slot + 1) % SLOTS;
+            let allocation = match self.allocations[slot] {
+                Some(allocation) => allocation,
+                None => return,
+            };
+
+            // distance walked by the probe of allocation to get here
+            let probe = (slot + SLOTS - home_slot(allocation.ptr)) % SLOTS;
+            if probe >= (slot + SLOTS - hole) % SLOTS {
+                self.allocations[hole] = self.allocations[slot].take();
+                hole = slot;
+            }
+        }
+    }
+
+    pub fn resize(&mut self, ptr: *mut u8, new_size: usize) {
+        if let Some(slot) = self.find(ptr) {
+            if let Some(allocation) = &mut self.allocations[slot] {
+                allocation.size = new_size;
+            }
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &TrackedAllocation> {
+        self.allocations.iter().flatten()
+    }
+
+    // the probe stops at the first empty slot
+    fn find(&self, ptr: *mut u8) -> Option<usize> {
+        let mut slot = home_slot(ptr as usize);
+        loop {
+            match self.allocations[slot] {
+                Some(allocation) if allocation.ptr == ptr as usize => return Some(slot),
+                Some(_) => slot = (slot + 1) % SLOTS,
+                None => return None,
+            }
+        }
+    }
+}
+
+// fibonacci hashing, the low bits of the pointers are the same because of the alignment
+fn home_slot(ptr: usize) -> usize {
+    ((ptr as u32).wrapping_mul(0x9E37_79B9) >> (32 - SLOTS.trailing_zeros())) as usize
+}
+
+/// The first return addresses of the current call chain
+fn allocation_site() -> [usize; SITE_DEPTH] {
+    let mut site = [0; SITE_DEPTH];
+    let ebp: usize;
+    unsafe { core::arch::asm!("mov {}, ebp", out(reg) ebp) };
+
+    for (slot, return_address) in site.iter_mut().zip(unsafe { StackFrames::from_ebp(ebp) }) {
+        *slot = return_address;
+    }
+    site
+}
@@ -4,11 +4,9 @@
 // change_page_directory(page_direcotry: u32)
 .global change_page_directory
     change_page_directory:
-        //push eax
-        //mov eax, [esp + 4]
-        //mov eax, eax
+        // cdecl, the argument is on the stack
+        mov eax, [esp + 4]
         mov cr3, eax
-        //pop eax
         ret
     
 // enable_paging
@@ -16,7 +14,8 @@
     enable_paging:
         push eax
         mov eax, cr0
-        or eax, 0x80000001
+        // PG | WP | PE, with WP also the kernel fault on read only pages (copy on write)
+        or eax, 0x80010001
         mov cr0, eax
         pop eax
         ret
@@ -24,8 +23,13 @@
 // flush_tlb_entry(virtual_addr)
 .global flush_tlb_entry
     flush_tlb_entry:
-        cli
+        mov eax, [esp + 4]
 		invlpg [eax]
-		sti
+        ret
+
+// current_page_directory() -> u32
+.global current_page_directory
+    current_page_directory:
+        mov eax, cr3
         ret
 
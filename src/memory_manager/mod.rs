@@ -1,14 +1,43 @@
 use super::multiboot::BootInfo;
+use crate::concurrency::spin_mutex::SpinMutex;
+use crate::interrupts::without_interrupts;
+use crate::runtime_static::RuntimeStatic;
 
 const FRAME_SIZE: usize = 4096;
-const PAGE_SIZE: usize = 4096;
+pub const PAGE_SIZE: usize = 4096;
 
 const ENTRIES_PER_PAGE: usize = 1024;
 
+// Virtual memory layout:
+//
+// |0 ... kernel space (identity mapping) ... 768MiB|kernel heap 1GiB|1GiB ... user space ... 3GiB|
+//
+// The kernel space is the same in every address space, all the page directories
+// point to the same kernel page tables
+pub const KERNEL_SPACE_END: usize = 0x40000000;
+// the heap grows here on demand, this part of the kernel space is not identity mapped
+pub const KERNEL_HEAP_START: usize = 0x30000000;
+pub const KERNEL_HEAP_END: usize = KERNEL_SPACE_END;
+pub const KERNEL_PD_ENTRIES: usize = KERNEL_SPACE_END / (ENTRIES_PER_PAGE * PAGE_SIZE);
+pub const USER_SPACE_START: usize = KERNEL_SPACE_END;
+pub const USER_SPACE_END: usize = 0xC0000000;
+
+pub mod address_space;
 pub mod frame_allocator;
+pub mod free_list_allocator;
 pub mod global_allocator;
 pub mod heap_allocator;
+#[cfg(feature = "heap_debug")]
+pub mod heap_debug;
+pub mod heap_stats;
+#[cfg(feature = "heap_track")]
+pub mod heap_tracker;
 pub mod paging;
+pub mod slab;
+// on the host, see test_on_host.sh
+#[cfg(all(test, not(target_os = "none")))]
+mod test_support;
+pub mod vma;
 
 use frame_allocator::{Allocator, Frame, FrameAllocator};
 use paging::*;
@@ -16,7 +45,92 @@ use paging::*;
 extern "C" {
     pub fn change_page_directory(page_direcotry_ptr: usize);
     pub fn enable_paging();
-    //pub fn flush_tlb_entry(virtual_addr_ptr: usize);
+    pub fn flush_tlb_entry(virtual_addr_ptr: usize);
+    pub fn current_page_directory() -> usize;
+}
+
+/// Initialized as soon as paging is enabled, it is used also
+/// inside interrupts (page fault), so access it with with_memory_manager
+pub static MEMORY_MANAGER: RuntimeStatic<SpinMutex<MemoryManager>> = RuntimeStatic::get_uninit();
+
+/// Lock the global MemoryManager with the interrupts disabled
+///
+/// Pay attention, NEVER allocate on the heap inside f,
+/// the heap could need the memory manager to grow
+pub fn with_memory_manager<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut MemoryManager) -> R,
+{
+    without_interrupts(|| f(&mut MEMORY_MANAGER.lock()))
+}
+
+/// Used by the kernel heap to grow, fails if paging is not enabled yet
+pub fn map_kernel_heap_page(virt: usize) -> Result<(), &'static str> {
+    let memory_manager = MEMORY_MANAGER.get().ok_or("Paging not enabled yet")?;
+    without_interrupts(|| {
+        memory_manager
+            .lock()
+            .map_kernel_heap_page(&VirtualAddr::new(virt))
+    })
+}
+
+/// Frame for the kernel objects (like the slabs), reachable with the identity mapping.
+/// None before paging is enabled
+pub fn allocate_kernel_frame() -> Option<Frame> {
+    let memory_manager = MEMORY_MANAGER.get()?;
+    without_interrupts(|| memory_manager.lock().allocate_frame())
+}
+
+pub fn deallocate_kernel_frame(frame: Frame) {
+    if let Some(memory_manager) = MEMORY_MANAGER.get() {
+        without_interrupts(|| memory_manager.lock().deallocate_frame(frame));
+    }
+}
+
+/// Used by the kernel heap to shrink
+pub fn unmap_kernel_heap_page(virt: usize) {
+    if let Some(memory_manager) = MEMORY_MANAGER.get() {
+        without_interrupts(|| {
+            memory_manager
+                .lock()
+                .unmap_kernel_heap_page(&VirtualAddr::new(virt))
+        });
+    }
+}
+
+// CR0.PG
+const CR0_PAGING: usize = 0x80000000;
+
+/// PageTableFlag of the page that contains virt in the page directory in use,
+/// None if it is not mapped. Without paging everything is present and writable
+pub fn current_page_flags(virt: usize) -> Option<u32> {
+    let cr0: usize;
+    unsafe {
+        core::arch::asm!("mov {}, cr0", out(reg) cr0, options(nomem, nostack, preserves_flags))
+    };
+    if cr0 & CR0_PAGING == 0 {
+        return Some(PageTableFlag::Present as u32 | PageTableFlag::Writable as u32);
+    }
+
+    let virt = VirtualAddr::new(virt);
+    let page_directory =
+        PageDirectory::from_physical_address(PhysicalAddr::new(unsafe { current_page_directory() }));
+    let pde = &page_directory[virt.get_pd_index()];
+    if !pde.is_valid_flag(PageDirectoryFlag::Present as u32) {
+        return None;
+    }
+
+    let table = pde.get_page_table();
+    let pte = &table[virt.get_pt_index()];
+    if !pte.is_valid_flag(PageTableFlag::Present as u32) {
+        return None;
+    }
+    // the page is writable only if also the page table is
+    if pde.is_valid_flag(PageDirectoryFlag::Writable as u32) {
+        Some(pte.get_flags())
+    } else {
+        Some(pte.get_flags() & !(PageTableFlag::Writable as u32))
+    }
 }
 
 // PD(2^10 entry = 1024) -> PT(2^10 entry = 1024) -> offset(2^12)
@@ -26,8 +140,10 @@ pub struct MemoryManager {
 }
 
 impl MemoryManager {
-    pub fn new(boot_info: &BootInfo) -> Self {
-        let mut frame_allocator = FrameAllocator::new(boot_info);
+    /// kernel_end is the first physical address not used by the kernel (code, stack and heap)
+    #[cfg_attr(all(test, not(target_os = "none")), allow(dead_code))]
+    pub fn new(boot_info: &BootInfo, kernel_end: usize) -> Self {
+        let mut frame_allocator = FrameAllocator::new(boot_info, kernel_end);
 
         // Now what should be done?
         // Should be allocated a new PageDirectory
@@ -93,6 +209,7 @@ impl MemoryManager {
         //m
     }
 
+    #[cfg_attr(all(test, not(target_os = "none")), allow(dead_code))]
     pub unsafe fn enable_paging(&self) {
         // Change pd
         change_page_directory(self.page_directory.get_physical_addr().get());
@@ -100,12 +217,13 @@ impl MemoryManager {
         enable_paging();
     }
 
+    #[cfg_attr(all(test, not(target_os = "none")), allow(dead_code))]
     pub fn set_up_identity_paging(&mut self, to_limit: usize) -> Result<(), &'static str> {
-        // TODO evaluation, if to_limit goes over 1GiB LHK is no more respected
-        // always identity mapping one page more
-        //crate::println!("limit: {}", to_limit);
-        let needed_pd = (to_limit / (ENTRIES_PER_PAGE * PAGE_SIZE)) + 1;
-        //crate::println!("total pd: {}", needed_pd);
+        // never go over the kernel space
+        crate::debug!("identity paging up to 0x{:X}", to_limit);
+        let pd_size = ENTRIES_PER_PAGE * PAGE_SIZE;
+        let needed_pd = ((to_limit + pd_size - 1) / pd_size).min(KERNEL_PD_ENTRIES);
+        crate::debug!("identity mapped page tables: {}", needed_pd);
 
         for i_pd in 0..needed_pd {
             // setting up identity paging
@@ -132,57 +250,97 @@ impl MemoryManager {
         Ok(())
     }
 
-    pub fn switch_page_directory(&mut self, new_pd: *mut PageDirectory) {
-        // should call an arm function that change the CR3 with the new pd
-    }
-
-    /// This function will map a virtual_addr to a specific physical_addr
-    /// This mean that when paging is enabled to refer a particular pyshical address we have to
-    /// pass throught this virtual addr
-    pub fn map_addr_without_paging(
-        &mut self,
-        virt_addr: VirtualAddr,
-        physic_addr: PhysicalAddr,
-        pd_flag: u32,
-        pt_flag: u32,
-    ) -> Result<(), &'static str> {
-        // the pointer is not really pointing to the page directory, only if inside the
-        // identity mapping
-        // How manage this if paging is enable?
-
-        // Get the corret PageDirectoryEntry
-        let mut page_table: PageTable;
-
-        // Is this pde valid?
-        if !self.page_directory[virt_addr.get_pd_index()]
-            .is_valid_flag(PageDirectoryFlag::Present as u32)
-        {
-            // if the pde is not present than alloc a new page table
-            // and validate the page directory entry
-            page_table = self.page_directory.alloc_new_page_table(
+    /// Allocate the empty page tables of the kernel heap, MUST be called before creating
+    /// any AddressSpace, so the heap tables are shared like the rest of the kernel space
+    #[cfg_attr(all(test, not(target_os = "none")), allow(dead_code))]
+    pub fn set_up_kernel_heap(&mut self) -> Result<(), &'static str> {
+        let first_pd = KERNEL_HEAP_START / (ENTRIES_PER_PAGE * PAGE_SIZE);
+
+        for i_pd in first_pd..KERNEL_PD_ENTRIES {
+            if self.page_directory[i_pd].is_valid_flag(PageDirectoryFlag::Present as u32) {
+                return Err("Kernel heap overlaps the identity mapping");
+            }
+            self.page_directory.alloc_new_page_table(
                 &mut self.frame_allocator,
-                virt_addr.get_pd_index(),
-                pd_flag,
+                i_pd,
+                PageDirectoryFlag::Present as u32 | PageDirectoryFlag::Writable as u32,
             )?;
-        } else {
-            page_table = self.page_directory[virt_addr.get_pd_index()].get_page_table();
         }
 
-        self.page_directory[virt_addr.get_pd_index()].add_attribute(pd_flag);
-
-        //let pte_index = virt_addr.get_pt_index();
-        let pte = &mut page_table[virt_addr.get_pt_index()];
+        Ok(())
+    }
 
+    /// Map a new frame at virt, it MUST be inside the kernel heap
+    pub fn map_kernel_heap_page(&mut self, virt: &VirtualAddr) -> Result<(), &'static str> {
+        let mut table = self.kernel_heap_table(virt)?;
+        let pte = &mut table[virt.get_pt_index()];
         if pte.is_valid_flag(PageTableFlag::Present as u32) {
-            return Err("Page already present, should be deallocated and managed");
+            return Err("Kernel heap page already mapped");
         }
 
-        pte.add_attribute(pt_flag);
-        pte.set_frame(Frame::from_physical_address(physic_addr));
+        // the heap expects the new memory zeroed (see alloc_zeroed)
+        let frame = self
+            .allocate_zeroed_frame()
+            .ok_or("No frame left for the kernel heap")?;
+        pte.add_attribute(PageTableFlag::Present as u32 | PageTableFlag::Writable as u32);
+        pte.set_frame(frame);
 
+        unsafe { flush_tlb_entry(virt.get()) };
         Ok(())
     }
 
+    /// Give back the frame mapped at virt inside the kernel heap
+    pub fn unmap_kernel_heap_page(&mut self, virt: &VirtualAddr) {
+        if let Ok(mut table) = self.kernel_heap_table(virt) {
+            table.free_page(&mut self.frame_allocator, virt.get_pt_index());
+            unsafe { flush_tlb_entry(virt.get()) };
+        }
+    }
+
+    fn kernel_heap_table(&self, virt: &VirtualAddr) -> Result<PageTable, &'static str> {
+        if virt.get() < KERNEL_HEAP_START || virt.get() >= KERNEL_HEAP_END {
+            return Err("Address outside the kernel heap");
+        }
+
+        let pde = &self.page_directory[virt.get_pd_index()];
+        if !pde.is_valid_flag(PageDirectoryFlag::Present as u32) {
+            return Err("Kernel heap not set up");
+        }
+        Ok(pde.get_page_table())
+    }
+
+    /// Load the page directory in CR3, the kernel space MUST be mapped inside it
+    pub unsafe fn switch_page_directory(&mut self, new_pd: &PageDirectory) {
+        change_page_directory(new_pd.get_physical_addr().get());
+    }
+
+    /// Page directory created at boot, it contains only the kernel space
+    pub fn kernel_page_directory(&self) -> &PageDirectory {
+        &self.page_directory
+    }
+
+    pub fn allocate_frame(&mut self) -> Option<Frame> {
+        self.frame_allocator.allocate()
+    }
+
+    /// Allocate a frame and fill it with zeros
+    pub fn allocate_zeroed_frame(&mut self) -> Option<Frame> {
+        let frame = self.frame_allocator.allocate()?;
+        // frames are always reachable throught the identity mapping
+        unsafe {
+            core::ptr::write_bytes(frame.get_physical_addr().get() as *mut u8, 0, FRAME_SIZE);
+        }
+        Some(frame)
+    }
+
+    pub fn deallocate_frame(&mut self, frame: Frame) {
+        self.frame_allocator.deallocate(frame);
+    }
+
+    pub fn frame_allocator(&mut self) -> &mut FrameAllocator {
+        &mut self.frame_allocator
+    }
+
     // TODO:
     // + map virtual addr to physical addr
     // + flush TLB
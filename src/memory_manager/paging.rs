@@ -133,7 +133,7 @@ impl PageDirectory {
 
         let table = PageTable::new(frame_allocator)?;
 
-        //crate::println!("Allocated page table: {:?}", table.get_physical_addr());
+        crate::trace!("Allocated page table: {}", table.get_physical_addr());
 
         self[index].add_attribute(flags);
         self[index].set_frame(Frame::from_physical_address(table.get_physical_addr()));
@@ -142,14 +142,17 @@ impl PageDirectory {
         Ok(table)
     }
 
-    pub fn free_page_table(&mut self, index: usize) {
-        /*
-        let frame = self.entries[index].get_frame();
+    /// Give back to the allocator the frame of the page table,
+    /// the pages inside it are NOT freed
+    pub fn free_page_table(&mut self, frame_allocator: &mut FrameAllocator, index: usize) {
+        if !self[index].is_valid_flag(PageDirectoryFlag::Present as u32) {
+            return;
+        }
 
-        self.frame_allocator.deallocate(frame);
+        let table = self[index].get_page_table();
+        frame_allocator.deallocate(Frame::from_physical_address(table.get_physical_addr()));
 
-        self.entries[index].del_attribute(PageTableFlag::Present as u32);
-        */
+        self[index].clear();
     }
 }
 
@@ -210,6 +213,10 @@ impl PageDirectoryEntry {
         //Ok(())
     }
 
+    pub fn clear(&mut self) {
+        self.0 = 0;
+    }
+
     pub fn get_page_table(&self) -> PageTable {
         // the address should not be shifted, the flag should do everything
         //unsafe { *((self.0 & (PageTableFlag::Frame as u32)) as *mut PageTable) }
@@ -323,15 +330,16 @@ impl PageTable {
         Ok(())
     }
 
-    /*
-    pub fn free_page(&mut self, index: usize) {
-        let frame = self.entries[index].get_frame();
+    /// Give back to the allocator the frame of the page and clear the entry
+    pub fn free_page(&mut self, frame_allocator: &mut FrameAllocator, index: usize) {
+        if !self[index].is_valid_flag(PageTableFlag::Present as u32) {
+            return;
+        }
 
-        self.frame_allocator.deallocate(frame);
+        frame_allocator.deallocate(self[index].get_frame());
 
-        self.entries[index].del_attribute(PageTableFlag::Present as u32);
+        self[index].clear();
     }
-    */
 }
 
 pub enum PageTableFlag {
@@ -345,6 +353,8 @@ pub enum PageTableFlag {
     Pat = 0x80,          //0000000000000000000000010000000
     CpuGlobal = 0x100,   //0000000000000000000000100000000
     Lv4Global = 0x200,   //0000000000000000000001000000000
+    // available for the os (bits 9-11)
+    CopyOnWrite = 0x400, //0000000000000000000010000000000 shared frame, copy it on write
     Frame = 0x7FFFF000,  //1111111111111111111000000000000
 }
 
@@ -380,6 +390,18 @@ impl PageTableEntry {
         PhysicalAddr::new((self.0 & (PageTableFlag::Frame as u32)) as usize)
     }
 
+    pub fn get_frame(&self) -> Frame {
+        Frame::from_physical_address(self.get_page())
+    }
+
+    pub fn get_flags(&self) -> u32 {
+        self.0 & !(PageTableFlag::Frame as u32)
+    }
+
+    pub fn clear(&mut self) {
+        self.0 = 0;
+    }
+
     pub fn is_valid_flag(&self, attribute: u32) -> bool {
         (self.0 & attribute) == attribute
     }
@@ -404,3 +426,86 @@ impl fmt::Display for PageTableEntry {
         )
     }
 }
+
+// on the host, see test_on_host.sh
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+    use std::boxed::Box;
+
+    #[repr(align(4096))]
+    struct TableBuffer([PageTableEntry; ENTRIES_PER_PAGE]);
+
+    #[test]
+    fn virtual_address_indexes() {
+        let addr = VirtualAddr::new(0x40123456);
+
+        assert_eq!(0x100, addr.get_pd_index());
+        assert_eq!(0x123, addr.get_pt_index());
+        assert_eq!(0x456, addr.get_offset());
+    }
+
+    #[test]
+    fn page_table_entry_flags_and_frame() {
+        let mut entry = PageTableEntry(0);
+        entry.add_attribute(PageTableFlag::Present as u32 | PageTableFlag::Writable as u32);
+        entry.set_frame(Frame::from_frame_number(0x12345));
+
+        assert!(entry.is_valid_flag(PageTableFlag::Present as u32));
+        assert!(!entry.is_valid_flag(PageTableFlag::User as u32));
+        assert_eq!(0x12345000, entry.get_page().get());
+        assert_eq!(Frame::from_frame_number(0x12345), entry.get_frame());
+        assert_eq!(
+            PageTableFlag::Present as u32 | PageTableFlag::Writable as u32,
+            entry.get_flags()
+        );
+
+        entry.add_attribute(PageTableFlag::CopyOnWrite as u32);
+        entry.del_attribute(PageTableFlag::Writable as u32);
+        assert!(entry.is_valid_flag(PageTableFlag::CopyOnWrite as u32));
+        assert!(!entry.is_valid_flag(PageTableFlag::Writable as u32));
+        // the flags never touch the frame
+        assert_eq!(0x12345000, entry.get_page().get());
+
+        entry.clear();
+        assert_eq!(0, entry.get_value());
+    }
+
+    #[test]
+    fn page_directory_entry_points_to_its_table() {
+        let mut entry = PageDirectoryEntry(0);
+        entry.add_attribute(PageDirectoryFlag::Present as u32 | PageDirectoryFlag::User as u32);
+        entry.set_frame(Frame::from_frame_number(0x400));
+
+        assert!(entry.is_valid_flag(PageDirectoryFlag::User as u32));
+        assert_eq!(0x400000, entry.get_page_table().get_physical_addr().get());
+
+        entry.del_attribute(PageDirectoryFlag::Present as u32);
+        assert!(!entry.is_valid_flag(PageDirectoryFlag::Present as u32));
+        assert_eq!(0x400000, entry.get_page_table().get_physical_addr().get());
+    }
+
+    #[test]
+    fn page_table_alloc_and_free_page() {
+        // only the table is touched, the frames given to it are just numbers
+        let mut buffer = Box::new(TableBuffer([PageTableEntry(0); ENTRIES_PER_PAGE]));
+        let mut table =
+            PageTable::from_physical_address(PhysicalAddr::new(&mut buffer.0 as *mut _ as usize));
+        let mut frame_allocator = FrameAllocator::with_total_memory(64 * FRAME_SIZE, 0);
+        let flags = PageTableFlag::Present as u32 | PageTableFlag::Writable as u32;
+
+        table
+            .alloc_new_page(&mut frame_allocator, 3, flags)
+            .unwrap();
+        assert!(table[3].is_valid_flag(flags));
+        assert_eq!(1, frame_allocator.reference_count(&table[3].get_frame()));
+        assert!(table
+            .alloc_new_page(&mut frame_allocator, 3, flags)
+            .is_err());
+
+        let frame = table[3].get_frame();
+        table.free_page(&mut frame_allocator, 3);
+        assert_eq!(0, table[3].get_value());
+        assert_eq!(0, frame_allocator.reference_count(&frame));
+    }
+}
@@ -0,0 +1,405 @@
+use super::{frame_allocator::Frame, paging::PhysicalAddr, FRAME_SIZE};
+use crate::concurrency::spin_mutex::SpinMutex;
+use crate::interrupts::without_interrupts;
+use core::{
+    marker::PhantomData,
+    mem::{align_of, size_of},
+    ops::{Deref, DerefMut},
+    ptr::{null_mut, NonNull},
+};
+
+// completely free slabs kept by a cache, the others are given back to the frame allocator
+const MAX_EMPTY_SLABS: usize = 1;
+
+/// Where the slabs get their frames (by address, reached with the identity mapping),
+/// the memory manager inside the kernel
+#[derive(Debug, Clone, Copy)]
+pub struct SlabFrames {
+    pub allocate: fn() -> Option<usize>,
+    pub deallocate: fn(usize),
+}
+
+pub const KERNEL_FRAMES: SlabFrames = SlabFrames {
+    allocate: || super::allocate_kernel_frame().map(|frame| frame.get_physical_addr().get()),
+    deallocate: |addr| {
+        super::deallocate_kernel_frame(Frame::from_physical_address(PhysicalAddr::new(addr)))
+    },
+};
+
+/// Cache of objects with the same size, every slab is a frame carved into slots
+///
+/// |SlabHeader|slot|slot|slot|...|
+///
+/// The free slots of a slab are a linked list written inside the slots themselves,
+/// the slab of an object is found aligning down its address. Only the slabs with at
+/// least one free slot are linked in the cache, a full slab is found again when one
+/// of its objects is freed
+///
+/// The frames are reached with the identity mapping, so nothing can be allocated
+/// before paging is enabled
+pub struct RawSlabCache {
+    // distance between two slots, a multiple of align
+    object_size: usize,
+    align: usize,
+    // slabs with at least one free slot
+    partial: *mut SlabHeader,
+    empty_slabs: usize,
+    frames: SlabFrames,
+    stats: SlabStats,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SlabStats {
+    pub object_size: usize,
+    pub objects_per_slab: usize,
+    pub slabs: usize,
+    pub objects_in_use: usize,
+    pub allocations: usize,
+    pub frees: usize,
+    // allocations failed because no frame was available (like before paging)
+    pub failures: usize,
+}
+
+struct SlabHeader {
+    next: *mut SlabHeader,
+    prev: *mut SlabHeader,
+    free: *mut FreeSlot,
+    in_use: usize,
+}
+
+struct FreeSlot {
+    next: *mut FreeSlot,
+}
+
+impl RawSlabCache {
+    /// Every object will be at least size bytes aligned to align (a power of two)
+    pub const fn new(size: usize, align: usize) -> Self {
+        Self::with_frames(size, align, KERNEL_FRAMES)
+    }
+
+    /// Like new, with the frames taken from frames
+    pub const fn with_frames(size: usize, align: usize, frames: SlabFrames) -> Self {
+        // a free slot must contain the link to the next one
+        let size = if size < size_of::<FreeSlot>() {
+            size_of::<FreeSlot>()
+        } else {
+            size
+        };
+        let align = if align < align_of::<FreeSlot>() {
+            align_of::<FreeSlot>()
+        } else {
+            align
+        };
+        let object_size = (size + align - 1) & !(align - 1);
+
+        let first_slot = (size_of::<SlabHeader>() + align - 1) & !(align - 1);
+        let objects_per_slab = if first_slot + object_size <= FRAME_SIZE {
+            (FRAME_SIZE - first_slot) / object_size
+        } else {
+            0
+        };
+
+        Self {
+            object_size,
+            align,
+            partial: null_mut(),
+            empty_slabs: 0,
+            frames,
+            stats: SlabStats {
+                object_size,
+                objects_per_slab,
+                slabs: 0,
+                objects_in_use: 0,
+                allocations: 0,
+                frees: 0,
+                failures: 0,
+            },
+        }
+    }
+
+    pub fn stats(&self) -> SlabStats {
+        self.stats
+    }
+
+    /// Null if there is no free slot and a new slab can't be allocated
+    pub fn alloc(&mut self) -> *mut u8 {
+        if self.partial.is_null() && self.grow().is_err() {
+            self.stats.failures += 1;
+            return null_mut();
+        }
+
+        unsafe {
+            let slab = &mut *self.partial;
+            let slot = slab.free;
+            slab.free = (*slot).next;
+
+            if slab.in_use == 0 {
+                self.empty_slabs -= 1;
+            }
+            slab.in_use += 1;
+
+            // full, it will be linked again by free
+            if slab.free.is_null() {
+                self.unlink(slab);
+            }
+
+            self.stats.allocations += 1;
+            self.stats.objects_in_use += 1;
+            slot as *mut u8
+        }
+    }
+
+    /// ptr MUST be returned by alloc of this cache
+    pub unsafe fn free(&mut self, ptr: *mut u8) {
+        let slab = (ptr as usize & !(FRAME_SIZE - 1)) as *mut SlabHeader;
+        let was_full = (*slab).free.is_null();
+
+        let slot = ptr as *mut FreeSlot;
+        (*slot).next = (*slab).free;
+        (*slab).free = slot;
+        (*slab).in_use -= 1;
+
+        if was_full {
+            self.link(slab);
+        }
+
+        self.stats.frees += 1;
+        self.stats.objects_in_use -= 1;
+
+        if (*slab).in_use == 0 {
+            if self.empty_slabs < MAX_EMPTY_SLABS {
+                self.empty_slabs += 1;
+            } else {
+                self.unlink(slab);
+                (self.frames.deallocate)(slab as usize);
+                self.stats.slabs -= 1;
+            }
+        }
+    }
+
+    /// Carve a new frame into slots
+    fn grow(&mut self) -> Result<(), &'static str> {
+        if self.stats.objects_per_slab == 0 {
+            return Err("Object too big for a slab");
+        }
+
+        let start = (self.frames.allocate)().ok_or("No frame for a new slab")?;
+        let first_slot = (start + size_of::<SlabHeader>() + self.align - 1) & !(self.align - 1);
+
+        unsafe {
+            // the lowest slot is the first one given
+            let mut free = null_mut();
+            for i in (0..self.stats.objects_per_slab).rev() {
+                let slot = (first_slot + i * self.object_size) as *mut FreeSlot;
+                (*slot).next = free;
+                free = slot;
+            }
+
+            let slab = start as *mut SlabHeader;
+            slab.write(SlabHeader {
+                next: null_mut(),
+                prev: null_mut(),
+                free,
+                in_use: 0,
+            });
+            self.link(slab);
+        }
+
+        self.empty_slabs += 1;
+        self.stats.slabs += 1;
+        Ok(())
+    }
+
+    unsafe fn link(&mut self, slab: *mut SlabHeader) {
+        (*slab).prev = null_mut();
+        (*slab).next = self.partial;
+        if !self.partial.is_null() {
+            (*self.partial).prev = slab;
+        }
+        self.partial = slab;
+    }
+
+    unsafe fn unlink(&mut self, slab: *mut SlabHeader) {
+        if !(*slab).next.is_null() {
+            (*(*slab).next).prev = (*slab).prev;
+        }
+        if (*slab).prev.is_null() {
+            self.partial = (*slab).next;
+        } else {
+            (*(*slab).prev).next = (*slab).next;
+        }
+    }
+}
+
+/// Typed SlabCache, for the kernel objects allocated a lot of times (tasks, page
+/// table bookkeeping, driver buffers ...). It is not locked, wrap it in a SpinMutex
+pub struct SlabCache<T> {
+    raw: RawSlabCache,
+    _marker: PhantomData<T>,
+}
+
+impl<T> SlabCache<T> {
+    pub const fn new() -> Self {
+        Self {
+            raw: RawSlabCache::new(size_of::<T>(), align_of::<T>()),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Move value inside a slot, it is dropped if there is no space
+    pub fn alloc(&mut self, value: T) -> Result<NonNull<T>, &'static str> {
+        let ptr = self.raw.alloc() as *mut T;
+        let ptr = NonNull::new(ptr).ok_or("No space left in the slab cache")?;
+        unsafe { ptr.as_ptr().write(value) };
+        Ok(ptr)
+    }
+
+    /// Move the object out and free its slot, ptr MUST be returned by alloc of this cache
+    pub unsafe fn take(&mut self, ptr: NonNull<T>) -> T {
+        let value = ptr.as_ptr().read();
+        self.raw.free(ptr.as_ptr() as *mut u8);
+        value
+    }
+}
+
+/// Like a Box, but the object is inside a slot of cache
+pub struct SlabBox<T: 'static> {
+    ptr: NonNull<T>,
+    cache: &'static SpinMutex<SlabCache<T>>,
+}
+
+impl<T> SlabBox<T> {
+    /// Err if there is no slot and no frame for a new slab, value is dropped
+    pub fn new(value: T, cache: &'static SpinMutex<SlabCache<T>>) -> Result<Self, &'static str> {
+        let ptr = without_interrupts(|| cache.lock().alloc(value))?;
+        Ok(Self { ptr, cache })
+    }
+}
+
+impl<T> Deref for SlabBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T> DerefMut for SlabBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T> Drop for SlabBox<T> {
+    fn drop(&mut self) {
+        let value = without_interrupts(|| unsafe { self.cache.lock().take(self.ptr) });
+        // outside the lock, the drop of value could need the cache again
+        drop(value);
+    }
+}
+
+// on the host, see test_on_host.sh
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::super::heap_allocator::SLAB_SIZE_CLASSES;
+    use super::super::test_support::{free_host_frames, host_frames_in_use, HOST_FRAMES};
+    use super::*;
+    use std::vec::Vec;
+
+    // the cache and the frames still owned by it
+    struct TestCache(RawSlabCache);
+
+    impl TestCache {
+        fn new(size: usize, align: usize) -> Self {
+            Self(RawSlabCache::with_frames(size, align, HOST_FRAMES))
+        }
+
+        fn alloc(&mut self) -> usize {
+            let ptr = self.0.alloc();
+            assert!(!ptr.is_null());
+            ptr as usize
+        }
+
+        fn free(&mut self, ptr: usize) {
+            unsafe { self.0.free(ptr as *mut u8) };
+        }
+
+        fn frames(&self) -> usize {
+            host_frames_in_use()
+        }
+    }
+
+    impl Drop for TestCache {
+        fn drop(&mut self) {
+            free_host_frames();
+        }
+    }
+
+    #[test]
+    fn full_slab_is_linked_again_by_free() {
+        let mut cache = TestCache::new(64, 64);
+        let objects: Vec<usize> = (0..cache.0.stats().objects_per_slab)
+            .map(|_| cache.alloc())
+            .collect();
+        assert!(cache.0.partial.is_null());
+        assert_eq!(1, cache.frames());
+
+        cache.free(objects[3]);
+        assert!(!cache.0.partial.is_null());
+
+        // the slot comes from the same slab, no new frame
+        assert_eq!(objects[3], cache.alloc());
+        assert!(cache.0.partial.is_null());
+        assert_eq!(1, cache.frames());
+    }
+
+    #[test]
+    fn empty_slabs_over_the_max_are_released() {
+        let mut cache = TestCache::new(128, 8);
+        let slabs = MAX_EMPTY_SLABS + 2;
+        let objects: Vec<usize> = (0..cache.0.stats().objects_per_slab * slabs)
+            .map(|_| cache.alloc())
+            .collect();
+        assert_eq!(slabs, cache.0.stats().slabs);
+        assert_eq!(slabs, cache.frames());
+
+        for object in objects {
+            cache.free(object);
+        }
+        assert_eq!(0, cache.0.stats().objects_in_use);
+        assert_eq!(MAX_EMPTY_SLABS, cache.0.stats().slabs);
+        assert_eq!(MAX_EMPTY_SLABS, cache.frames());
+
+        // the kept slab is used again
+        cache.alloc();
+        assert_eq!(MAX_EMPTY_SLABS, cache.frames());
+    }
+
+    #[test]
+    fn every_size_class_is_aligned() {
+        for class in SLAB_SIZE_CLASSES {
+            let mut cache = TestCache::new(class, class);
+            // more than one slab
+            let mut objects: Vec<usize> = (0..cache.0.stats().objects_per_slab + 1)
+                .map(|_| cache.alloc())
+                .collect();
+            assert_eq!(2, cache.frames());
+
+            objects.sort();
+            for object in objects.iter() {
+                assert_eq!(0, object % class, "class {}", class);
+                // after the header, inside the frame
+                let offset = object % FRAME_SIZE;
+                assert!(offset >= size_of::<SlabHeader>(), "class {}", class);
+                assert!(offset + class <= FRAME_SIZE, "class {}", class);
+            }
+            for pair in objects.windows(2) {
+                assert!(pair[0] + class <= pair[1], "class {}", class);
+            }
+
+            for object in objects {
+                cache.free(object);
+            }
+        }
+    }
+}
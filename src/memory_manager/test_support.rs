@@ -0,0 +1,149 @@
+use super::heap_allocator::HeapRegion;
+use super::slab::SlabFrames;
+use super::PAGE_SIZE;
+use core::alloc::Layout;
+use core::cell::RefCell;
+use std::alloc::{alloc_zeroed, dealloc};
+use std::vec::Vec;
+
+// Fixtures shared by the host tests of the memory manager, see test_on_host.sh
+
+/// xorshift, the tests must not depend on anything outside std
+pub struct Random(pub u64);
+
+impl Random {
+    pub fn next(&mut self) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 as usize
+    }
+
+    pub fn below(&mut self, max: usize) -> usize {
+        self.next() % max
+    }
+}
+
+/// Zeroed and page aligned buffer of the host
+pub struct TestBuffer {
+    ptr: *mut u8,
+    size: usize,
+}
+
+impl TestBuffer {
+    pub fn new(size: usize) -> Self {
+        let ptr = unsafe { alloc_zeroed(Self::layout(size)) };
+        assert!(!ptr.is_null());
+        Self { ptr, size }
+    }
+
+    fn layout(size: usize) -> Layout {
+        Layout::from_size_align(size, PAGE_SIZE).unwrap()
+    }
+
+    pub fn start(&self) -> usize {
+        self.ptr as usize
+    }
+
+    pub fn end(&self) -> usize {
+        self.ptr as usize + self.size
+    }
+
+    pub fn contains(&self, ptr: *mut u8) -> bool {
+        (self.start()..self.end()).contains(&(ptr as usize))
+    }
+}
+
+impl Drop for TestBuffer {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.ptr, Self::layout(self.size)) };
+    }
+}
+
+/// A region allocator over its own buffer
+pub struct TestHeap<R: HeapRegion> {
+    pub heap: R,
+    pub buffer: TestBuffer,
+}
+
+impl<R: HeapRegion> TestHeap<R> {
+    pub fn new(size: usize) -> Self {
+        let buffer = TestBuffer::new(size);
+        Self {
+            heap: unsafe { R::new(buffer.start(), buffer.end()) },
+            buffer,
+        }
+    }
+
+    pub fn start(&self) -> usize {
+        self.buffer.start()
+    }
+
+    pub fn end(&self) -> usize {
+        self.buffer.end()
+    }
+
+    pub fn alloc(&self, size: usize) -> *mut u8 {
+        let ptr = unsafe { self.heap.alloc(layout(size)) };
+        assert!(!ptr.is_null());
+        ptr
+    }
+
+    /// The memory is checked to be zeroed
+    pub fn alloc_zeroed(&self, size: usize) -> *mut u8 {
+        let ptr = unsafe { self.heap.alloc_zeroed(layout(size)) };
+        assert!(!ptr.is_null());
+        let data = unsafe { core::slice::from_raw_parts(ptr, size) };
+        assert!(data.iter().all(|byte| *byte == 0));
+        ptr
+    }
+
+    pub fn dealloc(&self, ptr: *mut u8, size: usize) {
+        unsafe { self.heap.dealloc(ptr, layout(size)) };
+    }
+}
+
+/// Layout of the allocations of TestHeap
+pub fn layout(size: usize) -> Layout {
+    Layout::from_size_align(size, 8).unwrap()
+}
+
+std::thread_local! {
+    // every test runs in its own thread
+    static HOST_FRAME_LIST: RefCell<Vec<usize>> = RefCell::new(Vec::new());
+}
+
+fn frame_layout() -> Layout {
+    Layout::from_size_align(PAGE_SIZE, PAGE_SIZE).unwrap()
+}
+
+/// Frames for the slabs allocated on the host, they are counted by thread
+pub const HOST_FRAMES: SlabFrames = SlabFrames {
+    allocate: || {
+        let frame = unsafe { alloc_zeroed(frame_layout()) } as usize;
+        HOST_FRAME_LIST.with(|frames| frames.borrow_mut().push(frame));
+        Some(frame)
+    },
+    deallocate: |frame| {
+        HOST_FRAME_LIST.with(|frames| {
+            let mut frames = frames.borrow_mut();
+            let i = frames.iter().position(|other| *other == frame);
+            frames.swap_remove(i.expect("Frame not allocated with HOST_FRAMES"));
+        });
+        unsafe { dealloc(frame as *mut u8, frame_layout()) };
+    },
+};
+
+/// HOST_FRAMES allocated and not deallocated by this thread
+pub fn host_frames_in_use() -> usize {
+    HOST_FRAME_LIST.with(|frames| frames.borrow().len())
+}
+
+/// Give back every frame still in use, the slabs that own them MUST not be used again
+pub fn free_host_frames() {
+    HOST_FRAME_LIST.with(|frames| {
+        for frame in frames.borrow_mut().drain(..) {
+            unsafe { dealloc(frame as *mut u8, frame_layout()) };
+        }
+    });
+}
@@ -0,0 +1,151 @@
+use super::PAGE_SIZE;
+use alloc::{collections::BTreeMap, vec::Vec};
+
+/// Virtual Memory Area, a range of the user space reserved for the process
+///
+/// The pages inside are allocated only on the first access (page fault),
+/// start and end are page aligned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Vma {
+    pub start: usize,
+    // first address after the area
+    pub end: usize,
+    // PageTableFlag used to map the pages
+    pub flags: u32,
+}
+
+impl Vma {
+    pub fn contains(&self, addr: usize) -> bool {
+        self.start <= addr && addr < self.end
+    }
+
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+}
+
+/// All the areas of an address space, never overlapping and ordered by start
+#[derive(Debug, Clone, Default)]
+pub struct VmaList {
+    areas: BTreeMap<usize, Vma>,
+}
+
+impl VmaList {
+    pub const fn new() -> Self {
+        Self {
+            areas: BTreeMap::new(),
+        }
+    }
+
+    /// Area that contains addr
+    pub fn find(&self, addr: usize) -> Option<&Vma> {
+        self.areas
+            .range(..=addr)
+            .next_back()
+            .map(|(_, vma)| vma)
+            .filter(|vma| vma.contains(addr))
+    }
+
+    /// true if no area intersects [start, end)
+    pub fn is_free(&self, start: usize, end: usize) -> bool {
+        // the only candidates are the last one starting before end
+        match self.areas.range(..end).next_back() {
+            Some((_, vma)) => vma.end <= start,
+            None => true,
+        }
+    }
+
+    /// Add [start, end), it MUST not intersect other areas.
+    /// Touching areas with the same flags are merged
+    pub fn insert(&mut self, start: usize, end: usize, flags: u32) -> Result<(), &'static str> {
+        if start % PAGE_SIZE != 0 || end % PAGE_SIZE != 0 {
+            return Err("Area not page aligned");
+        }
+        if start >= end {
+            return Err("Empty area");
+        }
+        if !self.is_free(start, end) {
+            return Err("Area overlaps another one");
+        }
+
+        let mut vma = Vma { start, end, flags };
+
+        let previous = self
+            .areas
+            .range(..start)
+            .next_back()
+            .map(|(_, previous)| *previous)
+            .filter(|previous| previous.end == start && previous.flags == flags);
+        if let Some(previous) = previous {
+            self.areas.remove(&previous.start);
+            vma.start = previous.start;
+        }
+
+        let next = self
+            .areas
+            .get(&end)
+            .copied()
+            .filter(|next| next.flags == flags);
+        if let Some(next) = next {
+            self.areas.remove(&next.start);
+            vma.end = next.end;
+        }
+
+        self.areas.insert(vma.start, vma);
+        Ok(())
+    }
+
+    /// Remove [start, end) from every area, splitting them if needed.
+    /// Return the removed pieces
+    pub fn remove(&mut self, start: usize, end: usize) -> Vec<Vma> {
+        let touched: Vec<Vma> = self
+            .areas
+            .range(..end)
+            .map(|(_, vma)| *vma)
+            .filter(|vma| vma.end > start)
+            .collect();
+
+        let mut removed = Vec::with_capacity(touched.len());
+        for vma in touched {
+            self.areas.remove(&vma.start);
+
+            if vma.start < start {
+                self.areas.insert(vma.start, Vma { end: start, ..vma });
+            }
+            if vma.end > end {
+                self.areas.insert(end, Vma { start: end, ..vma });
+            }
+
+            removed.push(Vma {
+                start: vma.start.max(start),
+                end: vma.end.min(end),
+                flags: vma.flags,
+            });
+        }
+        removed
+    }
+
+    /// Highest free range of len bytes inside [min, max), return its start
+    pub fn find_free(&self, len: usize, min: usize, max: usize) -> Option<usize> {
+        let mut end = max;
+        for (_, vma) in self.areas.range(..max).rev() {
+            if vma.end <= min {
+                break;
+            }
+            if vma.end < end && end - vma.end >= len {
+                return Some(end - len);
+            }
+            end = end.min(vma.start);
+        }
+
+        if end >= min && end - min >= len {
+            Some(end - len)
+        } else {
+            None
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Vma> {
+        self.areas.values()
+    }
+}
@@ -1,3 +1,6 @@
+use crate::elf::SectionHeader;
+use core::mem::size_of;
+
 #[derive(Debug)]
 pub enum Syms {
     Symbols {
@@ -77,7 +80,7 @@ impl BootInfo {
                     addr: *address.offset(9) as *const usize,
                     shndx: *address.offset(10)
                 });
-            } // TODO understand why is always null
+            } // qemu -kernel never sets the flag 5, GRUB does
 
             let mmap = check_flag_and_set!(6, 
                 MemoryMap {
@@ -100,6 +103,33 @@ impl BootInfo {
         }
     }
 
+    /// Iterate over the modules, empty if the flag 3 is not set
+    pub fn modules(&self) -> ModuleIterator {
+        match (self.mods_count, self.mods_address) {
+            (Some(count), Some(address)) => ModuleIterator {
+                current: address as *const ModuleElement,
+                remaining: count,
+            },
+            _ => ModuleIterator {
+                current: core::ptr::null(),
+                remaining: 0,
+            },
+        }
+    }
+
+    /// Section headers of the kernel, empty if the flag 5 is not set
+    pub fn elf_sections(&self) -> &'static [SectionHeader] {
+        match self.syms {
+            Some(Syms::Elfs { num, size, addr, .. }) if size == size_of::<SectionHeader>() => {
+                if addr.is_null() {
+                    return &[];
+                }
+                unsafe { core::slice::from_raw_parts(addr as *const SectionHeader, num) }
+            }
+            _ => &[],
+        }
+    }
+
     /*
     pub fn get_flag(&self) -> usize {
         unsafe{ *self.address }
@@ -223,3 +253,76 @@ impl Iterator for MemoryMapIterator {
 
     }
 }
+
+/// Module loaded by the bootloader (GRUB module or qemu -initrd),
+/// start.s ask for page aligned modules
+#[derive(Debug, Clone, Copy)]
+pub struct Module {
+    pub start: usize,
+    // first byte after the module
+    pub end: usize,
+    pub cmd_line: &'static str,
+}
+
+impl Module {
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    /// The module is in the kernel space (identity mapped) and never freed
+    pub fn as_slice(&self) -> &'static [u8] {
+        unsafe { core::slice::from_raw_parts(self.start as *const u8, self.len()) }
+    }
+}
+
+// layout of every element of the mods list
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct ModuleElement {
+    mod_start: usize,
+    mod_end: usize,
+    string: usize,
+    reserved: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ModuleIterator {
+    current: *const ModuleElement,
+    remaining: usize,
+}
+
+impl Iterator for ModuleIterator {
+    type Item = Module;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+
+        unsafe {
+            let elem = *self.current;
+            self.current = self.current.add(1);
+            self.remaining -= 1;
+
+            Some(Module {
+                start: elem.mod_start,
+                end: elem.mod_end,
+                cmd_line: c_str(elem.string as *const u8),
+            })
+        }
+    }
+}
+
+/// Null terminated string, invalid utf8 become an empty string
+unsafe fn c_str(ptr: *const u8) -> &'static str {
+    if ptr.is_null() {
+        return "";
+    }
+
+    let mut len = 0;
+    while *ptr.add(len) != 0 {
+        len += 1;
+    }
+
+    core::str::from_utf8(core::slice::from_raw_parts(ptr, len)).unwrap_or("")
+}
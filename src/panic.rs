@@ -0,0 +1,142 @@
+use crate::stack_frames::StackFrames;
+use crate::symbols::Symbolized;
+use core::arch::asm;
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+// Kernel panic
+//
+// Interrupts are disabled, the panic is printed with the registers and the return
+// addresses (see stack_frames), then the cpu is halted
+
+// a panic while panicking (inside the printing) only halts
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+/// Registers read at the beginning of the panic handler
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Registers {
+    pub eax: u32,
+    pub ebx: u32,
+    pub ecx: u32,
+    pub edx: u32,
+    pub esi: u32,
+    pub edi: u32,
+    pub ebp: u32,
+    pub esp: u32,
+    pub eflags: u32,
+    pub cr0: u32,
+    pub cr2: u32,
+    pub cr3: u32,
+}
+
+impl Registers {
+    #[inline(always)]
+    pub fn read() -> Self {
+        let mut regs = Self::default();
+        unsafe {
+            asm!(
+                "mov {}, eax",
+                "mov {}, ebx",
+                "mov {}, ecx",
+                "mov {}, edx",
+                out(reg) regs.eax,
+                out(reg) regs.ebx,
+                out(reg) regs.ecx,
+                out(reg) regs.edx,
+                options(nomem, nostack, preserves_flags)
+            );
+            asm!(
+                "mov {}, esi",
+                "mov {}, edi",
+                "mov {}, ebp",
+                "mov {}, esp",
+                out(reg) regs.esi,
+                out(reg) regs.edi,
+                out(reg) regs.ebp,
+                out(reg) regs.esp,
+                options(nomem, nostack, preserves_flags)
+            );
+            asm!("pushfd", "pop {}", out(reg) regs.eflags, options(nomem, preserves_flags));
+            asm!(
+                "mov {}, cr0",
+                "mov {}, cr2",
+                "mov {}, cr3",
+                out(reg) regs.cr0,
+                out(reg) regs.cr2,
+                out(reg) regs.cr3,
+                options(nomem, nostack, preserves_flags)
+            );
+        }
+        regs
+    }
+}
+
+impl fmt::Display for Registers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "eax: 0x{:08X} ebx: 0x{:08X} ecx: 0x{:08X} edx: 0x{:08X}",
+            self.eax, self.ebx, self.ecx, self.edx
+        )?;
+        writeln!(
+            f,
+            "esi: 0x{:08X} edi: 0x{:08X} ebp: 0x{:08X} esp: 0x{:08X}",
+            self.esi, self.edi, self.ebp, self.esp
+        )?;
+        write!(
+            f,
+            "eflags: 0x{:08X} cr0: 0x{:08X} cr2: 0x{:08X} cr3: 0x{:08X}",
+            self.eflags, self.cr0, self.cr2, self.cr3
+        )
+    }
+}
+
+/// Print the return addresses starting from the frame ebp, with the function when known
+pub fn print_backtrace(out: &mut dyn Write, ebp: usize) -> fmt::Result {
+    writeln!(out, "Backtrace:")?;
+    for (i, return_addr) in unsafe { StackFrames::from_ebp(ebp) }.enumerate() {
+        writeln!(out, "  {:2}: {}", i, Symbolized(return_addr))?;
+    }
+    Ok(())
+}
+
+/// Stop the cpu forever, only an NMI can wake it up and it halts again
+pub fn halt() -> ! {
+    loop {
+        unsafe { asm!("cli", "hlt", options(nomem, nostack)) };
+    }
+}
+
+// Screen and COM1 without waiting for their locks: the cpu is not going to
+// run anything else, the owner of a lock (maybe the code that panicked) never releases it
+struct PanicConsole;
+
+impl Write for PanicConsole {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        unsafe {
+            if let Some(writer) = crate::vga_buffer::WRITER.get() {
+                writer.force_unlock();
+                writer.lock().write_string(s);
+            }
+            crate::serial::SERIAL1.force_unlock();
+            crate::serial::SERIAL1.lock().write_str(s)
+        }
+    }
+}
+
+pub fn kernel_panic(info: &PanicInfo) -> ! {
+    let regs = Registers::read();
+    unsafe { asm!("cli", options(nomem, nostack)) };
+
+    if PANICKING.swap(true, Ordering::Relaxed) {
+        halt();
+    }
+
+    let mut console = PanicConsole;
+    let _ = writeln!(console, "\nKERNEL PANIC: {}", info);
+    let _ = writeln!(console, "{}", regs);
+    let _ = print_backtrace(&mut console, regs.ebp as usize);
+
+    halt();
+}
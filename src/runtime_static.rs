@@ -12,7 +12,7 @@ macro_rules! lazy_static {
             fn deref(&self) -> &Self::Target {
 
                 fn __static_fn() -> $type { $value }
-                
+
                 static $name : FnOnce() -> $type = __static_fn();
 
 
@@ -27,16 +27,26 @@ macro_rules! lazy_static {
 */
 
 use core::{
-    cell::UnsafeCell,
+    cell::{Cell, UnsafeCell},
     mem::MaybeUninit,
-    sync::atomic::{ AtomicBool, Ordering }
+    sync::atomic::{AtomicU8, Ordering},
 };
 
+// States shared by RuntimeStatic and Once
+//
+// UNINIT -> RUNNING -> COMPLETE
+//
+// RUNNING is held only while the value is being written, everyone else
+// that wants to read has to spin until COMPLETE is reached
+const UNINIT: u8 = 0;
+const RUNNING: u8 = 1;
+const COMPLETE: u8 = 2;
+
 // Use this to allow the creation of something static but
 // depends on runtime
-pub struct RuntimeStatic<T> {  
-     init: AtomicBool,
-     data: UnsafeCell<MaybeUninit<T>>
+pub struct RuntimeStatic<T> {
+    state: AtomicU8,
+    data: UnsafeCell<MaybeUninit<T>>,
 }
 
 unsafe impl<T> Sync for RuntimeStatic<T> {}
@@ -44,46 +54,173 @@ unsafe impl<T> Sync for RuntimeStatic<T> {}
 impl<T> RuntimeStatic<T> {
     pub const fn get_uninit() -> Self {
         Self {
-            init: AtomicBool::new(false), // false
-            data: UnsafeCell::new(MaybeUninit::uninit())
+            state: AtomicU8::new(UNINIT),
+            data: UnsafeCell::new(MaybeUninit::uninit()),
         }
     }
 
     pub fn init(&self, data: T) {
-        if self.init.swap(true, Ordering::Relaxed) {
+        if self
+            .state
+            .compare_exchange(UNINIT, RUNNING, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
             panic!("RuntimeStatic already init");
         }
 
-        let data_container = unsafe{ &mut *self.data.get() };
+        let data_container = unsafe { &mut *self.data.get() };
         data_container.write(data);
+
+        // Release: the write of the data must be visible before the state
+        self.state.store(COMPLETE, Ordering::Release);
+    }
+
+    /// Return None if init was not called yet (or is still running)
+    pub fn get(&self) -> Option<&T> {
+        if self.state.load(Ordering::Acquire) != COMPLETE {
+            return None;
+        }
+
+        unsafe { Some((*self.data.get()).assume_init_ref()) }
+    }
+
+    pub fn is_init(&self) -> bool {
+        self.state.load(Ordering::Acquire) == COMPLETE
     }
 }
 
 impl<T> core::ops::Deref for RuntimeStatic<T> {
     type Target = T;
 
-    fn deref(&self) -> &Self::Target{
-        if !self.init.load(Ordering::Relaxed) {
-            panic!("Impossible dereference ad Unint data");
+    fn deref(&self) -> &Self::Target {
+        match self.get() {
+            Some(data) => data,
+            None => panic!("Impossible dereference ad Unint data"),
         }
+    }
+}
 
-        unsafe {
-            let data_container = &mut *self.data.get();
-            data_container.assume_init_ref()
+impl<T> core::ops::DerefMut for RuntimeStatic<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        // &mut self means no one else can be in the middle of an init
+        if *self.state.get_mut() != COMPLETE {
+            panic!("Impossible dereference ad Unint data");
         }
+
+        unsafe { self.data.get_mut().assume_init_mut() }
     }
 }
 
-impl<T> core::ops::DerefMut for RuntimeStatic<T> {
+/// Cell that can be written only once, the first one that call
+/// call_once will run the initializer, every other caller will spin until the value is ready
+///
+/// There is a single cpu: an interrupt handler that calls call_once while the code it
+/// interrupted is running the initializer spins forever. A Once used also by an
+/// interrupt handler must be initialized with the interrupts disabled
+pub struct Once<T> {
+    state: AtomicU8,
+    data: UnsafeCell<MaybeUninit<T>>,
+}
 
-    fn deref_mut(&mut self) -> &mut Self::Target{
-        if !self.init.load(Ordering::Relaxed) {
-            panic!("Impossible dereference ad Unint data");
+unsafe impl<T: Send + Sync> Sync for Once<T> {}
+unsafe impl<T: Send> Send for Once<T> {}
+
+impl<T> Once<T> {
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU8::new(UNINIT),
+            data: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    /// Run init only if no one else did it before and return the stored value
+    ///
+    /// If init panics the Once stay in RUNNING forever, but in the kernel a panic
+    /// never returns so there is no other caller
+    pub fn call_once<F: FnOnce() -> T>(&self, init: F) -> &T {
+        match self
+            .state
+            .compare_exchange(UNINIT, RUNNING, Ordering::Acquire, Ordering::Acquire)
+        {
+            Ok(_) => {
+                unsafe { (*self.data.get()).write(init()) };
+                self.state.store(COMPLETE, Ordering::Release);
+            }
+            Err(COMPLETE) => (),
+            Err(_) => self.wait(),
+        }
+
+        unsafe { (*self.data.get()).assume_init_ref() }
+    }
+
+    /// Return None if the value is not yet initialized, never spin
+    pub fn get(&self) -> Option<&T> {
+        if self.state.load(Ordering::Acquire) != COMPLETE {
+            return None;
+        }
+
+        unsafe { Some((*self.data.get()).assume_init_ref()) }
+    }
+
+    pub fn is_completed(&self) -> bool {
+        self.state.load(Ordering::Acquire) == COMPLETE
+    }
+
+    fn wait(&self) {
+        while self.state.load(Ordering::Acquire) != COMPLETE {
+            core::hint::spin_loop();
         }
+    }
+}
 
-        unsafe {
-            let data_container = &mut *self.data.get();
-            data_container.assume_init_mut()
+impl<T> Drop for Once<T> {
+    fn drop(&mut self) {
+        if *self.state.get_mut() == COMPLETE {
+            unsafe { self.data.get_mut().assume_init_drop() };
         }
     }
 }
+
+/// Value initialized the first time is accessed,
+/// something like the lazy_static macro commented above but without macro:
+///
+/// static TABLE: Lazy<Table> = Lazy::new(|| Table::new());
+pub struct Lazy<T, F = fn() -> T> {
+    once: Once<T>,
+    init: Cell<Option<F>>,
+}
+
+// The Cell is touched only by the one that win the race in Once
+unsafe impl<T: Send + Sync, F: Send> Sync for Lazy<T, F> {}
+
+impl<T, F> Lazy<T, F> {
+    pub const fn new(init: F) -> Self {
+        Self {
+            once: Once::new(),
+            init: Cell::new(Some(init)),
+        }
+    }
+
+    /// Return None if nobody accessed the value yet
+    pub fn get(&self) -> Option<&T> {
+        self.once.get()
+    }
+}
+
+impl<T, F: FnOnce() -> T> Lazy<T, F> {
+    /// Force the evaluation, same as a deref
+    pub fn force(this: &Self) -> &T {
+        this.once.call_once(|| match this.init.take() {
+            Some(init) => init(),
+            None => panic!("Lazy instance has previously been poisoned"),
+        })
+    }
+}
+
+impl<T, F: FnOnce() -> T> core::ops::Deref for Lazy<T, F> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        Self::force(self)
+    }
+}
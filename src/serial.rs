@@ -0,0 +1,198 @@
+use crate::concurrency::spin_mutex::SpinMutex;
+use crate::port::Port8Bit;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// First serial port, with qemu -serial stdio it is the terminal that started qemu
+pub const COM1: u16 = 0x3F8;
+pub const COM2: u16 = 0x2F8;
+
+/// IRQ raised by COM1 when something is received
+pub const COM1_IRQ: u8 = 4;
+/// Same for COM2, only the gdb stub enables it
+pub const COM2_IRQ: u8 = 3;
+
+/// The speed is set with a divisor of this
+const BASE_BAUD: u32 = 115200;
+pub const DEFAULT_BAUD: u32 = 38400;
+
+// registers of a 16550 UART, offsets from its base port
+// with DLAB set DATA and INTERRUPT_ENABLE are the low and high byte of the divisor
+const DATA: u16 = 0;
+const INTERRUPT_ENABLE: u16 = 1;
+// FIFO_CONTROL when written, the interrupt identification when read
+const FIFO_CONTROL: u16 = 2;
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+
+// INTERRUPT_ENABLE
+const RECEIVED_DATA_INTERRUPT: u8 = 0x01;
+
+// FIFO_CONTROL, interrupt when 14 bytes are in the receive fifo (or after a timeout)
+const FIFO_ENABLE: u8 = 0x01;
+const FIFO_CLEAR_RECEIVE: u8 = 0x02;
+const FIFO_CLEAR_TRANSMIT: u8 = 0x04;
+const FIFO_TRIGGER_14: u8 = 0xC0;
+
+// LINE_CONTROL
+const EIGHT_BITS_NO_PARITY_ONE_STOP: u8 = 0x03;
+const DLAB: u8 = 0x80;
+
+// MODEM_CONTROL, OUT2 connects the interrupt line of the uart to the PIC
+const DTR: u8 = 0x01;
+const RTS: u8 = 0x02;
+const OUT1: u8 = 0x04;
+const OUT2: u8 = 0x08;
+const LOOPBACK: u8 = 0x10;
+
+// LINE_STATUS
+const DATA_READY: u8 = 0x01;
+const TRANSMIT_EMPTY: u8 = 0x20;
+
+/// 16550 UART, the transmission is polled, the reception can be polled
+/// with read_byte or raise an interrupt
+pub struct SerialPort {
+    base: u16,
+    // init was called, the uart could be missing anyway
+    probed: bool,
+    // the loopback test passed
+    ready: bool,
+}
+
+impl SerialPort {
+    pub const fn new(base: u16) -> Self {
+        Self {
+            base,
+            probed: false,
+            ready: false,
+        }
+    }
+
+    fn register(&self, offset: u16) -> Port8Bit {
+        Port8Bit::new(self.base + offset)
+    }
+
+    /// baud must divide 115200, the line is 8 bits, no parity and one stop bit
+    ///
+    /// Err if there is no uart at this port (the loopback test fails),
+    /// the writes are lost but nothing breaks
+    pub fn init(&mut self, baud: u32) -> Result<(), &'static str> {
+        if baud == 0 || BASE_BAUD % baud != 0 || BASE_BAUD / baud > u16::MAX as u32 {
+            return Err("Baud rate must be a divisor of 115200");
+        }
+        let divisor = (BASE_BAUD / baud) as u16;
+        self.probed = true;
+        self.ready = false;
+
+        self.register(INTERRUPT_ENABLE).write(0x00);
+        self.register(LINE_CONTROL).write(DLAB);
+        self.register(DATA).write((divisor & 0xFF) as u8);
+        self.register(INTERRUPT_ENABLE).write((divisor >> 8) as u8);
+        self.register(LINE_CONTROL)
+            .write(EIGHT_BITS_NO_PARITY_ONE_STOP);
+        self.register(FIFO_CONTROL)
+            .write(FIFO_ENABLE | FIFO_CLEAR_RECEIVE | FIFO_CLEAR_TRANSMIT | FIFO_TRIGGER_14);
+
+        // what is sent comes back in loopback mode
+        self.register(MODEM_CONTROL).write(LOOPBACK | RTS);
+        self.register(DATA).write(0xAE);
+        if self.register(DATA).read() != 0xAE {
+            return Err("No serial port");
+        }
+
+        // out of loopback, only if there is someone to talk to
+        self.register(MODEM_CONTROL).write(DTR | RTS | OUT1 | OUT2);
+        self.ready = true;
+        Ok(())
+    }
+
+    /// From now on every byte received raises the IRQ of the port
+    pub fn enable_receive_interrupt(&self) {
+        self.register(INTERRUPT_ENABLE)
+            .write(RECEIVED_DATA_INTERRUPT);
+    }
+
+    pub fn write_byte(&mut self, byte: u8) {
+        // used before anyone called init (early prints, panics)
+        if !self.probed {
+            let _ = self.init(DEFAULT_BAUD);
+        }
+        // no uart, the byte is lost
+        if !self.ready {
+            return;
+        }
+
+        while self.register(LINE_STATUS).read() & TRANSMIT_EMPTY == 0 {
+            core::hint::spin_loop();
+        }
+        self.register(DATA).write(byte);
+    }
+
+    /// None if nothing was received
+    pub fn read_byte(&self) -> Option<u8> {
+        if self.register(LINE_STATUS).read() & DATA_READY == 0 {
+            return None;
+        }
+        Some(self.register(DATA).read())
+    }
+}
+
+impl fmt::Write for SerialPort {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            // the terminal on the other side needs also the carriage return
+            if byte == b'\n' {
+                self.write_byte(b'\r');
+            }
+            self.write_byte(byte);
+        }
+        Ok(())
+    }
+}
+
+// initialized by the first write if init was not called, so they can be used before anything else
+pub static SERIAL1: SpinMutex<SerialPort> = SpinMutex::new(SerialPort::new(COM1));
+pub static SERIAL2: SpinMutex<SerialPort> = SpinMutex::new(SerialPort::new(COM2));
+
+// print! and println! write also on COM1
+static CONSOLE_MIRROR: AtomicBool = AtomicBool::new(true);
+
+/// Set up both ports, what is received on COM1 raises COM1_IRQ
+pub fn init() {
+    crate::interrupts::without_interrupts(|| {
+        let mut serial1 = SERIAL1.lock();
+        if serial1.init(DEFAULT_BAUD).is_ok() {
+            serial1.enable_receive_interrupt();
+        }
+        let _ = SERIAL2.lock().init(DEFAULT_BAUD);
+    });
+}
+
+/// Mirror (or not) the console (print! and println!) on COM1
+pub fn set_console_mirror(enabled: bool) {
+    CONSOLE_MIRROR.store(enabled, Ordering::Relaxed);
+}
+
+pub fn console_mirror() -> bool {
+    CONSOLE_MIRROR.load(Ordering::Relaxed)
+}
+
+#[macro_export]
+macro_rules! serial_print {
+    ($($arg:tt)*) => ($crate::serial::_print(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! serial_println {
+    () => ($crate::serial_print!("\n"));
+    ($($arg:tt)*) => ($crate::serial_print!("{}\n", format_args!($($arg)*)));
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    use core::fmt::Write;
+    crate::interrupts::without_interrupts(|| {
+        SERIAL1.lock().write_fmt(args).unwrap();
+    });
+}
@@ -0,0 +1,45 @@
+// The return addresses are found following the frame pointers (the target keeps them).
+// Every function starts with
+//
+// push ebp
+// mov ebp, esp
+//
+// so [ebp] is the ebp of the caller and [ebp + 4] the return address,
+// start.s clears ebp before kernel_main to end the chain
+
+// frames walked at most, the chain could be corrupted
+const MAX_FRAMES: usize = 32;
+
+/// Return addresses of the frames from ebp up to kernel_main
+pub struct StackFrames {
+    ebp: usize,
+    depth: usize,
+}
+
+impl StackFrames {
+    /// ebp MUST be the frame pointer of a function, or 0
+    pub unsafe fn from_ebp(ebp: usize) -> Self {
+        Self { ebp, depth: 0 }
+    }
+}
+
+impl Iterator for StackFrames {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        if self.ebp == 0 || self.ebp % 4 != 0 || self.depth == MAX_FRAMES {
+            return None;
+        }
+
+        let frame = self.ebp as *const usize;
+        let (caller_ebp, return_addr) = unsafe { (*frame, *frame.add(1)) };
+        if return_addr == 0 {
+            return None;
+        }
+
+        // the callers are higher on the stack, anything else is a broken chain
+        self.ebp = if caller_ebp > self.ebp { caller_ebp } else { 0 };
+        self.depth += 1;
+        Some(return_addr)
+    }
+}
@@ -27,7 +27,7 @@
         .skip 1 * 1024 * 1024 // 1MB
     stack_top:
     heap_bottom:
-        .skip 16 * 1024 * 1024 // 16MB
+        .skip 4 * 1024 * 1024 // 4MB, only the boot heap, it grows after paging
     heap_top:
 
 .section .text
@@ -44,6 +44,9 @@
         push ebx // push Multiboot info
         push eax // push Multiboot flag
 
+        // the end of the frame pointer chain
+        xor ebp, ebp
+
         // now the environment is ready, start the code
         call kernel_main
 
@@ -0,0 +1,294 @@
+use crate::elf::{SectionHeader, Symbol, SHN_UNDEF, SHT_STRTAB, SHT_SYMTAB, STT_FUNC, STT_NOTYPE};
+use crate::multiboot::BootInfo;
+use crate::runtime_static::Once;
+use core::fmt;
+use core::mem::size_of;
+use core::ptr::read_unaligned;
+
+// Symbols of the kernel, used to print function+offset instead of a bare address
+//
+// The bootloader loads also the sections without SHF_ALLOC (.symtab and .strtab) after the
+// kernel and passes the section headers (multiboot flag 5), nothing is copied: the sections
+// are identity mapped and kernel_main keeps them out of the frame allocator.
+// qemu -kernel doesn't pass them, the addresses are printed without names
+
+/// .symtab and its string table, .strtab
+pub struct SymbolTable {
+    symbols: &'static [u8],
+    strings: &'static [u8],
+}
+
+impl SymbolTable {
+    /// Err if there is no symbol table inside the sections
+    pub fn from_sections(sections: &'static [SectionHeader]) -> Result<Self, &'static str> {
+        let symtab = sections
+            .iter()
+            .find(|section| section.section_type == SHT_SYMTAB)
+            .ok_or("No symbol table")?;
+        let strtab = sections
+            .get(symtab.link as usize)
+            .filter(|section| section.section_type == SHT_STRTAB)
+            .ok_or("No string table for the symbols")?;
+        if symtab.addr == 0 || strtab.addr == 0 {
+            return Err("Symbol table not loaded");
+        }
+
+        unsafe {
+            Ok(Self::new(
+                core::slice::from_raw_parts(symtab.addr as *const u8, symtab.size as usize),
+                core::slice::from_raw_parts(strtab.addr as *const u8, strtab.size as usize),
+            ))
+        }
+    }
+
+    pub fn new(symbols: &'static [u8], strings: &'static [u8]) -> Self {
+        Self { symbols, strings }
+    }
+
+    pub fn len(&self) -> usize {
+        self.symbols.len() / size_of::<Symbol>()
+    }
+
+    fn symbol(&self, index: usize) -> Symbol {
+        // the section could be not aligned
+        unsafe {
+            read_unaligned(self.symbols.as_ptr().add(index * size_of::<Symbol>()) as *const Symbol)
+        }
+    }
+
+    fn name(&self, symbol: &Symbol) -> &'static str {
+        let start = (symbol.name as usize).min(self.strings.len());
+        let name = &self.strings[start..];
+        let len = name
+            .iter()
+            .position(|byte| *byte == 0)
+            .unwrap_or(name.len());
+        core::str::from_utf8(&name[..len]).unwrap_or("")
+    }
+
+    /// Function containing addr (or the label before it for the assembly) and the offset
+    pub fn resolve(&self, addr: usize) -> Option<(&'static str, usize)> {
+        let mut best: Option<Symbol> = None;
+
+        for index in 0..self.len() {
+            let symbol = self.symbol(index);
+            let value = symbol.value as usize;
+            let code = matches!(symbol.symbol_type(), STT_FUNC | STT_NOTYPE);
+
+            if !code || symbol.shndx == SHN_UNDEF || value == 0 || value > addr {
+                continue;
+            }
+            // the labels of the assembly have no size
+            if symbol.size != 0 && addr - value >= symbol.size as usize {
+                continue;
+            }
+            if best.map_or(true, |best| best.value < symbol.value) {
+                best = Some(symbol);
+            }
+        }
+
+        best.map(|symbol| (self.name(&symbol), addr - symbol.value as usize))
+    }
+}
+
+static KERNEL_SYMBOLS: Once<SymbolTable> = Once::new();
+
+/// Return the number of symbols found
+#[cfg_attr(all(test, not(target_os = "none")), allow(dead_code))]
+pub fn init(boot_info: &BootInfo) -> Result<usize, &'static str> {
+    let table = SymbolTable::from_sections(boot_info.elf_sections())?;
+    Ok(KERNEL_SYMBOLS.call_once(|| table).len())
+}
+
+/// None before init or if addr is not inside a known function
+pub fn resolve(addr: usize) -> Option<(&'static str, usize)> {
+    KERNEL_SYMBOLS.get()?.resolve(addr)
+}
+
+/// Printed as "0x00101234 kernel::panic::kernel_panic+0x1a"
+#[derive(Debug, Clone, Copy)]
+pub struct Symbolized(pub usize);
+
+impl fmt::Display for Symbolized {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "0x{:08X}", self.0)?;
+        if let Some((name, offset)) = resolve(self.0) {
+            write!(f, " {}+0x{:x}", Demangled(name), offset)?;
+        }
+        Ok(())
+    }
+}
+
+/// Rust legacy mangling (_ZN...E) without the hash, anything else is printed as it is.
+/// Nothing is allocated, it is used while panicking
+pub struct Demangled<'a>(pub &'a str);
+
+impl fmt::Display for Demangled<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match split_path(self.0) {
+            Some(path) => {
+                for (i, part) in path.enumerate() {
+                    if i != 0 {
+                        f.write_str("::")?;
+                    }
+                    write_unescaped(f, part)?;
+                }
+                Ok(())
+            }
+            None => f.write_str(self.0),
+        }
+    }
+}
+
+// the parts of the path, None if name is not a legacy mangled name
+fn split_path(name: &str) -> Option<impl Iterator<Item = &str>> {
+    let mut rest = name.strip_prefix("_ZN")?.strip_suffix('E')?;
+
+    // every part is its length followed by the characters, check them all before printing
+    let mut parts = 0;
+    while !rest.is_empty() {
+        let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+        let len: usize = rest[..digits].parse().ok()?;
+        rest = rest[digits..].get(len..)?;
+        parts += 1;
+    }
+
+    let mut rest = &name[3..name.len() - 1];
+    let parts = (0..parts).map(move |_| {
+        let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+        let len: usize = rest[..digits].parse().unwrap_or(0);
+        let part = &rest[digits..digits + len];
+        rest = &rest[digits + len..];
+        part
+    });
+    Some(parts.filter(|part| !is_hash(part)))
+}
+
+// the last part, h followed by 16 hex digits
+fn is_hash(part: &str) -> bool {
+    part.len() == 17
+        && part.starts_with('h')
+        && part[1..].bytes().all(|byte| byte.is_ascii_hexdigit())
+}
+
+// $LT$ -> <, $u20$ -> ' ', .. -> ::
+fn write_unescaped(f: &mut fmt::Formatter, part: &str) -> fmt::Result {
+    // a part can't start with $, it gets a _ before
+    let mut rest = if part.starts_with("_$") {
+        &part[1..]
+    } else {
+        part
+    };
+
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix("..") {
+            f.write_str("::")?;
+            rest = after;
+            continue;
+        }
+
+        let escape = rest
+            .strip_prefix('$')
+            .and_then(|after| after.find('$').map(|end| &after[..end]));
+        let Some(escape) = escape else {
+            let end = rest
+                .char_indices()
+                .skip(1)
+                .find(|(_, c)| *c == '$' || *c == '.')
+                .map_or(rest.len(), |(end, _)| end);
+            f.write_str(&rest[..end])?;
+            rest = &rest[end..];
+            continue;
+        };
+
+        let unescaped = match escape {
+            "SP" => Some('@'),
+            "BP" => Some('*'),
+            "RF" => Some('&'),
+            "LT" => Some('<'),
+            "GT" => Some('>'),
+            "LP" => Some('('),
+            "RP" => Some(')'),
+            "C" => Some(','),
+            _ => escape
+                .strip_prefix('u')
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                .and_then(char::from_u32),
+        };
+        match unescaped {
+            Some(c) => write!(f, "{}", c)?,
+            None => write!(f, "${}$", escape)?,
+        }
+        rest = &rest[escape.len() + 2..];
+    }
+    Ok(())
+}
+
+// on the host, see test_on_host.sh
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+    use alloc::vec::Vec;
+
+    #[test]
+    fn demangle_legacy_names() {
+        let demangle = |name| Demangled(name).to_string();
+
+        assert_eq!(
+            demangle("_ZN6kernel5panic12kernel_panic17h0123456789abcdefE"),
+            "kernel::panic::kernel_panic"
+        );
+        assert_eq!(
+            demangle(
+                "_ZN4core3ptr42drop_in_place$LT$alloc..string..String$GT$17h1111111111111111E"
+            ),
+            "core::ptr::drop_in_place<alloc::string::String>"
+        );
+        assert_eq!(
+            demangle("_ZN56_$LT$kernel..log..Record$u20$as$u20$core..fmt..Write$GT$9write_str17hffffffffffffffffE"),
+            "<kernel::log::Record as core::fmt::Write>::write_str"
+        );
+        assert_eq!(demangle("start"), "start");
+        assert_eq!(demangle("_ZN6kernelE99"), "_ZN6kernelE99");
+        assert_eq!(demangle("_ZN10too_shortE"), "_ZN10too_shortE");
+    }
+
+    fn symbol(name: u32, value: u32, size: u32, symbol_type: u8) -> Symbol {
+        Symbol {
+            name,
+            value,
+            size,
+            info: symbol_type,
+            other: 0,
+            shndx: 1,
+        }
+    }
+
+    #[test]
+    fn resolve_addresses() {
+        let symbols = [
+            symbol(0, 0, 0, STT_NOTYPE),
+            symbol(1, 0x1000, 0x100, STT_FUNC),
+            symbol(7, 0x1100, 0, STT_NOTYPE),
+            symbol(13, 0x2000, 0x10, STT_FUNC),
+        ];
+        let bytes: Vec<u8> = symbols
+            .iter()
+            .flat_map(|symbol| {
+                let ptr = symbol as *const Symbol as *const u8;
+                unsafe { core::slice::from_raw_parts(ptr, size_of::<Symbol>()) }.to_vec()
+            })
+            .collect();
+        let table = SymbolTable::new(bytes.leak(), b"\0first\0label\0second\0");
+
+        assert_eq!(table.len(), 4);
+        assert_eq!(table.resolve(0x1000), Some(("first", 0)));
+        assert_eq!(table.resolve(0x10FF), Some(("first", 0xFF)));
+        assert_eq!(table.resolve(0x1100), Some(("label", 0)));
+        assert_eq!(table.resolve(0x1FFF), Some(("label", 0xEFF)));
+        assert_eq!(table.resolve(0x2008), Some(("second", 8)));
+        assert_eq!(table.resolve(0x2010), Some(("label", 0xF10)));
+        assert_eq!(table.resolve(0x10), None);
+    }
+}
@@ -0,0 +1,412 @@
+use crate::interrupts::{interrupt_frame::InterruptFrame, PIT_FREQUENCY};
+use crate::memory_manager::paging::{PageTableFlag, VirtualAddr};
+use crate::memory_manager::{PAGE_SIZE, USER_SPACE_END, USER_SPACE_START};
+use crate::task::{process, scheduler, TaskId};
+use crate::{info, print};
+use alloc::{string::String, vec::Vec};
+
+// System calls are called from user mode with int 0x80 (SYSCALL_INTERRUPT):
+// eax contains the number and ebx, ecx, edx, esi, edi, ebp the arguments,
+// the result is returned in eax. Errors are returned as -errno, like linux
+
+// same numbers of linux i386
+pub const SYS_EXIT: u32 = 1;
+pub const SYS_FORK: u32 = 2;
+pub const SYS_WRITE: u32 = 4;
+pub const SYS_WAITPID: u32 = 7;
+pub const SYS_EXECVE: u32 = 11;
+pub const SYS_GETPID: u32 = 20;
+// sbrk does not exist, it is implemented in user space with brk
+pub const SYS_BRK: u32 = 45;
+pub const SYS_GETPPID: u32 = 64;
+pub const SYS_MUNMAP: u32 = 91;
+// the offset is in pages, only anonymous mappings are supported
+pub const SYS_MMAP2: u32 = 192;
+// not in linux (it would be nanosleep with a timespec), the argument is in milliseconds
+pub const SYS_SLEEP: u32 = 1000;
+
+const STDOUT: u32 = 1;
+const STDERR: u32 = 2;
+
+// waitpid options
+const WNOHANG: u32 = 1;
+
+// mmap protection
+const PROT_WRITE: u32 = 0x2;
+// without NX readable and executable are the same thing
+const PROT_READ_EXEC: u32 = 0x1 | 0x4;
+
+// mmap flags
+const MAP_SHARED: u32 = 0x01;
+const MAP_PRIVATE: u32 = 0x02;
+const MAP_FIXED: u32 = 0x10;
+const MAP_ANONYMOUS: u32 = 0x20;
+
+// limits for the strings copied from user mode
+const MAX_STRING_LEN: usize = 4096;
+const MAX_ARGS: usize = 64;
+
+// the user buffers are copied on the stack a piece at time,
+// nothing is allocated while the scheduler is locked
+const COPY_CHUNK: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i32)]
+pub enum Errno {
+    ENOENT = 2,
+    E2BIG = 7,
+    ENOEXEC = 8,
+    EBADF = 9,
+    ECHILD = 10,
+    EAGAIN = 11,
+    ENOMEM = 12,
+    EFAULT = 14,
+    EINVAL = 22,
+    ENOSYS = 38,
+}
+
+type SyscallResult = Result<u32, Errno>;
+
+/// Execute the system call saved in frame (number in eax, arguments in
+/// ebx, ecx, edx, esi, edi, ebp) and put the result in eax
+pub fn dispatch(frame: &mut InterruptFrame) {
+    let args = [frame.ebx, frame.ecx, frame.edx, frame.esi, frame.edi, frame.ebp];
+
+    let result = match frame.eax {
+        SYS_EXIT => sys_exit(args[0]),
+        SYS_FORK => sys_fork(frame),
+        SYS_WRITE => sys_write(args[0], args[1], args[2]),
+        SYS_WAITPID => sys_waitpid(args[0], args[1], args[2]),
+        SYS_EXECVE => sys_execve(frame, args[0], args[1], args[2]),
+        SYS_GETPID => sys_getpid(),
+        SYS_BRK => sys_brk(args[0]),
+        SYS_GETPPID => sys_getppid(),
+        SYS_MUNMAP => sys_munmap(args[0], args[1]),
+        SYS_MMAP2 => sys_mmap2(args[0], args[1], args[2], args[3], args[4], args[5]),
+        SYS_SLEEP => sys_sleep(args[0]),
+        _ => Err(Errno::ENOSYS),
+    };
+
+    frame.eax = match result {
+        Ok(value) => value,
+        Err(errno) => (-(errno as i32)) as u32,
+    };
+}
+
+/// Ok only if every byte of [addr, addr + len) of the running task is accessible
+/// from user mode, a kernel thread has no user buffer
+fn check_user_buffer(addr: usize, len: usize, writable: bool) -> Result<(), Errno> {
+    scheduler::with_current_address_space(|address_space| {
+        address_space.check_user_buffer(addr, len, writable)
+    })
+    .ok_or(Errno::EFAULT)?
+    .map_err(|_| Errno::EFAULT)
+}
+
+/// Copy [addr, addr + buf.len()) of the running task into buf
+///
+/// The pages are made present here: a fault in the kernel would be a panic,
+/// running out of frames is ENOMEM for the caller
+fn copy_from_user(addr: usize, buf: &mut [u8]) -> Result<(), Errno> {
+    scheduler::with_current_address_space(|address_space| {
+        address_space
+            .check_user_buffer(addr, buf.len(), false)
+            .map_err(|_| Errno::EFAULT)?;
+        address_space
+            .read(VirtualAddr::new(addr), buf)
+            .map_err(|_| Errno::ENOMEM)
+    })
+    .ok_or(Errno::EFAULT)?
+}
+
+/// Copy data into [addr, addr + data.len()) of the running task, like copy_from_user
+fn copy_to_user(addr: usize, data: &[u8]) -> Result<(), Errno> {
+    scheduler::with_current_address_space(|address_space| {
+        address_space
+            .check_user_buffer(addr, data.len(), true)
+            .map_err(|_| Errno::EFAULT)?;
+        address_space
+            .write(VirtualAddr::new(addr), data)
+            .map_err(|_| Errno::ENOMEM)
+    })
+    .ok_or(Errno::EFAULT)?
+}
+
+/// Copy a null terminated string from user mode
+fn user_c_str(addr: u32) -> Result<String, Errno> {
+    let mut bytes = Vec::new();
+    let mut chunk = [0u8; COPY_CHUNK];
+
+    loop {
+        let addr = addr as usize + bytes.len();
+        // never past the page, the string could end before the next one
+        let page_end = (addr & !(PAGE_SIZE - 1)) + PAGE_SIZE;
+        let chunk = &mut chunk[..(page_end - addr).min(COPY_CHUNK)];
+        copy_from_user(addr, chunk)?;
+
+        match chunk.iter().position(|byte| *byte == 0) {
+            Some(len) => {
+                bytes.extend_from_slice(&chunk[..len]);
+                break;
+            }
+            None => bytes.extend_from_slice(chunk),
+        }
+
+        if bytes.len() > MAX_STRING_LEN {
+            return Err(Errno::E2BIG);
+        }
+    }
+
+    String::from_utf8(bytes).map_err(|_| Errno::EINVAL)
+}
+
+/// Copy a null terminated array of strings (like argv) from user mode, NULL is an empty array
+fn user_c_str_array(addr: u32) -> Result<Vec<String>, Errno> {
+    let mut strings = Vec::new();
+    if addr == 0 {
+        return Ok(strings);
+    }
+
+    loop {
+        if strings.len() == MAX_ARGS {
+            return Err(Errno::E2BIG);
+        }
+
+        let mut ptr = [0u8; 4];
+        copy_from_user(addr as usize + strings.len() * 4, &mut ptr)?;
+        let ptr = u32::from_le_bytes(ptr);
+        if ptr == 0 {
+            return Ok(strings);
+        }
+
+        strings.push(user_c_str(ptr)?);
+    }
+}
+
+fn sys_exit(status: u32) -> SyscallResult {
+    let status = status as i32;
+    info!(
+        "Task {} exited with status {}",
+        scheduler::current_id().get(),
+        status
+    );
+    process::exit(status);
+}
+
+fn sys_fork(frame: &mut InterruptFrame) -> SyscallResult {
+    let user_frame = frame.as_user_frame().ok_or(Errno::EFAULT)?;
+    process::fork(user_frame)
+        .map(|pid| pid.get() as u32)
+        .map_err(|_| Errno::EAGAIN)
+}
+
+/// status is written like linux, the exit status is in the second byte
+fn sys_waitpid(pid: u32, status_addr: u32, options: u32) -> SyscallResult {
+    let pid = match pid as i32 {
+        -1 => None,
+        pid if pid > 0 => Some(TaskId::from_raw(pid as usize)),
+        // process groups do not exist
+        _ => return Err(Errno::EINVAL),
+    };
+
+    // checked before, so the child is never lost
+    if status_addr != 0 {
+        check_user_buffer(status_addr as usize, 4, true)?;
+    }
+
+    match process::wait(pid, options & WNOHANG != 0) {
+        Ok(Some((child, status))) => {
+            if status_addr != 0 {
+                let wait_status = ((status as u32) & 0xFF) << 8;
+                copy_to_user(status_addr as usize, &wait_status.to_le_bytes())?;
+            }
+            Ok(child.get() as u32)
+        }
+        Ok(None) => Ok(0),
+        Err(_) => Err(Errno::ECHILD),
+    }
+}
+
+/// The path is the name of a program registered at boot
+fn sys_execve(frame: &mut InterruptFrame, path: u32, argv: u32, envp: u32) -> SyscallResult {
+    let path = user_c_str(path)?;
+    let argv = user_c_str_array(argv)?;
+    let envp = user_c_str_array(envp)?;
+
+    let elf_data = process::find_program(&path).ok_or(Errno::ENOENT)?;
+    let user_frame = frame.as_user_frame().ok_or(Errno::EFAULT)?;
+
+    let argv: Vec<&str> = argv.iter().map(|arg| arg.as_str()).collect();
+    let envp: Vec<&str> = envp.iter().map(|env| env.as_str()).collect();
+    process::exec(elf_data, &argv, &envp, user_frame).map_err(|_| Errno::ENOEXEC)?;
+
+    // eax of the new program
+    Ok(0)
+}
+
+fn sys_write(fd: u32, buf: u32, len: u32) -> SyscallResult {
+    if fd != STDOUT && fd != STDERR {
+        return Err(Errno::EBADF);
+    }
+
+    let len = len as usize;
+    let mut buffer = [0u8; COPY_CHUNK];
+    // bytes of a character cut at the end of the previous chunk, kept at the start of buffer
+    let mut pending = 0;
+    let mut written = 0;
+
+    while written < len {
+        let size = (len - written).min(COPY_CHUNK - pending);
+        match copy_from_user(buf as usize + written, &mut buffer[pending..pending + size]) {
+            Ok(()) => (),
+            // like linux, what was already written is not an error
+            Err(errno) if written == 0 => return Err(errno),
+            Err(_) => break,
+        }
+        written += size;
+
+        let end = pending + size;
+        pending = print_utf8_lossy(&buffer[..end]);
+        buffer.copy_within(end - pending..end, 0);
+    }
+    if pending != 0 {
+        print!("{}", char::REPLACEMENT_CHARACTER);
+    }
+
+    Ok(written as u32)
+}
+
+/// Print data, the invalid utf8 becomes U+FFFD. Return the length of the
+/// character not complete at the end of data, it is not printed
+fn print_utf8_lossy(mut data: &[u8]) -> usize {
+    loop {
+        match core::str::from_utf8(data) {
+            Ok(valid) => {
+                print!("{}", valid);
+                return 0;
+            }
+            Err(error) => {
+                let (valid, rest) = data.split_at(error.valid_up_to());
+                print!("{}", unsafe { core::str::from_utf8_unchecked(valid) });
+                match error.error_len() {
+                    Some(invalid) => {
+                        print!("{}", char::REPLACEMENT_CHARACTER);
+                        data = &rest[invalid..];
+                    }
+                    None => return rest.len(),
+                }
+            }
+        }
+    }
+}
+
+fn sys_getpid() -> SyscallResult {
+    Ok(scheduler::current_id().get() as u32)
+}
+
+/// 0 if the process has no parent (like init)
+fn sys_getppid() -> SyscallResult {
+    Ok(process::parent(scheduler::current_id()).map_or(0, |parent| parent.get() as u32))
+}
+
+/// Like linux, the new break is returned on success and the old one on failure,
+/// brk(0) returns the current break
+fn sys_brk(addr: u32) -> SyscallResult {
+    scheduler::with_current_address_space(|address_space| {
+        if addr != 0 {
+            let _ = address_space.set_program_break(addr as usize);
+        }
+        address_space.program_break() as u32
+    })
+    .ok_or(Errno::ENOMEM)
+}
+
+/// Anonymous private mapping, the pages are allocated on the first access.
+/// Without MAP_FIXED addr is only a hint
+fn sys_mmap2(addr: u32, len: u32, prot: u32, flags: u32, fd: u32, pgoffset: u32) -> SyscallResult {
+    if flags & MAP_ANONYMOUS == 0 || flags & MAP_SHARED != 0 || flags & MAP_PRIVATE == 0 {
+        return Err(Errno::EINVAL);
+    }
+    // no files, fd must be -1 like linux wants for anonymous mappings
+    if fd as i32 != -1 || pgoffset != 0 {
+        return Err(Errno::EINVAL);
+    }
+
+    let (addr, len) = (addr as usize, len as usize);
+    if len == 0 {
+        return Err(Errno::EINVAL);
+    }
+    let len = len.checked_add(PAGE_SIZE - 1).ok_or(Errno::ENOMEM)? & !(PAGE_SIZE - 1);
+    let fixed = flags & MAP_FIXED != 0;
+    if fixed
+        && (addr % PAGE_SIZE != 0
+            || addr < USER_SPACE_START
+            || addr.checked_add(len).map_or(true, |end| end > USER_SPACE_END))
+    {
+        return Err(Errno::EINVAL);
+    }
+
+    let mut page_flags = 0;
+    if prot & (PROT_READ_EXEC | PROT_WRITE) != 0 {
+        page_flags |= PageTableFlag::User as u32;
+    }
+    if prot & PROT_WRITE != 0 {
+        page_flags |= PageTableFlag::Writable as u32;
+    }
+
+    scheduler::with_current_address_space(|address_space| {
+        if fixed {
+            // replace everything that was there
+            address_space
+                .replace(addr, len, page_flags)
+                .map_err(|_| Errno::ENOMEM)?;
+            return Ok(addr as u32);
+        }
+
+        // without MAP_FIXED addr is only a hint, moved to the next page
+        let hint = addr.checked_add(PAGE_SIZE - 1).unwrap_or(0) & !(PAGE_SIZE - 1);
+        let start = if hint != 0 && address_space.is_free(hint, len) {
+            hint
+        } else {
+            address_space.find_free_area(len).ok_or(Errno::ENOMEM)?
+        };
+
+        address_space
+            .reserve(start, len, page_flags)
+            .map_err(|_| Errno::ENOMEM)?;
+        Ok(start as u32)
+    })
+    .ok_or(Errno::ENOMEM)?
+}
+
+/// Remove the mappings inside [addr, addr + len), it is not an error if nothing was mapped
+fn sys_munmap(addr: u32, len: u32) -> SyscallResult {
+    let (addr, len) = (addr as usize, len as usize);
+    if len == 0 || addr % PAGE_SIZE != 0 || addr < USER_SPACE_START {
+        return Err(Errno::EINVAL);
+    }
+    let len = len.checked_add(PAGE_SIZE - 1).ok_or(Errno::EINVAL)? & !(PAGE_SIZE - 1);
+    if addr.checked_add(len).map_or(true, |end| end > USER_SPACE_END) {
+        return Err(Errno::EINVAL);
+    }
+
+    scheduler::with_current_address_space(|address_space| address_space.unreserve(addr, len))
+        .ok_or(Errno::EINVAL)?
+        .map_err(|_| Errno::EINVAL)?;
+    Ok(0)
+}
+
+/// sleep(0) only gives up the rest of the time slice
+fn sys_sleep(milliseconds: u32) -> SyscallResult {
+    let ticks = (milliseconds as usize)
+        .checked_mul(PIT_FREQUENCY as usize)
+        .and_then(|ticks| ticks.checked_add(999))
+        .ok_or(Errno::EINVAL)?
+        / 1000;
+    if ticks == 0 {
+        scheduler::yield_now();
+    } else {
+        scheduler::sleep(ticks);
+    }
+    Ok(0)
+}
@@ -0,0 +1,230 @@
+pub mod process;
+pub mod scheduler;
+
+use scheduler::Priority;
+use crate::elf::{loader::{self, LoadedProgram}, ElfFile};
+use crate::gdt::{KERNEL_CODE_SELECTOR, KERNEL_DATA_SELECTOR, USER_CODE_SELECTOR, USER_DATA_SELECTOR};
+use crate::interrupts::interrupt_frame::{
+    InterruptFrame, UserInterruptFrame, EFLAGS_IF, EFLAGS_RESERVED,
+};
+use crate::memory_manager::address_space::AddressSpace;
+use alloc::boxed::Box;
+use core::{
+    mem::size_of,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+pub const KERNEL_STACK_SIZE: usize = 16 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TaskId(usize);
+
+impl TaskId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+        TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn get(&self) -> usize {
+        self.0
+    }
+
+    /// Id received from outside (like a pid from user mode), could not exist
+    pub fn from_raw(id: usize) -> Self {
+        TaskId(id)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskState {
+    Ready,
+    Running,
+    // waiting for someone to wake it up
+    Blocked,
+    // waiting until the pit tick counter reach the value
+    Sleeping(usize),
+    // finished, the scheduler will never pick it again and
+    // the stack will be freed as soon as possible
+    Dead,
+}
+
+/// Every task has its own kernel stack, when a task is not running
+/// all its registers are saved on top of it (see InterruptFrame)
+/// so the only thing needed to restore a task is the esp
+pub struct Task {
+    id: TaskId,
+    state: TaskState,
+    priority: Priority,
+    // pit ticks left before the task will be switched out
+    remaining_ticks: usize,
+    // None only for the boot task, that use the stack reserved in start.s
+    kernel_stack: Option<Box<[u8]>>,
+    // updated every time the task is switched out
+    esp: u32,
+    // None for kernel threads, they run in whatever address space is loaded
+    address_space: Option<AddressSpace>,
+}
+
+impl Task {
+    /// Task that describe the code that is running now (kernel_main),
+    /// the esp will be saved at the first switch
+    fn boot_task() -> Self {
+        Self {
+            id: TaskId::new(),
+            state: TaskState::Running,
+            priority: Priority::Normal,
+            remaining_ticks: 0,
+            kernel_stack: None,
+            esp: 0,
+            address_space: None,
+        }
+    }
+
+    /// Allocate a new stack and prepare it like the task was interrupted
+    /// just before calling kernel_thread_entry(entry)
+    ///
+    /// |...free stack...|InterruptFrame|fake return address|entry|top
+    fn new_kernel_thread(entry: fn(), priority: Priority) -> Self {
+        let kernel_stack = vec![0u8; KERNEL_STACK_SIZE].into_boxed_slice();
+        let stack_top = (kernel_stack.as_ptr() as usize + KERNEL_STACK_SIZE) & !0xF;
+
+        let esp = unsafe {
+            // arguments of kernel_thread_entry as pushed by a call
+            let args = (stack_top - 2 * size_of::<u32>()) as *mut u32;
+            args.write(0);
+            args.add(1).write(entry as usize as u32);
+
+            let frame = (args as usize - size_of::<InterruptFrame>()) as *mut InterruptFrame;
+            frame.write(InterruptFrame {
+                gs: KERNEL_DATA_SELECTOR as u32,
+                fs: KERNEL_DATA_SELECTOR as u32,
+                es: KERNEL_DATA_SELECTOR as u32,
+                ds: KERNEL_DATA_SELECTOR as u32,
+                eip: kernel_thread_entry as *const () as usize as u32,
+                cs: KERNEL_CODE_SELECTOR as u32,
+                eflags: EFLAGS_IF | EFLAGS_RESERVED,
+                ..Default::default()
+            });
+
+            frame as usize as u32
+        };
+
+        Self {
+            id: TaskId::new(),
+            state: TaskState::Ready,
+            priority,
+            remaining_ticks: 0,
+            kernel_stack: Some(kernel_stack),
+            esp,
+            address_space: None,
+        }
+    }
+
+    /// Prepare the kernel stack like the task was interrupted in ring 3
+    /// just before the first instruction of the program,
+    /// the first switch will iret directly in user mode
+    fn new_user_task(address_space: AddressSpace, program: &LoadedProgram) -> Self {
+        Self::new_user_task_with_frame(address_space, user_entry_frame(program), Priority::Normal)
+    }
+
+    /// The first switch will restore frame
+    ///
+    /// |...free stack...|UserInterruptFrame|top
+    fn new_user_task_with_frame(
+        address_space: AddressSpace,
+        user_frame: UserInterruptFrame,
+        priority: Priority,
+    ) -> Self {
+        let kernel_stack = vec![0u8; KERNEL_STACK_SIZE].into_boxed_slice();
+        let stack_top = (kernel_stack.as_ptr() as usize + KERNEL_STACK_SIZE) & !0xF;
+
+        let esp = unsafe {
+            let frame = (stack_top - size_of::<UserInterruptFrame>()) as *mut UserInterruptFrame;
+            frame.write(user_frame);
+
+            frame as usize as u32
+        };
+
+        Self {
+            id: TaskId::new(),
+            state: TaskState::Ready,
+            priority,
+            remaining_ticks: 0,
+            kernel_stack: Some(kernel_stack),
+            esp,
+            address_space: Some(address_space),
+        }
+    }
+
+    /// Where esp0 must point when the task is running, the kernel stack is empty
+    /// every time the task is in user mode
+    fn kernel_stack_top(&self) -> Option<u32> {
+        self.kernel_stack
+            .as_ref()
+            .map(|stack| ((stack.as_ptr() as usize + KERNEL_STACK_SIZE) & !0xF) as u32)
+    }
+
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    pub fn state(&self) -> TaskState {
+        self.state
+    }
+
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+}
+
+/// Registers of a program that is about to execute its first instruction
+fn user_entry_frame(program: &LoadedProgram) -> UserInterruptFrame {
+    UserInterruptFrame {
+        frame: InterruptFrame {
+            gs: USER_DATA_SELECTOR as u32,
+            fs: USER_DATA_SELECTOR as u32,
+            es: USER_DATA_SELECTOR as u32,
+            ds: USER_DATA_SELECTOR as u32,
+            eip: program.entry as u32,
+            cs: USER_CODE_SELECTOR as u32,
+            eflags: EFLAGS_IF | EFLAGS_RESERVED,
+            ..Default::default()
+        },
+        user_esp: program.stack_pointer as u32,
+        user_ss: USER_DATA_SELECTOR as u32,
+    }
+}
+
+/// First function executed by every kernel thread, the iret
+/// of the first switch will jump here
+// fn() is just a pointer, the only thing that matters is the stack layout
+#[allow(improper_ctypes_definitions)]
+extern "C" fn kernel_thread_entry(entry: fn()) -> ! {
+    entry();
+    scheduler::exit_current();
+}
+
+/// Create a new kernel thread that will start executing entry when
+/// chosen by the scheduler, when entry returns the thread is terminated
+pub fn spawn_kernel_thread(entry: fn()) -> TaskId {
+    spawn_kernel_thread_with_priority(entry, Priority::Normal)
+}
+
+pub fn spawn_kernel_thread_with_priority(entry: fn(), priority: Priority) -> TaskId {
+    scheduler::add_task(Task::new_kernel_thread(entry, priority))
+}
+
+/// Load the ELF executable elf_data in a new address space
+/// and create a user process that will start from its entry point
+///
+/// A process spawned by the kernel has no parent, the first one is the init process
+pub fn spawn_user_process(elf_data: &[u8], argv: &[&str]) -> Result<TaskId, &'static str> {
+    let elf = ElfFile::new(elf_data)?;
+    let mut address_space = AddressSpace::new()?;
+    let program = loader::load(&elf, &mut address_space, argv, &[])?;
+
+    let task = Task::new_user_task(address_space, &program);
+    // registered before the task could run (and exit)
+    process::register(task.id, None);
+    Ok(scheduler::add_task(task))
+}
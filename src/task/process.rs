@@ -0,0 +1,261 @@
+use super::{scheduler, user_entry_frame, Task, TaskId};
+use crate::concurrency::{spin_mutex::SpinMutex, wait_queue::WaitQueue};
+use crate::elf::{loader, ElfFile};
+use crate::interrupts::{interrupt_frame::UserInterruptFrame, without_interrupts};
+use crate::memory_manager::address_space::AddressSpace;
+use crate::println;
+use alloc::{collections::BTreeMap, vec::Vec};
+
+/// Every process has only one task, so the pid is the id of that task
+pub type Pid = TaskId;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessState {
+    Alive,
+    // exited with this status, waiting for the parent to read it
+    Zombie(i32),
+}
+
+/// Process Control Block, the registers, the kernel stack and the
+/// address space are inside the Task of the process
+#[derive(Debug)]
+struct Process {
+    parent: Option<Pid>,
+    children: Vec<Pid>,
+    state: ProcessState,
+}
+
+struct ProcessTable {
+    processes: BTreeMap<Pid, Process>,
+    // the first process without a parent, adopts all the orphans
+    init: Option<Pid>,
+}
+
+impl ProcessTable {
+    const fn new() -> Self {
+        Self {
+            processes: BTreeMap::new(),
+            init: None,
+        }
+    }
+
+    fn register(&mut self, pid: Pid, parent: Option<Pid>) {
+        match parent {
+            Some(parent) => {
+                if let Some(parent) = self.processes.get_mut(&parent) {
+                    parent.children.push(pid);
+                }
+            }
+            None => {
+                if self.init.is_none() {
+                    self.init = Some(pid);
+                }
+            }
+        }
+
+        self.processes.insert(
+            pid,
+            Process {
+                parent,
+                children: Vec::new(),
+                state: ProcessState::Alive,
+            },
+        );
+    }
+
+    /// Make pid a zombie and give all its children to init.
+    /// Nobody can wait for a process without a parent, it is removed at once
+    fn exit(&mut self, pid: Pid, status: i32) {
+        let (parent, children) = match self.processes.get_mut(&pid) {
+            Some(process) => {
+                process.state = ProcessState::Zombie(status);
+                (process.parent, core::mem::take(&mut process.children))
+            }
+            None => return,
+        };
+
+        if self.init == Some(pid) {
+            println!("The init process exited with status {}", status);
+        }
+        let init = self
+            .init
+            .filter(|init| *init != pid && self.is_alive(*init));
+
+        for child in children.iter() {
+            let Some(process) = self.processes.get_mut(child) else {
+                continue;
+            };
+            process.parent = init;
+            // nobody will ever wait for the orphans
+            if init.is_none() && process.state != ProcessState::Alive {
+                self.processes.remove(child);
+            }
+        }
+        if let Some(init) = init.and_then(|init| self.processes.get_mut(&init)) {
+            init.children.extend(children);
+        }
+
+        if parent.is_none() {
+            self.processes.remove(&pid);
+        }
+    }
+
+    fn is_alive(&self, pid: Pid) -> bool {
+        self.processes
+            .get(&pid)
+            .map_or(false, |process| process.state == ProcessState::Alive)
+    }
+
+    /// Find a zombie between the children of parent (only pid if Some)
+    ///
+    /// Err if there is no child that could be waited
+    fn find_zombie_child(
+        &self,
+        parent: Pid,
+        pid: Option<Pid>,
+    ) -> Result<Option<(Pid, i32)>, &'static str> {
+        let process = self.processes.get(&parent).ok_or("Not a process")?;
+
+        let mut candidates = process
+            .children
+            .iter()
+            .filter(|child| pid.map_or(true, |pid| **child == pid))
+            .peekable();
+
+        if candidates.peek().is_none() {
+            return Err("No child to wait");
+        }
+
+        Ok(candidates.find_map(|child| match self.processes.get(child)?.state {
+            ProcessState::Zombie(status) => Some((*child, status)),
+            ProcessState::Alive => None,
+        }))
+    }
+
+    /// Remove a zombie, from now on the pid does not exist anymore
+    fn remove(&mut self, pid: Pid) {
+        if let Some(process) = self.processes.remove(&pid) {
+            if let Some(parent) = process.parent.and_then(|parent| self.processes.get_mut(&parent)) {
+                parent.children.retain(|child| *child != pid);
+            }
+        }
+    }
+}
+
+static PROCESS_TABLE: SpinMutex<ProcessTable> = SpinMutex::new(ProcessTable::new());
+// notified every time a process becomes a zombie, every waiter checks its own children
+static CHILD_EXITED: WaitQueue = WaitQueue::new();
+
+// There is no file system, exec can only load the programs registered
+// by the kernel at boot (the multiboot modules)
+static PROGRAMS: SpinMutex<Vec<(&'static str, &'static [u8])>> = SpinMutex::new(Vec::new());
+
+pub fn register_program(name: &'static str, elf_data: &'static [u8]) {
+    without_interrupts(|| PROGRAMS.lock().push((name, elf_data)));
+}
+
+pub fn find_program(name: &str) -> Option<&'static [u8]> {
+    without_interrupts(|| {
+        PROGRAMS
+            .lock()
+            .iter()
+            .find(|(program, _)| *program == name)
+            .map(|(_, elf_data)| *elf_data)
+    })
+}
+
+/// Add the process of the task pid, MUST be called before the task is added to the scheduler
+pub fn register(pid: Pid, parent: Option<Pid>) {
+    without_interrupts(|| PROCESS_TABLE.lock().register(pid, parent));
+}
+
+pub fn is_process(pid: Pid) -> bool {
+    without_interrupts(|| PROCESS_TABLE.lock().processes.contains_key(&pid))
+}
+
+pub fn parent(pid: Pid) -> Option<Pid> {
+    without_interrupts(|| PROCESS_TABLE.lock().processes.get(&pid)?.parent)
+}
+
+/// Create a copy of the running process, the child will restore user_frame
+/// with eax set to 0. Return the pid of the child
+pub fn fork(user_frame: &UserInterruptFrame) -> Result<Pid, &'static str> {
+    let parent = scheduler::current_id();
+    if !is_process(parent) {
+        return Err("Not a process");
+    }
+
+    let address_space = scheduler::with_current_address_space(|address_space| {
+        address_space.duplicate()
+    })
+    .ok_or("Not a process")??;
+
+    let mut child_frame = *user_frame;
+    child_frame.frame.eax = 0;
+
+    // the child runs with the same priority of the parent
+    let priority = scheduler::current_priority();
+    let task = Task::new_user_task_with_frame(address_space, child_frame, priority);
+    register(task.id, Some(parent));
+    Ok(scheduler::add_task(task))
+}
+
+/// Replace the program of the running process with elf_data,
+/// user_frame is updated to start from the entry point of the new program
+///
+/// If this fails the process is left untouched
+pub fn exec(
+    elf_data: &[u8],
+    argv: &[&str],
+    envp: &[&str],
+    user_frame: &mut UserInterruptFrame,
+) -> Result<(), &'static str> {
+    let elf = ElfFile::new(elf_data)?;
+    let mut address_space = AddressSpace::new()?;
+    let program = loader::load(&elf, &mut address_space, argv, envp)?;
+
+    let old_address_space = scheduler::with_current_address_space(|current| {
+        let old = core::mem::replace(current, address_space);
+        unsafe { current.activate() };
+        old
+    })
+    .ok_or("Not a process")?;
+    // not active anymore, all its pages are freed
+    drop(old_address_space);
+
+    *user_frame = user_entry_frame(&program);
+    Ok(())
+}
+
+/// Terminate the running process, it stays a zombie until the parent waits for it
+pub fn exit(status: i32) -> ! {
+    let pid = scheduler::current_id();
+    without_interrupts(|| PROCESS_TABLE.lock().exit(pid, status));
+    CHILD_EXITED.notify_all();
+
+    // the address space is freed when the task is reaped
+    scheduler::exit_current();
+}
+
+/// Wait until a child (only pid if Some) exits and remove it,
+/// return its pid and its exit status
+///
+/// With nohang return None instead of blocking
+pub fn wait(pid: Option<Pid>, nohang: bool) -> Result<Option<(Pid, i32)>, &'static str> {
+    let parent = scheduler::current_id();
+
+    without_interrupts(|| loop {
+        {
+            let mut table = PROCESS_TABLE.lock();
+            match table.find_zombie_child(parent, pid)? {
+                Some((child, status)) => {
+                    table.remove(child);
+                    return Ok(Some((child, status)));
+                }
+                None if nohang => return Ok(None),
+                None => (),
+            }
+        }
+        CHILD_EXITED.wait();
+    })
+}
@@ -0,0 +1,327 @@
+use super::{Task, TaskId, TaskState};
+use crate::concurrency::spin_mutex::SpinMutex;
+use crate::gdt;
+use crate::interrupts::{without_interrupts, YIELD_INTERRUPT};
+use crate::memory_manager::slab::{SlabBox, SlabCache};
+use crate::memory_manager::{address_space::AddressSpace, change_page_directory};
+use crate::runtime_static::RuntimeStatic;
+use alloc::collections::{BTreeMap, VecDeque};
+use core::arch::asm;
+
+/// The scheduler is used inside the pit handler, so every access
+/// outside of an interrupt MUST be done with the interrupts disabled
+pub static SCHEDULER: RuntimeStatic<SpinMutex<Scheduler>> = RuntimeStatic::get_uninit();
+
+// every Task has the same size, they live in their own slabs
+static TASK_CACHE: RuntimeStatic<SpinMutex<SlabCache<Task>>> = RuntimeStatic::get_uninit();
+
+pub const PRIORITY_LEVELS: usize = 3;
+
+/// A task is never executed if someone with an higher priority is Ready
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    High = 0,
+    Normal = 1,
+    Low = 2,
+}
+
+// in pit ticks
+const DEFAULT_TIME_SLICES: [usize; PRIORITY_LEVELS] = [5, 10, 20];
+
+/// Priority scheduler, one ready queue for each priority level
+/// and round-robin inside the same level
+///
+/// Every Ready task is in exactly one ready queue, the running one is pushed back
+/// when switched out. Blocked and Sleeping tasks are only in tasks, a wake_up
+/// will put them back in their queue
+///
+/// If nothing is Ready the idle task is executed
+pub struct Scheduler {
+    tasks: BTreeMap<TaskId, SlabBox<Task>>,
+    ready: [VecDeque<TaskId>; PRIORITY_LEVELS],
+    time_slices: [usize; PRIORITY_LEVELS],
+    current: TaskId,
+    idle: TaskId,
+    // pit ticks from the scheduler initialization
+    ticks: usize,
+}
+
+impl Scheduler {
+    fn new() -> Self {
+        let boot_task = Task::boot_task();
+        let idle_task = Task::new_kernel_thread(idle_loop, Priority::Low);
+
+        let current = boot_task.id;
+        let idle = idle_task.id;
+
+        let mut tasks = BTreeMap::new();
+        tasks.insert(current, Self::new_box(boot_task));
+        tasks.insert(idle, Self::new_box(idle_task));
+
+        let mut scheduler = Self {
+            tasks,
+            ready: [VecDeque::new(), VecDeque::new(), VecDeque::new()],
+            time_slices: DEFAULT_TIME_SLICES,
+            current,
+            idle,
+            ticks: 0,
+        };
+        scheduler.current_mut().remaining_ticks =
+            scheduler.time_slices[Priority::Normal as usize];
+        scheduler
+    }
+
+    fn new_box(task: Task) -> SlabBox<Task> {
+        SlabBox::new(task, &TASK_CACHE).expect("No memory for a new task")
+    }
+
+    fn current_mut(&mut self) -> &mut Task {
+        self.tasks
+            .get_mut(&self.current)
+            .expect("Running task not present in the scheduler")
+    }
+
+    fn add(&mut self, task: Task) -> TaskId {
+        let id = task.id;
+        self.ready[task.priority as usize].push_back(id);
+        self.tasks.insert(id, Self::new_box(task));
+        id
+    }
+
+    /// Called at every pit tick, return the esp of the task to execute
+    fn tick(&mut self, esp: u32) -> u32 {
+        self.ticks += 1;
+        self.wake_sleepers();
+
+        let current = self.current;
+        let is_idle = current == self.idle;
+        let task = self.current_mut();
+        task.remaining_ticks = task.remaining_ticks.saturating_sub(1);
+
+        let current_level = if is_idle {
+            PRIORITY_LEVELS
+        } else {
+            task.priority as usize
+        };
+
+        let slice_ended = task.remaining_ticks == 0;
+        let preempted = self.ready[..current_level]
+            .iter()
+            .any(|queue| !queue.is_empty());
+
+        if slice_ended || preempted {
+            self.switch(esp)
+        } else {
+            esp
+        }
+    }
+
+    /// Save the esp of the running task and return the esp of the next one
+    fn switch(&mut self, esp: u32) -> u32 {
+        let current = self.current;
+        let is_idle = current == self.idle;
+        let task = self.current_mut();
+        task.esp = esp;
+
+        if task.state == TaskState::Running {
+            task.state = TaskState::Ready;
+            // the idle task is never in a ready queue
+            if !is_idle {
+                let priority = task.priority as usize;
+                self.ready[priority].push_back(current);
+            }
+        }
+
+        self.current = self.pick_next();
+
+        let time_slices = self.time_slices;
+        let next = self.current_mut();
+        next.state = TaskState::Running;
+        next.remaining_ticks = time_slices[next.priority as usize];
+
+        if let Some(stack_top) = next.kernel_stack_top() {
+            gdt::set_kernel_stack(stack_top);
+        }
+        // kernel threads use only the kernel space, shared by every page directory,
+        // so the page directory is changed only for user tasks
+        if let Some(address_space) = &next.address_space {
+            if !address_space.is_active() {
+                unsafe { change_page_directory(address_space.get_physical_addr().get()) };
+            }
+        }
+
+        next.esp
+    }
+
+    fn pick_next(&mut self) -> TaskId {
+        for queue in self.ready.iter_mut() {
+            while let Some(id) = queue.pop_front() {
+                match self.tasks.get(&id) {
+                    Some(task) if task.state == TaskState::Ready => return id,
+                    _ => (),
+                }
+            }
+        }
+
+        self.idle
+    }
+
+    fn wake_sleepers(&mut self) {
+        let ticks = self.ticks;
+        for (id, task) in self.tasks.iter_mut() {
+            if let TaskState::Sleeping(until) = task.state {
+                if until <= ticks {
+                    task.state = TaskState::Ready;
+                    self.ready[task.priority as usize].push_back(*id);
+                }
+            }
+        }
+    }
+
+    /// Make a Blocked or Sleeping task Ready again
+    fn wake_up(&mut self, id: TaskId) {
+        if let Some(task) = self.tasks.get_mut(&id) {
+            match task.state {
+                TaskState::Blocked | TaskState::Sleeping(_) => {
+                    task.state = TaskState::Ready;
+                    self.ready[task.priority as usize].push_back(id);
+                }
+                _ => (),
+            }
+        }
+    }
+
+    /// Remove all the dead tasks, this will free their stacks and address spaces
+    fn reap(&mut self) {
+        self.tasks.retain(|_, task| task.state != TaskState::Dead);
+    }
+
+    pub fn current_id(&self) -> TaskId {
+        self.current
+    }
+
+    pub fn ticks(&self) -> usize {
+        self.ticks
+    }
+}
+
+/// Executed when nothing else is Ready
+fn idle_loop() {
+    loop {
+        // idle is the only one that surely has nothing better to do
+        without_interrupts(|| SCHEDULER.lock().reap());
+
+        unsafe { asm!("sti", "hlt", options(nomem, nostack)) };
+    }
+}
+
+/// Create the scheduler, the running code become the first task
+pub fn init() {
+    TASK_CACHE.init(SpinMutex::new(SlabCache::new()));
+    SCHEDULER.init(SpinMutex::new(Scheduler::new()));
+}
+
+/// Called by the pit handler, return the esp of the task to restore
+pub fn schedule(esp: u32) -> u32 {
+    // interrupts could arrive before the scheduler is ready
+    match SCHEDULER.get() {
+        Some(scheduler) => scheduler.lock().tick(esp),
+        None => esp,
+    }
+}
+
+/// Called by the yield interrupt handler, the running task
+/// gives up the rest of its time slice
+pub fn reschedule(esp: u32) -> u32 {
+    match SCHEDULER.get() {
+        Some(scheduler) => scheduler.lock().switch(esp),
+        None => esp,
+    }
+}
+
+pub fn add_task(task: Task) -> TaskId {
+    without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        scheduler.reap();
+        scheduler.add(task)
+    })
+}
+
+/// Run f on the address space of the running task, None for kernel threads
+///
+/// The scheduler is locked while f runs, f must be short and must not block
+pub fn with_current_address_space<F, R>(f: F) -> Option<R>
+where
+    F: FnOnce(&mut AddressSpace) -> R,
+{
+    without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        scheduler.current_mut().address_space.as_mut().map(f)
+    })
+}
+
+pub fn current_id() -> TaskId {
+    without_interrupts(|| SCHEDULER.lock().current_id())
+}
+
+pub fn current_priority() -> Priority {
+    without_interrupts(|| SCHEDULER.lock().current_mut().priority)
+}
+
+/// Pit ticks elapsed from the scheduler initialization
+pub fn ticks() -> usize {
+    without_interrupts(|| SCHEDULER.lock().ticks())
+}
+
+/// Change the number of ticks a task with this priority can run
+/// before being switched out, used from the next switch
+pub fn set_time_slice(priority: Priority, ticks: usize) {
+    if ticks == 0 {
+        panic!("Time slice must be at least one tick");
+    }
+    without_interrupts(|| SCHEDULER.lock().time_slices[priority as usize] = ticks);
+}
+
+/// Give the cpu to someone else, the task will be executed again
+/// when it will be chosen by the scheduler
+///
+/// Works also with interrupts disabled, the state is restored when the task come back
+pub fn yield_now() {
+    unsafe { asm!("int {}", const YIELD_INTERRUPT, options(nomem)) };
+}
+
+/// The running task will not be executed for at least ticks pit ticks
+pub fn sleep(ticks: usize) {
+    without_interrupts(|| {
+        {
+            let mut scheduler = SCHEDULER.lock();
+            // a too long sleep never ends, it must not wrap and end at once
+            let until = scheduler.ticks.saturating_add(ticks);
+            scheduler.current_mut().state = TaskState::Sleeping(until);
+        }
+        yield_now();
+    });
+}
+
+/// Stop the running task until someone will call wake_up on it
+///
+/// The caller should disable interrupts before making itself reachable
+/// by a waker, otherwise the wake_up could happen before the block
+pub fn block_current() {
+    without_interrupts(|| {
+        SCHEDULER.lock().current_mut().state = TaskState::Blocked;
+        yield_now();
+    });
+}
+
+pub fn wake_up(id: TaskId) {
+    without_interrupts(|| SCHEDULER.lock().wake_up(id));
+}
+
+/// Terminate the running task, the stack will be freed by someone else
+pub fn exit_current() -> ! {
+    without_interrupts(|| SCHEDULER.lock().current_mut().state = TaskState::Dead);
+
+    yield_now();
+    unreachable!("Dead task executed again");
+}
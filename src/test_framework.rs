@@ -0,0 +1,58 @@
+use crate::port::Port8Bit;
+use crate::{serial_print, serial_println};
+use core::panic::PanicInfo;
+
+// Runner of the #[test_case]s, used only by the kernel built with cargo test (see build_and_test.sh)
+//
+// Every test is a function, they run one after the other inside kernel_main once the kernel
+// is ready. The results are written on the serial port and qemu is stopped by the
+// isa-debug-exit device with the exit code of the tests
+
+/// Port of the isa-debug-exit device, qemu -device isa-debug-exit,iobase=0xf4,iosize=0x04
+const ISA_DEBUG_EXIT_PORT: u16 = 0xF4;
+
+/// Written on isa-debug-exit, qemu exits with (code << 1) | 1, so 33 or 35.
+/// 0 and 1 can't be used, they are the exit codes of qemu itself
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum QemuExitCode {
+    Success = 0x10,
+    Failed = 0x11,
+}
+
+pub fn exit_qemu(exit_code: QemuExitCode) -> ! {
+    Port8Bit::new(ISA_DEBUG_EXIT_PORT).write(exit_code as u8);
+
+    // without the device (not started by build_and_test.sh) qemu keeps running
+    crate::panic::halt()
+}
+
+pub trait Testable {
+    fn run(&self);
+}
+
+impl<T: Fn()> Testable for T {
+    fn run(&self) {
+        serial_print!("{} ... ", core::any::type_name::<T>());
+        self();
+        serial_println!("[ok]");
+    }
+}
+
+pub fn test_runner(tests: &[&dyn Testable]) {
+    serial_println!("Running {} tests", tests.len());
+    for test in tests {
+        test.run();
+    }
+    serial_println!("All tests passed");
+
+    exit_qemu(QemuExitCode::Success);
+}
+
+/// A failed assert is a panic, the remaining tests are not run
+pub fn test_panic_handler(info: &PanicInfo) -> ! {
+    serial_println!("[failed]");
+    serial_println!("{}", info);
+
+    exit_qemu(QemuExitCode::Failed);
+}
@@ -221,9 +221,13 @@ pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
     // This has to be unsafe until I find a way to have something static but
     // I can initialize on runtime
-    unsafe {
+    // interrupt handlers print too, so never hold the lock with interrupts enabled
+    crate::interrupts::without_interrupts(|| unsafe {
         WRITER.lock().write_fmt(args).unwrap();
-    }
+        if crate::serial::console_mirror() {
+            crate::serial::SERIAL1.lock().write_fmt(args).unwrap();
+        }
+    });
 }
 
 /* OLD
@@ -0,0 +1,7 @@
+#!/bin/sh
+
+# the allocators tests run with cargo test on the host (not inside the kernel),
+# from outside of the repository so .cargo/config.toml (custom target and build-std) is ignored
+MANIFEST="$(cd "$(dirname "$0")" && pwd)/Cargo.toml"
+cd /
+cargo +nightly test --manifest-path "$MANIFEST" "$@"